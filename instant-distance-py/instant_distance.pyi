@@ -0,0 +1,165 @@
+from array import array
+from typing import (
+    Any,
+    Callable,
+    Dict,
+    Iterable,
+    Iterator,
+    List,
+    Optional,
+    Protocol,
+    Sequence,
+    Tuple,
+    Union,
+)
+
+# A point: up to 300 floats, as a list or a 1-dimensional float32 or float64 buffer (like a
+# numpy array)
+Vector = Iterable[float]
+
+# A point for `ObjectHnsw`: any (picklable) object that can measure its distance to another
+class HasDistance(Protocol):
+    def distance(self, other: Any) -> float: ...
+
+def register_metric(name: str, function: Callable[[List[float], List[float]], float]) -> None: ...
+
+class Candidate:
+    @property
+    def pid(self) -> int: ...
+    @property
+    def original_index(self) -> int: ...
+    @property
+    def distance(self) -> float: ...
+
+class EvalReport:
+    @property
+    def mean_recall(self) -> float: ...
+    @property
+    def min_recall(self) -> float: ...
+    @property
+    def max_recall(self) -> float: ...
+    @property
+    def recall_ci(self) -> Tuple[float, float]: ...
+    @property
+    def qps(self) -> float: ...
+
+class Heuristic:
+    extend_candidates: bool
+    keep_pruned: bool
+    def __init__(self) -> None: ...
+
+class Config:
+    ef_search: int
+    ef_construction: int
+    ml: float
+    seed: int
+    heuristic: Optional[Heuristic]
+    dedup_exact: bool
+    flat: bool
+    metric: str
+    accumulator: str
+    def __init__(self) -> None: ...
+
+class Search:
+    k: Optional[int]
+    min_distance: Optional[float]
+    max_distance: Optional[float]
+    dedup_epsilon: Optional[float]
+    skip_invalid_links: bool
+    def __init__(self) -> None: ...
+    def reset(self) -> None: ...
+    def as_arrays(self) -> Tuple[array, array]: ...
+    def __iter__(self) -> Iterator[Candidate]: ...
+    def __next__(self) -> Candidate: ...
+    def __len__(self) -> int: ...
+
+class KeyedHnsw:
+    @staticmethod
+    def build(entries: List[Tuple[str, Vector, str]], config: Config) -> KeyedHnsw: ...
+    @staticmethod
+    def load(fname: str) -> KeyedHnsw: ...
+    def dump(self, fname: str) -> None: ...
+    def get(self, key: str) -> Optional[str]: ...
+    def search(self, point: Vector, k: int) -> List[Tuple[str, str, float]]: ...
+    def __len__(self) -> int: ...
+    def __contains__(self, key: str) -> bool: ...
+
+class Hnsw:
+    @staticmethod
+    def build(
+        input: List[Vector],
+        config: Config,
+        attributes: Optional[Dict[str, Union[List[bool], List[int], List[str]]]] = None,
+    ) -> Tuple[Hnsw, List[int]]: ...
+    @staticmethod
+    def load(fname: str, metric: Optional[str] = None) -> Hnsw: ...
+    def with_metric(self, metric: str) -> Hnsw: ...
+    @property
+    def config(self) -> Config: ...
+    def dump(self, fname: str, format: str = "bincode") -> None: ...
+    def verify_integrity(self) -> List[str]: ...
+    def contains(self, pid: int) -> bool: ...
+    def layer_of(self, pid: int) -> int: ...
+    def layer_points(self, layer: int) -> List[int]: ...
+    def original_index(self, pid: int) -> int: ...
+    def centroid(self, pids: Sequence[int]) -> List[float]: ...
+    def get_many(self, pids: Sequence[int]) -> array: ...
+    def sample(self, n: int, uniform: bool = False, seed: int = 0) -> Tuple[array, array]: ...
+    def search(
+        self,
+        point: Vector,
+        search: Search,
+        metric: Optional[str] = None,
+        mask: Optional[Sequence[bool]] = None,
+    ) -> None: ...
+    def search_traced(self, point: Vector, search: Search) -> Dict[str, List[Dict[str, Any]]]: ...
+    def search_filtered(
+        self,
+        point: Vector,
+        filters: Dict[str, Union[bool, int, str, Tuple[int, int]]],
+        search: Search,
+    ) -> None: ...
+    def distances_to(self, point: Vector, pids: Sequence[int]) -> array: ...
+    def k_distances(self, k: int) -> array: ...
+    def search_pooled(self, point: Vector, k: int) -> List[Candidate]: ...
+    def search_ids(self, point: Vector, k: int) -> List[int]: ...
+    def count_within_radius(self, point: Vector, radius: float) -> int: ...
+    def export_dot(self, max_nodes: int = 1000) -> str: ...
+    def component_sizes(self) -> List[int]: ...
+    def export_vectors(self) -> array: ...
+    def export_adjacency(self) -> List[List[int]]: ...
+    def evaluate(
+        self,
+        queries: Sequence[Vector],
+        ground_truth: Sequence[Sequence[int]],
+        k: int,
+        ef_search: int,
+    ) -> EvalReport: ...
+    def __contains__(self, pid: int) -> bool: ...
+
+class SetHnsw:
+    @staticmethod
+    def build(
+        input: List[Sequence[int]], config: Config, minhash: bool = False
+    ) -> Tuple[SetHnsw, List[int]]: ...
+    @staticmethod
+    def load(fname: str) -> SetHnsw: ...
+    def dump(self, fname: str) -> None: ...
+    def contains(self, pid: int) -> bool: ...
+    def layer_of(self, pid: int) -> int: ...
+    def original_index(self, pid: int) -> int: ...
+    def search(self, point: Sequence[int], search: Search) -> None: ...
+    def __contains__(self, pid: int) -> bool: ...
+
+class ObjectHnsw:
+    @staticmethod
+    def build(input: List[HasDistance], config: Config) -> Tuple[ObjectHnsw, List[int]]: ...
+    @staticmethod
+    def load(fname: str) -> ObjectHnsw: ...
+    def dump(self, fname: str) -> None: ...
+    def get(self, pid: int) -> HasDistance: ...
+    def contains(self, pid: int) -> bool: ...
+    def layer_of(self, pid: int) -> int: ...
+    def original_index(self, pid: int) -> int: ...
+    def search(self, point: HasDistance, search: Search) -> None: ...
+    def __contains__(self, pid: int) -> bool: ...
@@ -1,103 +1,1407 @@
 #![allow(clippy::from_iter_instead_of_collect)]
+use std::arch::x86_64::{__m128, __m256};
+use std::cmp::{max, Ordering};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 use std::iter::FromIterator;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use instant_distance::Point;
+use bincode::Options;
+use instant_distance::{BuildParams, Centroid, Point, PointId};
+use pyo3::buffer::PyBuffer;
 use pyo3::exceptions::{PyTypeError, PyValueError};
-use pyo3::proc_macro::{pyclass, pymethods, pymodule, pyproto};
-use pyo3::types::{PyList, PyModule};
-use pyo3::{PyAny, PyErr, PyIterProtocol, PyObjectProtocol, PyRef, PyRefMut, PyResult, Python};
+use pyo3::proc_macro::{pyclass, pyfunction, pymethods, pymodule, pyproto};
+use pyo3::types::{PyBool, PyBytes, PyDict, PyList, PyModule, PyString};
+use pyo3::{
+    wrap_pyfunction, PyAny, PyErr, PyIterProtocol, PyNativeType, PyObject, PyObjectProtocol, PyRef,
+    PyRefMut, PyResult, PySequenceProtocol, Python,
+};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_big_array::big_array;
 
 #[pymodule]
 fn instant_distance(_: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Candidate>()?;
+    m.add_class::<EvalReport>()?;
     m.add_class::<Heuristic>()?;
     m.add_class::<Config>()?;
     m.add_class::<Search>()?;
+    m.add_class::<KeyedHnsw>()?;
     m.add_class::<Hnsw>()?;
+    m.add_class::<SetHnsw>()?;
+    m.add_class::<ObjectHnsw>()?;
+    m.add_function(wrap_pyfunction!(register_metric, m)?)?;
     Ok(())
 }
 
+/// Register a distance function implemented in Python as a metric named `name`
+///
+/// Once registered, the metric can be selected by setting a `Config`'s `metric` to `name`.
+/// The function is called with two lists of 300 floats (points are zero-padded, like for the
+/// built-in metrics) and must return the distance as a float. Exceptions raised by the
+/// function are printed and treated as an infinite distance. Registering a name again
+/// replaces the function for indexes built or loaded afterwards as well as existing ones.
+///
+/// Every distance computation calls into Python, which makes building and searching several
+/// orders of magnitude slower than with the built-in metrics, and parallel builds are
+/// serialized on the GIL. This is meant for prototyping new metrics, not for production use.
+/// Indexes built with a custom metric can be dumped; the metric has to be registered under the
+/// same name before loading them again.
+#[pyfunction]
+fn register_metric(name: &str, function: &PyAny) -> PyResult<()> {
+    if !function.is_callable() {
+        return Err(PyTypeError::new_err("metric function must be callable"));
+    }
+    if BUILTIN_METRICS.contains(&name) {
+        return Err(PyValueError::new_err(format!(
+            "cannot replace the built-in {:?} metric",
+            name
+        )));
+    }
+
+    let function = PyObject::from(function);
+    let mut metrics = CUSTOM_METRICS.lock().unwrap();
+    match metrics.iter_mut().find(|metric| metric.name == name) {
+        Some(metric) => metric.function = function,
+        None => metrics.push(CustomMetric {
+            name: Box::leak(name.to_owned().into_boxed_str()),
+            function,
+        }),
+    }
+    Ok(())
+}
+
+/// An index of vectors identified by string keys, each carrying a string value
+///
+/// This is the recommended class for most applications: searches return the keys and values
+/// of the nearest entries directly, so there are no point ids to keep track of. Use `Hnsw`
+/// for access to the ids and the lower-level operations built on them. Like `Hnsw`, this is
+/// specialized to 300-element float vectors, and the metric is selected through the `Config`.
+#[pyclass]
+struct KeyedHnsw {
+    inner: instant_distance::KeyedHnsw<String, FloatArray, String>,
+    metric: Metric,
+    accumulator: Accumulator,
+    /// Search buffers for `search()`
+    pool: Mutex<Vec<instant_distance::Search>>,
+}
+
+#[pymethods]
+impl KeyedHnsw {
+    /// Build the index from a list of `(key, vector, value)` tuples
+    ///
+    /// If several entries share a key, only the last one is kept.
+    #[staticmethod]
+    fn build(list: &PyList, config: &Config) -> PyResult<Self> {
+        let entries = list
+            .into_iter()
+            .map(|entry| {
+                let (key, point, value) = entry.extract::<(String, &PyAny, String)>()?;
+                let point =
+                    FloatArray::try_from(point)?.with_metric(config.metric, config.accumulator);
+                Ok((key, point, value))
+            })
+            .collect::<Result<Vec<_>, PyErr>>()?;
+
+        let builder = instant_distance::Builder::from(config);
+        let inner = list.py().allow_threads(|| builder.build_keyed(entries));
+        Ok(Self {
+            inner,
+            metric: config.metric,
+            accumulator: config.accumulator,
+            pool: Mutex::default(),
+        })
+    }
+
+    /// Load an index from the given file name
+    #[staticmethod]
+    fn load(fname: &str) -> PyResult<Self> {
+        let mut f = BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?);
+        let (inner, metric, accumulator, norms, custom) = bincode::deserialize_from::<
+            _,
+            (
+                instant_distance::KeyedHnsw<String, FloatArray, String>,
+                Metric,
+                Accumulator,
+                Vec<f32>,
+                Option<String>,
+            ),
+        >(&mut f)
+        .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))?;
+        let metric = Metric::resolve(metric, custom)?;
+
+        let inner = inner.map_points(|pid, point| match norms.get(pid.into_inner() as usize) {
+            Some(&norm) => point.with_norm(metric, accumulator, norm),
+            None => point.with_metric(metric, accumulator),
+        });
+        Ok(Self {
+            inner,
+            metric,
+            accumulator,
+            pool: Mutex::default(),
+        })
+    }
+
+    /// Dump the index to the given file name
+    fn dump(&self, fname: &str) -> PyResult<()> {
+        let norms = match self.metric.uses_norm() {
+            true => Vec::from_iter(self.inner.iter().map(|(_, point, _)| point.norm)),
+            false => Vec::new(),
+        };
+        let f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
+        let custom = self.metric.custom_name();
+        let stored = (&self.inner, self.metric, self.accumulator, norms, custom);
+        bincode::serialize_into(f, &stored)
+            .map_err(|e| PyValueError::new_err(format!("serialization error: {:?}", e)))?;
+        Ok(())
+    }
+
+    /// The value stored for `key`, or `None` if there is no entry for it
+    fn get(&self, key: &str) -> Option<String> {
+        let (_, value) = self.inner.get(&key.to_owned())?;
+        Some(value.clone())
+    }
+
+    /// Search the index for the `k` entries nearest to the given point
+    ///
+    /// Returns a list of `(key, value, distance)` tuples, nearest first. Like
+    /// `Hnsw.search_pooled()`, the GIL is released while searching, so this can be called from
+    /// multiple Python threads at once. At most `ef_search` results are returned.
+    fn search(&self, py: Python, point: &PyAny, k: usize) -> PyResult<Vec<(String, String, f32)>> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let mut search = self.pool.lock().unwrap().pop().unwrap_or_default();
+        let results = py.allow_threads(|| {
+            search.set_k(Some(k));
+            self.inner
+                .search(&point, &mut search)
+                .map(|(key, value, distance)| (key.clone(), value.clone(), distance))
+                .collect::<Vec<_>>()
+        });
+
+        self.pool.lock().unwrap().push(search);
+        Ok(results)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for KeyedHnsw {
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn __contains__(&self, key: &str) -> bool {
+        self.inner.contains_key(&key.to_owned())
+    }
+}
+
 /// An instance of hierarchical navigable small worlds
 ///
-/// For now, this is specialized to only support 300-element (32-bit) float vectors
-/// with a squared Euclidean distance metric.
+/// For now, this is specialized to only support 300-element (32-bit) float vectors. The
+/// distance metric is selected through the `Config`'s `metric` attribute.
 #[pyclass]
 struct Hnsw {
     inner: instant_distance::Hnsw<FloatArray>,
+    metric: Metric,
+    accumulator: Accumulator,
+    /// Attributes used by `search_filtered()`
+    attributes: Attributes,
+    /// Search buffers for `search_pooled()`
+    pool: Mutex<Vec<instant_distance::Search>>,
 }
 
 #[pymethods]
 impl Hnsw {
     /// Build the index
+    ///
+    /// Returns the index along with a list of ids, one for every input point: the point at
+    /// position `i` in `input` gets id `ids[i]`. Search results refer to points by these ids.
+    ///
+    /// `attributes` optionally maps attribute names to a list of values, one for every input
+    /// point, for use with `search_filtered()`. All values in a list must have the same type:
+    /// `bool` for flags, `int` for numbers (like timestamps) or `str` for categories.
     #[staticmethod]
-    fn build(input: &PyList, config: &Config) -> PyResult<(Self, Vec<u32>)> {
+    #[args(attributes = "None")]
+    fn build(
+        input: &PyList,
+        config: &Config,
+        attributes: Option<&PyDict>,
+    ) -> PyResult<(Self, Vec<u32>)> {
         let points = input
             .into_iter()
-            .map(FloatArray::try_from)
+            .map(|value| {
+                Ok(FloatArray::try_from(value)?.with_metric(config.metric, config.accumulator))
+            })
             .collect::<Result<Vec<_>, PyErr>>()?;
 
-        let (inner, ids) = instant_distance::Builder::from(config).build(&points);
+        // Release the GIL, so that build threads can call custom metrics
+        let (builder, dedup) = (instant_distance::Builder::from(config), config.dedup_exact);
+        let (inner, ids) = input.py().allow_threads(|| match dedup {
+            true => builder.build_dedup(&points),
+            false => builder.build(&points),
+        });
+        let attributes = match attributes {
+            Some(attributes) => Attributes::new(attributes, &ids)?,
+            None => Attributes::default(),
+        };
+
         let ids = Vec::from_iter(ids.into_iter().map(|pid| pid.into_inner()));
-        Ok((Self { inner }, ids))
+        let (metric, accumulator) = (config.metric, config.accumulator);
+        let pool = Mutex::default();
+        Ok((
+            Self {
+                inner,
+                metric,
+                accumulator,
+                attributes,
+                pool,
+            },
+            ids,
+        ))
     }
 
     /// Load an index from the given file name
+    ///
+    /// Passing a `metric` searches the stored graph using that metric instead of the one the
+    /// index was built with, which is useful for quick experiments without rebuilding. The
+    /// graph's links were chosen for the original metric, so recall may be considerably lower
+    /// than for an index built with the new metric. The "euclidean", "cosine" and "angular"
+    /// metrics can be swapped for each other; "pearson" stores mean-centered points, so it can
+    /// only be used for indexes built with it.
+    ///
+    /// The format the index was dumped in is detected from the start of the file. Files dumped
+    /// by releases that didn't version the format are loaded as well.
     #[staticmethod]
-    fn load(fname: &str) -> PyResult<Self> {
-        let hnsw = bincode::deserialize_from::<_, instant_distance::Hnsw<FloatArray>>(
-            BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?),
-        )
-        .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))?;
-        Ok(Self { inner: hnsw })
+    #[args(metric = "None")]
+    fn load(fname: &str, metric: Option<&str>) -> PyResult<Self> {
+        let mut f = BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?);
+        let header = f.fill_buf()?;
+        let mut hnsw = if header.starts_with(Format::COMPACT_MAGIC) {
+            load_compact(&mut f)?
+        } else if header.starts_with(Format::BINCODE_MAGIC) {
+            bincode::deserialize_from::<_, instant_distance::Hnsw<FloatArray>>(&mut f)
+                .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))?
+        } else {
+            load_legacy(&mut f)?
+        };
+
+        // The metric, accumulator, norms, attributes, custom metric name and build parameters
+        // follow the graph. Files written by releases without a versioned format end after the
+        // graph, so all of these take their defaults: a euclidean metric, without attributes.
+        let stored = deserialize_or_default(&mut f)?;
+        let accumulator = deserialize_or_default(&mut f)?;
+        let norms = deserialize_or_default::<Vec<f32>>(&mut f)?;
+        let attributes = deserialize_or_default(&mut f)?;
+        let custom = deserialize_or_default::<Option<String>>(&mut f)?;
+        let params = deserialize_or_default::<Option<BuildParams>>(&mut f)?;
+        let stored = Metric::resolve(stored, custom)?;
+        let metric = match metric {
+            Some(name) => stored.replace(name)?,
+            None => stored,
+        };
+        if let Some(params) = params {
+            hnsw.set_build_params(params);
+        }
+        let inner = hnsw.map_points(|pid, point| match norms.get(pid.into_inner() as usize) {
+            Some(&norm) => point.with_norm(metric, accumulator, norm),
+            None => point.with_metric(metric, accumulator),
+        });
+        let pool = Mutex::default();
+        Ok(Self {
+            inner,
+            metric,
+            accumulator,
+            attributes,
+            pool,
+        })
+    }
+
+    /// Return a copy of the index that is searched using `metric`
+    ///
+    /// Like passing `metric` to `load()`, this searches the existing graph using another
+    /// metric, and the same restrictions apply. Recall may be considerably lower than for an
+    /// index built with the new metric, since the graph's links were chosen for the original
+    /// one. The copy takes as much memory as the index itself; this index is left unchanged.
+    fn with_metric(&self, metric: &str) -> PyResult<Self> {
+        let (metric, accumulator) = (self.metric.replace(metric)?, self.accumulator);
+        let inner = self.inner.clone().map_points(|_, point| {
+            let norm = point.norm;
+            point.with_norm(metric, accumulator, norm)
+        });
+        Ok(Self {
+            inner,
+            metric,
+            accumulator,
+            attributes: self.attributes.clone(),
+            pool: Mutex::default(),
+        })
     }
 
     /// Dump the index to the given file name
-    fn dump(&self, fname: &str) -> PyResult<()> {
-        let f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
-        bincode::serialize_into(f, &self.inner)
+    ///
+    /// `format` selects how the index is stored; `load()` detects it automatically:
+    ///
+    /// * "bincode" (the default) serializes the index as it is held in memory. It loads
+    ///   fastest.
+    /// * "compact" stores the graph with variable-length neighbor lists and the points with only
+    ///   as many values as the longest point has, instead of padding them to 300 values. This is
+    ///   much smaller for low-dimensional points or sparse graphs, but loading it takes longer.
+    ///
+    /// Both formats keep the `config` the index was built with.
+    ///
+    /// There is no memory-mappable format: both formats are read into memory when loading.
+    #[args(format = "\"bincode\"")]
+    fn dump(&self, fname: &str, format: &str) -> PyResult<()> {
+        let format = format.parse::<Format>()?;
+        let norms = match self.metric.uses_norm() {
+            true => Vec::from_iter(self.inner.iter().map(|(_, point)| point.norm)),
+            false => Vec::new(),
+        };
+
+        let mut f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
+        match format {
+            Format::Bincode => bincode::serialize_into(&mut f, &self.inner)
+                .map_err(|e| PyValueError::new_err(format!("serialization error: {:?}", e)))?,
+            Format::Compact => dump_compact(&self.inner, &mut f)?,
+        }
+
+        bincode::serialize_into(&mut f, &self.metric)
+            .and_then(|()| bincode::serialize_into(&mut f, &self.accumulator))
+            .and_then(|()| bincode::serialize_into(&mut f, &norms))
+            .and_then(|()| bincode::serialize_into(&mut f, &self.attributes))
+            .and_then(|()| bincode::serialize_into(&mut f, &self.metric.custom_name()))
+            .and_then(|()| bincode::serialize_into(&mut f, &self.inner.build_params()))
             .map_err(|e| PyValueError::new_err(format!("serialization error: {:?}", e)))?;
         Ok(())
     }
 
+    /// The configuration the index was built with
+    ///
+    /// This is stored with the index, so it is also available after `load()`, for logging how
+    /// an index was built. The `seed` is
+    /// the one that was actually used. `dedup_exact` is always `False`, since duplicates are
+    /// removed before the index is built.
+    #[getter]
+    fn config(&self) -> PyResult<Config> {
+        let params = self
+            .inner
+            .build_params()
+            .ok_or_else(|| PyValueError::new_err("build parameters missing"))?;
+        let ml = params
+            .ml()
+            .ok_or_else(|| PyValueError::new_err("layer distribution has no ml parameter"))?;
+        Ok(Config {
+            ef_search: params.ef_search,
+            ef_construction: params.ef_construction,
+            ml,
+            seed: params.seed,
+            heuristic: params.heuristic.map(Heuristic::from),
+            dedup_exact: false,
+            flat: params.flat,
+            metric: self.metric,
+            accumulator: self.accumulator,
+        })
+    }
+
+    /// Check the graph structure for corruption, without searching
+    ///
+    /// Returns a description of every problem found, or an empty list for an intact index.
+    /// Indexes read from a damaged file can make searches crash; checking after `load()` allows
+    /// falling back to another copy, or to searching with `Search.skip_invalid_links` set.
+    fn verify_integrity(&self) -> Vec<String> {
+        match self.inner.verify_integrity() {
+            Ok(()) => Vec::new(),
+            Err(found) => Vec::from_iter(found.iter().map(|corruption| corruption.to_string())),
+        }
+    }
+
+    /// Whether the given id refers to a point in this index
+    fn contains(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+
+    /// The highest layer the point with the given id was assigned to during construction
+    fn layer_of(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.layer_of(point_id(&self.inner, pid)?))
+    }
+
+    /// The ids of the points present on the given layer
+    ///
+    /// Layer 0 holds every point; higher layers hold sparse, roughly uniform samples of the
+    /// data, which can serve as representatives for coarse clustering. The top layer is
+    /// `layer_of(0)`, and layers above it are empty.
+    fn layer_points(&self, layer: usize) -> Vec<u32> {
+        Vec::from_iter(
+            self.inner
+                .layer_points(layer)
+                .into_iter()
+                .map(|pid| pid.into_inner()),
+        )
+    }
+
+    /// The position of the point with the given id in the input list passed to `build()`
+    ///
+    /// This is the inverse of the list of ids returned by `build()`, so it can be used to trace
+    /// a `Candidate`'s `pid` back to the input row.
+    fn original_index(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.original_index(point_id(&self.inner, pid)?))
+    }
+
+    /// Return the mean of the points with the given ids, as a list of 300 floats
+    ///
+    /// The result can be passed to `search()` directly, which makes it useful for relevance
+    /// feedback: searching for the centroid of a user's selected results finds more points
+    /// like them. For the Pearson metric, this averages the mean-centered points.
+    fn centroid(&self, pids: Vec<u32>) -> PyResult<Vec<f32>> {
+        if pids.is_empty() {
+            return Err(PyValueError::new_err("expected at least one id"));
+        }
+
+        let pids = pids
+            .into_iter()
+            .map(|pid| point_id(&self.inner, pid))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(self.inner.centroid(&pids).values.to_vec())
+    }
+
+    /// Return the points with the given ids, in the order of `pids`
+    ///
+    /// Returns an `array.array` of type "f" with 300 values per point; wrap it with
+    /// `numpy.frombuffer()` and reshape it to `(len(pids), 300)` to get a numpy array without
+    /// copying. Fetching all points in one call avoids the overhead of a call per id when
+    /// re-ranking search results. Raises a `ValueError` for the first id that doesn't refer to
+    /// a point in this index, like `centroid()`. For the Pearson metric, the points are
+    /// mean-centered.
+    fn get_many<'py>(&self, py: Python<'py>, pids: Vec<u32>) -> PyResult<&'py PyAny> {
+        let pids = pids
+            .into_iter()
+            .map(|pid| point_id(&self.inner, pid))
+            .collect::<PyResult<Vec<_>>>()?;
+        let points = self.inner.get_points(&pids);
+        let bytes = Vec::from_iter(
+            points
+                .iter()
+                .flat_map(|point| point.values.iter().flat_map(|value| value.to_ne_bytes())),
+        );
+        let array = py.import("array")?.getattr("array")?;
+        array.call1(("f", PyBytes::new(py, &bytes)))
+    }
+
+    /// Return a sample of up to `n` points, for visualizing the data without reading all of it
+    ///
+    /// Returns two `array.array`s: the ids (of type "I") and the points (of type "f"), with 300
+    /// values per point, in the order of the ids. `numpy.frombuffer()` turns the points into a
+    /// numpy array without copying, which can be reshaped to one row per point. By default,
+    /// the upper layers of the graph are taken first, since they are sparse, roughly uniform
+    /// samples of the data; pass `uniform=True` to make all points equally likely. The same
+    /// `seed` yields the same sample. For the Pearson metric, the points are mean-centered.
+    #[args(uniform = "false", seed = "0")]
+    fn sample<'py>(
+        &self,
+        py: Python<'py>,
+        n: usize,
+        uniform: bool,
+        seed: u64,
+    ) -> PyResult<(&'py PyAny, &'py PyAny)> {
+        let sampling = match uniform {
+            true => instant_distance::Sampling::Uniform,
+            false => instant_distance::Sampling::Layers,
+        };
+        let sample = self.inner.sample(n, sampling, seed);
+        let (mut pids, mut points) = (Vec::new(), Vec::new());
+        for (pid, point) in sample {
+            pids.extend_from_slice(&pid.into_inner().to_ne_bytes());
+            points.extend(point.values.iter().flat_map(|value| value.to_ne_bytes()));
+        }
+
+        let array = py.import("array")?.getattr("array")?;
+        Ok((
+            array.call1(("I", PyBytes::new(py, &pids)))?,
+            array.call1(("f", PyBytes::new(py, &points)))?,
+        ))
+    }
+
     /// Search the index for points neighboring the given point
     ///
     /// The `search` object contains buffers used for searching. When the search completes,
     /// iterate over the `Search` to get the results. The number of results should be equal
-    /// to the `ef_search` parameter set in the index's `config`.
+    /// to the `ef_search` parameter set in the index's `config`, unless limited by the
+    /// `Search`'s `k` attribute.
+    ///
+    /// For best performance, reusing `Search` objects is recommended. Passing the point as a
+    /// float32 or float64 numpy array (or another 1-dimensional float buffer) avoids converting it
+    /// element by element.
+    ///
+    /// Passing the name of another `metric` scores the results with that metric for this search
+    /// only, for comparing metrics without building an index for each. The graph is still
+    /// traversed using the index's own metric, so recall is usually lower than with an index
+    /// built for the other metric. The "pearson" metric can't be combined with others this way,
+    /// since it relies on mean-centered vectors.
     ///
-    /// For best performance, reusing `Search` objects is recommended.
-    fn search(&self, point: &PyAny, search: &mut Search) -> PyResult<()> {
+    /// Passing a `mask` computes distances over a subset of dimensions for this search only, as
+    /// if the others were zero in both the query and the indexed points: it holds one flag per
+    /// leading dimension, and dimensions whose flag is `False` or that lie past its end are left
+    /// out. The graph was linked using all dimensions, so recall drops as the mask diverges from
+    /// them. Masked distances are computed by a scalar loop over the selected dimensions instead
+    /// of the SIMD kernel, without the early exit of Euclidean distances, so their cost grows
+    /// with the number of selected dimensions.
+    #[args(metric = "None", mask = "None")]
+    fn search(
+        &self,
+        point: &PyAny,
+        search: &mut Search,
+        metric: Option<&str>,
+        mask: Option<Vec<bool>>,
+    ) -> PyResult<()> {
         let point = FloatArray::try_from(point)?;
+        let point = match &mask {
+            Some(mask) => point.with_mask(mask)?,
+            None => point,
+        };
+        search.inner.set_k(search.k);
+        search.inner.set_min_distance(search.min_distance);
+        search.inner.set_max_distance(search.max_distance);
+        search.inner.set_dedup_epsilon(search.dedup_epsilon);
+        search
+            .inner
+            .set_skip_invalid_links(search.skip_invalid_links);
+        match metric.map(Metric::from_str).transpose()? {
+            Some(metric) if metric != self.metric => {
+                if metric == Metric::Pearson || self.metric == Metric::Pearson {
+                    return Err(PyValueError::new_err(
+                        "the pearson metric can't be combined with other metrics",
+                    ));
+                }
+
+                let query = point.clone().with_metric(metric, self.accumulator);
+                let point = point.with_metric(self.metric, self.accumulator);
+                let _ = self
+                    .inner
+                    .search_rescored(&point, &mut search.inner, |pid| {
+                        query.distance(&self.inner[pid])
+                    });
+            }
+            _ => {
+                let point = point.with_metric(self.metric, self.accumulator);
+                let _ = self.inner.search(&point, &mut search.inner);
+            }
+        }
+
+        search.start(&self.inner)
+    }
+
+    /// Search the index like `search()`, returning a record of every step the search took
+    ///
+    /// This is a debugging tool for individual queries with disappointing results. The results
+    /// are returned through `search` as usual, and the trace as a dict (which can be passed to
+    /// `json.dumps()`) with two keys. "results" holds a dict with the "pid", "distance" and
+    /// "layer" for every result, where the layer is the highest layer at which it was first
+    /// reached. "steps" lists the steps in order, as dicts with a "kind" and a "layer":
+    ///
+    /// - "visit": the "distance" to "pid" was computed
+    /// - "expand": the neighbors of "pid" (at "distance") are visited next
+    /// - "stop": the search of the layer finished; "next" is the distance to the nearest
+    ///   candidate that was left unexpanded because it is farther away than all results, or
+    ///   `None` if all candidates were expanded
+    ///
+    /// Once the search holds `ef_search` results, distances to points that are too far away to
+    /// become results may be cut short, so those only tell that the point is farther away.
+    fn search_traced<'py>(
+        &self,
+        py: Python<'py>,
+        point: &PyAny,
+        search: &mut Search,
+    ) -> PyResult<&'py PyDict> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        search.inner.set_k(search.k);
+        search.inner.set_min_distance(search.min_distance);
+        search.inner.set_max_distance(search.max_distance);
+        search.inner.set_dedup_epsilon(search.dedup_epsilon);
+        search
+            .inner
+            .set_skip_invalid_links(search.skip_invalid_links);
+        let trace = self.inner.search_traced(&point, &mut search.inner);
+        search.start(&self.inner)?;
+
+        let results = PyList::empty(py);
+        for (candidate, layer) in trace.results {
+            let result = PyDict::new(py);
+            result.set_item("pid", candidate.pid.into_inner())?;
+            result.set_item("distance", candidate.distance())?;
+            result.set_item("layer", layer)?;
+            results.append(result)?;
+        }
+
+        let steps = PyList::empty(py);
+        for step in trace.steps {
+            let dict = PyDict::new(py);
+            match step {
+                instant_distance::TraceStep::Visit {
+                    layer,
+                    pid,
+                    distance,
+                } => {
+                    dict.set_item("kind", "visit")?;
+                    dict.set_item("layer", layer)?;
+                    dict.set_item("pid", pid.into_inner())?;
+                    dict.set_item("distance", distance)?;
+                }
+                instant_distance::TraceStep::Expand {
+                    layer,
+                    pid,
+                    distance,
+                } => {
+                    dict.set_item("kind", "expand")?;
+                    dict.set_item("layer", layer)?;
+                    dict.set_item("pid", pid.into_inner())?;
+                    dict.set_item("distance", distance)?;
+                }
+                instant_distance::TraceStep::Stop { layer, next } => {
+                    dict.set_item("kind", "stop")?;
+                    dict.set_item("layer", layer)?;
+                    dict.set_item("next", next)?;
+                }
+            }
+            steps.append(dict)?;
+        }
+
+        let trace = PyDict::new(py);
+        trace.set_item("results", results)?;
+        trace.set_item("steps", steps)?;
+        Ok(trace)
+    }
+
+    /// Search the index for points neighboring the given point that match all `filters`
+    ///
+    /// `filters` maps attribute names (passed to `build()`) to a condition: a single value
+    /// matches points with an equal value, and an `(min, max)` tuple of ints matches points with
+    /// a value within that (inclusive) range. Conditions are evaluated while searching, so this
+    /// is much faster than filtering results afterwards. Results are returned through `search`,
+    /// like `search()`. Restrictive filters may yield fewer results; raising `ef_search` helps.
+    fn search_filtered(
+        &self,
+        point: &PyAny,
+        filters: &PyDict,
+        search: &mut Search,
+    ) -> PyResult<()> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let conditions = self.attributes.conditions(filters)?;
+        search.inner.set_k(search.k);
+        search.inner.set_min_distance(search.min_distance);
+        search.inner.set_max_distance(search.max_distance);
+        search.inner.set_dedup_epsilon(search.dedup_epsilon);
+        search
+            .inner
+            .set_skip_invalid_links(search.skip_invalid_links);
+        let attributes = &self.attributes.inner;
+        let _ = self
+            .inner
+            .search_filtered(&point, attributes, &conditions, &mut search.inner);
+        search.start(&self.inner)
+    }
+
+    /// Compute the distances from the given point to the points with the given ids
+    ///
+    /// Returns an `array.array` of type "f" with one distance per id, in the order of `pids`;
+    /// wrap it with `numpy.frombuffer()` to get a numpy array without copying. This is useful
+    /// for re-ranking a list of candidates. The GIL is released while computing distances.
+    fn distances_to<'py>(
+        &self,
+        py: Python<'py>,
+        point: &PyAny,
+        pids: Vec<u32>,
+    ) -> PyResult<&'py PyAny> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let pids = pids
+            .into_iter()
+            .map(|pid| point_id(&self.inner, pid))
+            .collect::<PyResult<Vec<_>>>()?;
+        let distances = py.allow_threads(|| self.inner.distances_to(&point, &pids));
+        let bytes = Vec::from_iter(distances.iter().flat_map(|value| value.to_ne_bytes()));
+        let array = py.import("array")?.getattr("array")?;
+        array.call1(("f", PyBytes::new(py, &bytes)))
+    }
+
+    /// Return the distance from every point to its `k`th nearest other point, by id
+    ///
+    /// Returns an `array.array` of type "f" where position `i` holds the k-distance of the point
+    /// with id `i`, for density estimation or outlier detection. Points for which fewer than `k`
+    /// neighbors are found get infinity. Wrap the result with `numpy.frombuffer()` to get a
+    /// numpy array without copying. The GIL is released while searching.
+    fn k_distances<'py>(&self, py: Python<'py>, k: usize) -> PyResult<&'py PyAny> {
+        if k == 0 {
+            return Err(PyValueError::new_err("k must be at least 1"));
+        }
+
+        let distances = py.allow_threads(|| self.inner.k_distances(k));
+        let bytes = Vec::from_iter(distances.iter().flat_map(|value| value.to_ne_bytes()));
+        let array = py.import("array")?.getattr("array")?;
+        array.call1(("f", PyBytes::new(py, &bytes)))
+    }
+
+    /// Search the index for the `k` points nearest to the given point
+    ///
+    /// Returns a list of `Candidate`s, nearest first. Unlike `search()`, this does not take a
+    /// `Search` object: buffers are taken from a pool owned by the index, so every call uses
+    /// its own buffers. The GIL is released while searching, so this can safely be called from
+    /// multiple Python threads at once, and those searches run in parallel. At most
+    /// `ef_search` results are returned, even if `k` is larger.
+    fn search_pooled(&self, py: Python, point: &PyAny, k: usize) -> PyResult<Vec<Candidate>> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let mut search = self.pool.lock().unwrap().pop().unwrap_or_default();
+        let results = py.allow_threads(|| {
+            search.set_k(Some(k));
+            self.inner
+                .search(&point, &mut search)
+                .map(|candidate| Candidate {
+                    pid: candidate.pid.into_inner(),
+                    original_index: self.inner.original_index(candidate.pid),
+                    distance: candidate.distance(),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        self.pool.lock().unwrap().push(search);
+        Ok(results)
+    }
+
+    /// Search the index for the ids of the `k` points nearest to the given point
+    ///
+    /// Works like `search_pooled()`, but returns a list of ids (nearest first) instead of
+    /// `Candidate`s, for callers that don't need the distances.
+    fn search_ids(&self, py: Python, point: &PyAny, k: usize) -> PyResult<Vec<u32>> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let mut search = self.pool.lock().unwrap().pop().unwrap_or_default();
+        let ids = py.allow_threads(|| {
+            search.set_k(Some(k));
+            let ids = self.inner.search_ids(&point, &mut search);
+            Vec::from_iter(ids.map(|pid| pid.into_inner()))
+        });
+
+        self.pool.lock().unwrap().push(search);
+        Ok(ids)
+    }
+
+    /// Approximately count the points within `radius` of the given point
+    ///
+    /// This runs a regular search and walks the graph outward from every result within the
+    /// radius, without collecting the points, so it is much cheaper than a search returning
+    /// them all. The count is a lower bound, since points that can only be reached through
+    /// points outside the radius are missed, but it includes every result within the radius
+    /// that `search()` would return. The radius is a distance in terms of the index's metric; for the "euclidean"
+    /// metric, that is a squared distance. Like `search_pooled()`, this releases the GIL.
+    fn count_within_radius(&self, py: Python, point: &PyAny, radius: f32) -> PyResult<usize> {
+        let point = FloatArray::try_from(point)?.with_metric(self.metric, self.accumulator);
+        let mut search = self.pool.lock().unwrap().pop().unwrap_or_default();
+        let count =
+            py.allow_threads(|| self.inner.count_within_radius(&point, radius, &mut search));
+        self.pool.lock().unwrap().push(search);
+        Ok(count)
+    }
+
+    /// Return the graph in Graphviz DOT format, limited to the first `max_nodes` points
+    ///
+    /// Nodes are colored by their highest layer, and links are labeled with their distance.
+    #[args(max_nodes = "1000")]
+    fn export_dot(&self, max_nodes: usize) -> PyResult<String> {
+        let mut dot = Vec::new();
+        self.inner.export_dot(&mut dot, max_nodes)?;
+        Ok(String::from_utf8(dot).unwrap())
+    }
+
+    /// Return the sizes of the connected components of the zero layer graph, largest first
+    ///
+    /// A well-connected graph has a single component. Points in smaller components can be
+    /// hard or impossible to reach from the rest of the graph, which limits recall.
+    fn component_sizes(&self) -> Vec<usize> {
+        let components = self.inner.connected_components();
+        Vec::from_iter(components.iter().map(|component| component.len()))
+    }
+
+    /// Return all points as a single `array.array` of type "f", 300 values per point
+    ///
+    /// Row `i` holds the point with id `i`. The array supports the buffer protocol, so it can be
+    /// wrapped in a numpy array without copying, using `numpy.frombuffer(...).reshape(-1, 300)`.
+    /// Points shorter than 300 values are padded with zeros (for the Pearson metric, they hold
+    /// the mean-centered values).
+    fn export_vectors<'py>(&self, py: Python<'py>) -> PyResult<&'py PyAny> {
+        let vectors = self.inner.export_vectors();
+        let bytes = Vec::from_iter(vectors.iter().flat_map(|value| value.to_ne_bytes()));
+        let array = py.import("array")?.getattr("array")?;
+        array.call1(("f", PyBytes::new(py, &bytes)))
+    }
+
+    /// Return the ids of the neighbors of every point on the zero layer, nearest first
+    ///
+    /// The list at position `i` holds the neighbors of the point with id `i`. For indexes built
+    /// with `flat` set in the `Config`, all lists are empty.
+    fn export_adjacency(&self) -> Vec<Vec<u32>> {
+        let adjacency = self.inner.export_adjacency().into_iter();
+        Vec::from_iter(
+            adjacency.map(|ids| Vec::from_iter(ids.into_iter().map(|pid| pid.into_inner()))),
+        )
+    }
+
+    /// Measure the recall and throughput of searching for the given queries
+    ///
+    /// `ground_truth` contains a list of the ids of the true nearest neighbors for each query,
+    /// nearest first. Recall is measured over the first `k` results, searching with the given
+    /// `ef_search` value. Raises a `ValueError` for ids that don't refer to a point in this index.
+    /// The GIL is released while searching, so other Python threads keep running, but the
+    /// queries are searched one at a time to measure their latency.
+    fn evaluate(
+        &self,
+        py: Python,
+        queries: Vec<&PyAny>,
+        ground_truth: Vec<Vec<u32>>,
+        k: usize,
+        ef_search: usize,
+    ) -> PyResult<EvalReport> {
+        let queries = queries
+            .into_iter()
+            .map(
+                |value| Ok(FloatArray::try_from(value)?.with_metric(self.metric, self.accumulator)),
+            )
+            .collect::<Result<Vec<_>, PyErr>>()?;
+        if queries.len() != ground_truth.len() {
+            return Err(PyValueError::new_err(
+                "expected ground truth for every query",
+            ));
+        }
+
+        let ground_truth = ground_truth
+            .into_iter()
+            .map(|ids| {
+                ids.into_iter()
+                    .map(|pid| point_id(&self.inner, pid))
+                    .collect::<PyResult<Vec<_>>>()
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let report =
+            py.allow_threads(|| self.inner.evaluate(&queries, &ground_truth, k, ef_search));
+        Ok(report.into())
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for Hnsw {
+    fn __contains__(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+}
+
+/// Validate an id passed in from Python, which may not refer to a point in `hnsw`
+fn point_id<P: Point>(hnsw: &instant_distance::Hnsw<P>, pid: u32) -> PyResult<PointId> {
+    hnsw.point_id(pid)
+        .ok_or_else(|| PyValueError::new_err(format!("unknown id: {}", pid)))
+}
+
+/// Read a value appended to a dump, or the default if the file ends before it
+fn deserialize_or_default<T: DeserializeOwned + Default>(f: &mut impl Read) -> PyResult<T> {
+    match bincode::deserialize_from(f) {
+        Ok(value) => Ok(value),
+        Err(e) => match &*e {
+            bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(T::default()),
+            _ => Err(PyValueError::new_err(format!(
+                "deserialization error: {:?}",
+                e
+            ))),
+        },
+    }
+}
+
+/// Named attribute columns for an `Hnsw`, converted from and to Python values
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Attributes {
+    names: Vec<String>,
+    /// The name of each category, for category columns (empty for other columns)
+    labels: Vec<Vec<String>>,
+    inner: instant_distance::Attributes,
+}
+
+impl Attributes {
+    fn new(attributes: &PyDict, ids: &[PointId]) -> PyResult<Self> {
+        let (mut names, mut labels, mut columns) = (Vec::new(), Vec::new(), Vec::new());
+        for (name, values) in attributes {
+            let name = name.extract::<String>()?;
+            let values = values.downcast::<PyList>()?;
+            if values.len() != ids.len() {
+                return Err(PyValueError::new_err(format!(
+                    "expected {} values for attribute {:?}, got {}",
+                    ids.len(),
+                    name,
+                    values.len()
+                )));
+            }
+
+            // The type of the first value determines the type of the column
+            let (column, categories) = match values.iter().next() {
+                Some(first) if first.downcast::<PyBool>().is_ok() => {
+                    let values = values.extract::<Vec<bool>>()?;
+                    (instant_distance::Column::Flag(values), Vec::new())
+                }
+                Some(first) if first.downcast::<PyString>().is_ok() => {
+                    let mut known = HashMap::new();
+                    let mut categories = Vec::new();
+                    let mut numbers = Vec::with_capacity(ids.len());
+                    for value in values {
+                        let label = value.extract::<String>()?;
+                        let category = *known.entry(label.clone()).or_insert_with(|| {
+                            categories.push(label);
+                            categories.len() as u32 - 1
+                        });
+                        numbers.push(category);
+                    }
+                    (instant_distance::Column::Category(numbers), categories)
+                }
+                _ => {
+                    let values = values.extract::<Vec<i64>>()?;
+                    (instant_distance::Column::Int(values), Vec::new())
+                }
+            };
+
+            names.push(name);
+            labels.push(categories);
+            columns.push(column);
+        }
+
+        Ok(Self {
+            names,
+            labels,
+            inner: instant_distance::Attributes::new(columns, ids),
+        })
+    }
+
+    /// Convert a dict of filters passed to `search_filtered()` into conditions
+    fn conditions(&self, filters: &PyDict) -> PyResult<Vec<instant_distance::Condition>> {
+        let mut conditions = Vec::with_capacity(filters.len());
+        for (name, filter) in filters {
+            let name = name.extract::<&str>()?;
+            let column = match self.names.iter().position(|known| known == name) {
+                Some(column) => column,
+                None => {
+                    return Err(PyValueError::new_err(format!(
+                        "unknown attribute: {:?}",
+                        name
+                    )))
+                }
+            };
+
+            if let Ok((min, max)) = filter.extract::<(i64, i64)>() {
+                conditions.push(instant_distance::Condition::Range { column, min, max });
+                continue;
+            }
+
+            let value = match &self.inner.columns()[column] {
+                // Unknown categories are not an error, they just don't match any points
+                instant_distance::Column::Category(_) => {
+                    let label = filter.extract::<&str>()?;
+                    match self.labels[column].iter().position(|known| known == label) {
+                        Some(category) => category as i64,
+                        None => -1,
+                    }
+                }
+                instant_distance::Column::Int(_) => filter.extract::<i64>()?,
+                instant_distance::Column::Flag(_) => filter.extract::<bool>()? as i64,
+            };
+            conditions.push(instant_distance::Condition::Equals { column, value });
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// An instance of hierarchical navigable small worlds over sets of integers
+///
+/// By default, points are sets of integers (like token ids), given as lists; duplicate
+/// elements and their order are ignored. Their distance is the Jaccard distance: one minus
+/// the size of their intersection divided by the size of their union.
+///
+/// When built with `minhash=True`, points are MinHash signatures of equal length instead.
+/// Their distance estimates the Jaccard distance as the fraction of slots that differ.
+#[pyclass]
+struct SetHnsw {
+    inner: instant_distance::Hnsw<IntSet>,
+    minhash: bool,
+}
+
+#[pymethods]
+impl SetHnsw {
+    /// Build the index
+    ///
+    /// Returns the index along with a list of ids, one for every input point: the point at
+    /// position `i` in `input` gets id `ids[i]`. Search results refer to points by these ids.
+    #[staticmethod]
+    #[args(minhash = "false")]
+    fn build(input: &PyList, config: &Config, minhash: bool) -> PyResult<(Self, Vec<u32>)> {
+        let points = input
+            .into_iter()
+            .map(|value| IntSet::new(value.extract()?, minhash))
+            .collect::<Result<Vec<_>, PyErr>>()?;
+
+        if minhash
+            && points
+                .windows(2)
+                .any(|w| w[0].values.len() != w[1].values.len())
+        {
+            return Err(PyValueError::new_err("MinHash signatures differ in length"));
+        }
+
+        let builder = instant_distance::Builder::from(config);
+        let (inner, ids) = match config.dedup_exact {
+            true => builder.build_dedup(&points),
+            false => builder.build(&points),
+        };
+        let ids = Vec::from_iter(ids.into_iter().map(|pid| pid.into_inner()));
+        Ok((Self { inner, minhash }, ids))
+    }
+
+    /// Load an index from the given file name
+    #[staticmethod]
+    fn load(fname: &str) -> PyResult<Self> {
+        let (minhash, inner) =
+            bincode::deserialize_from::<_, (bool, instant_distance::Hnsw<IntSet>)>(
+                BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?),
+            )
+            .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))?;
+        Ok(Self { inner, minhash })
+    }
+
+    /// Dump the index to the given file name
+    fn dump(&self, fname: &str) -> PyResult<()> {
+        let f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
+        bincode::serialize_into(f, &(self.minhash, &self.inner))
+            .map_err(|e| PyValueError::new_err(format!("serialization error: {:?}", e)))?;
+        Ok(())
+    }
+
+    /// Whether the given id refers to a point in this index
+    fn contains(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+
+    /// The highest layer the point with the given id was assigned to during construction
+    fn layer_of(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.layer_of(point_id(&self.inner, pid)?))
+    }
+
+    /// The position of the point with the given id in the input list passed to `build()`
+    ///
+    /// This is the inverse of the list of ids returned by `build()`, so it can be used to trace
+    /// a `Candidate`'s `pid` back to the input row.
+    fn original_index(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.original_index(point_id(&self.inner, pid)?))
+    }
+
+    /// Search the index for points neighboring the given set (or signature)
+    ///
+    /// Works like `Hnsw.search()`; iterate over the `Search` to get the results.
+    fn search(&self, point: Vec<u32>, search: &mut Search) -> PyResult<()> {
+        let point = IntSet::new(point, self.minhash)?;
+        search.inner.set_k(search.k);
+        search.inner.set_min_distance(search.min_distance);
+        search.inner.set_max_distance(search.max_distance);
+        search.inner.set_dedup_epsilon(search.dedup_epsilon);
+        search
+            .inner
+            .set_skip_invalid_links(search.skip_invalid_links);
         let _ = self.inner.search(&point, &mut search.inner);
-        search.cur = Some(0);
+        search.start(&self.inner)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for SetHnsw {
+    fn __contains__(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+}
+
+/// An instance of hierarchical navigable small worlds over arbitrary Python objects
+///
+/// Points can be any objects with a `distance(other)` method returning the distance to
+/// another point as a float; both the indexed points and the queries are passed to it. This
+/// makes it possible to index tuples wrapped in a small class, strings with an edit distance,
+/// or anything else for which a metric can be written in Python:
+///
+/// ```python
+/// class Place:
+///     def __init__(self, lat, lon):
+///         self.lat, self.lon = lat, lon
+///
+///     def distance(self, other):
+///         return math.hypot(self.lat - other.lat, self.lon - other.lon)
+///
+/// hnsw, ids = ObjectHnsw.build([Place(52.4, 4.9), Place(48.9, 2.4)], Config())
+/// ```
+///
+/// Every distance computation calls into Python, so building and searching are several orders
+/// of magnitude slower than with `Hnsw`, and parallel builds are serialized on the GIL. Prefer
+/// `Hnsw` or `SetHnsw` whenever the points can be represented as vectors or sets. Exceptions
+/// raised by `distance()` are printed and treated as an infinite distance.
+///
+/// Indexes are dumped by pickling the points, so they must support `pickle` (which plain
+/// classes do by default, and others can through `__getstate__()` and `__setstate__()`).
+/// Loading an index unpickles its points, which can run arbitrary code: only load files from
+/// trusted sources.
+#[pyclass]
+struct ObjectHnsw {
+    inner: instant_distance::Hnsw<ObjectPoint>,
+}
+
+#[pymethods]
+impl ObjectHnsw {
+    /// Build the index
+    ///
+    /// Returns the index along with a list of ids, one for every input point: the point at
+    /// position `i` in `input` gets id `ids[i]`. Search results refer to points by these ids.
+    /// The `Config`'s `metric` and `accumulator` are ignored, and `dedup_exact` is not
+    /// supported, since objects can't be hashed from Rust.
+    #[staticmethod]
+    fn build(input: &PyList, config: &Config) -> PyResult<(Self, Vec<u32>)> {
+        if config.dedup_exact {
+            return Err(PyValueError::new_err(
+                "dedup_exact is not supported for objects",
+            ));
+        }
+
+        let points = Vec::from_iter(input.into_iter().map(|obj| ObjectPoint(obj.into())));
+        let builder = instant_distance::Builder::from(config);
+        let (inner, ids) = input.py().allow_threads(|| builder.build(&points));
+        let ids = Vec::from_iter(ids.into_iter().map(|pid| pid.into_inner()));
+        Ok((Self { inner }, ids))
+    }
+
+    /// Load an index from the given file name
+    ///
+    /// The points are restored with `pickle`, so a malicious file can execute arbitrary code
+    /// while loading. Never load an index from an untrusted source.
+    #[staticmethod]
+    fn load(py: Python, fname: &str) -> PyResult<Self> {
+        let mut f = BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?);
+        let graph = instant_distance::Hnsw::load_graph_only(&mut f)?;
+        let mut pickled = Vec::new();
+        f.read_to_end(&mut pickled)?;
+
+        let points = py
+            .import("pickle")?
+            .call1("loads", (PyBytes::new(py, &pickled),))?
+            .extract::<Vec<PyObject>>()?;
+        if points.len() != graph.iter().count() {
+            return Err(PyValueError::new_err(
+                "number of points does not match graph",
+            ));
+        }
+
+        let mut points = points.into_iter();
+        let inner = graph.map_points(|_, ()| ObjectPoint(points.next().unwrap()));
+        Ok(Self { inner })
+    }
+
+    /// Dump the index to the given file name
+    ///
+    /// This writes the graph, followed by the pickled list of points.
+    fn dump(&self, py: Python, fname: &str) -> PyResult<()> {
+        let points = Vec::from_iter(self.inner.iter().map(|(_, point)| &point.0));
+        let pickled = py.import("pickle")?.call1("dumps", (points,))?;
+        let mut f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
+        self.inner.dump_graph_only(&mut f)?;
+        f.write_all(pickled.downcast::<PyBytes>()?.as_bytes())?;
+        f.flush()?;
         Ok(())
     }
+
+    /// The point with the given id
+    fn get(&self, pid: u32) -> PyResult<PyObject> {
+        Ok(self.inner[point_id(&self.inner, pid)?].0.clone())
+    }
+
+    /// Whether the given id refers to a point in this index
+    fn contains(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+
+    /// The highest layer the point with the given id was assigned to during construction
+    fn layer_of(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.layer_of(point_id(&self.inner, pid)?))
+    }
+
+    /// The position of the point with the given id in the input list passed to `build()`
+    fn original_index(&self, pid: u32) -> PyResult<usize> {
+        Ok(self.inner.original_index(point_id(&self.inner, pid)?))
+    }
+
+    /// Search the index for points neighboring the given object
+    ///
+    /// Works like `Hnsw.search()`; iterate over the `Search` to get the results. The GIL is
+    /// released while searching, and reacquired for every distance computation.
+    fn search(&self, py: Python, point: &PyAny, search: &mut Search) -> PyResult<()> {
+        let point = ObjectPoint(point.into());
+        search.inner.set_k(search.k);
+        search.inner.set_min_distance(search.min_distance);
+        search.inner.set_max_distance(search.max_distance);
+        search.inner.set_dedup_epsilon(search.dedup_epsilon);
+        search
+            .inner
+            .set_skip_invalid_links(search.skip_invalid_links);
+        py.allow_threads(|| {
+            let _ = self.inner.search(&point, &mut search.inner);
+        });
+        search.start(&self.inner)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for ObjectHnsw {
+    fn __contains__(&self, pid: u32) -> bool {
+        self.inner.point_id(pid).is_some()
+    }
+}
+
+/// A Python object used as a point, measuring distances through its `distance()` method
+#[derive(Clone)]
+struct ObjectPoint(PyObject);
+
+impl Point for ObjectPoint {
+    fn distance(&self, rhs: &Self) -> f32 {
+        Python::with_gil(|py| {
+            let distance = self
+                .0
+                .call_method1(py, "distance", (rhs.0.clone_ref(py),))
+                .and_then(|d| d.extract::<f32>(py));
+            match distance {
+                Ok(distance) => distance,
+                Err(e) => {
+                    e.print(py);
+                    f32::INFINITY
+                }
+            }
+        })
+    }
 }
 
 /// Search buffer and result set
+///
+/// A `Search` can be reused for searches on any index.
 #[pyclass]
 struct Search {
     inner: instant_distance::Search,
     cur: Option<usize>,
+    /// Position in the input list of each result of the last search
+    original: Vec<usize>,
+    /// Maximum number of results to yield, or `None` to yield all `ef_search` results
+    #[pyo3(get, set)]
+    k: Option<usize>,
+    /// Exclude points closer than this distance from the results
+    ///
+    /// Closer points are still traversed, so the search continues outward to find
+    /// results beyond the minimum distance.
+    #[pyo3(get, set)]
+    min_distance: Option<f32>,
+    /// Exclude points farther than this distance from the results
+    ///
+    /// This doesn't change how the index is searched: results beyond the maximum are dropped
+    /// afterwards, so fewer than `k` results may be returned.
+    #[pyo3(get, set)]
+    max_distance: Option<f32>,
+    /// Drop results within this distance of a nearer result, to skip near-duplicates
+    ///
+    /// To make up for dropped results, the search looks deeper than `ef_search` until it has
+    /// found `k` results (or `ef_search`, if `k` is not set), or has considered every point.
+    #[pyo3(get, set)]
+    dedup_epsilon: Option<f32>,
+    /// Skip links to points that don't exist instead of crashing, for damaged indexes
+    ///
+    /// Searches that skip links emit a `RuntimeWarning` and return best-effort results; see
+    /// `Hnsw.verify_integrity()`. This makes searches slightly slower, so it is off by default.
+    #[pyo3(get, set)]
+    skip_invalid_links: bool,
+}
+
+#[pymethods]
+impl Search {
+    /// Initialize an empty search buffer
+    #[new]
+    fn new() -> Self {
+        Self {
+            inner: instant_distance::Search::default(),
+            cur: None,
+            original: Vec::new(),
+            k: None,
+            min_distance: None,
+            max_distance: None,
+            dedup_epsilon: None,
+            skip_invalid_links: false,
+        }
+    }
+
+    /// Discard the results of the last search
+    ///
+    /// The `k`, `min_distance`, `max_distance` and `dedup_epsilon` attributes are kept. Reusing
+    /// a `Search` for another search, including on a different index, does not require calling
+    /// this first.
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.cur = None;
+        self.original.clear();
+    }
+
+    /// Return the ids and distances of all results from the last search, nearest first
+    ///
+    /// The results are returned as two `array.array`s (of type "I" and "f"), without creating
+    /// a `Candidate` for every result. Both support the buffer protocol, so they can be wrapped
+    /// in numpy arrays without copying, using `numpy.frombuffer()`. Like `len()`, this takes
+    /// `k` into account but does not depend on how many results have already been iterated over.
+    fn as_arrays<'py>(&self, py: Python<'py>) -> PyResult<(&'py PyAny, &'py PyAny)> {
+        let len = self.inner.len();
+        let (mut pids, mut distances) = (Vec::with_capacity(len * 4), Vec::with_capacity(len * 4));
+        for candidate in (0..len).filter_map(|i| self.inner.get(i)) {
+            pids.extend_from_slice(&candidate.pid.into_inner().to_ne_bytes());
+            distances.extend_from_slice(&candidate.distance().to_ne_bytes());
+        }
+
+        let array = py.import("array")?.getattr("array")?;
+        Ok((
+            array.call1(("I", PyBytes::new(py, &pids)))?,
+            array.call1(("f", PyBytes::new(py, &distances)))?,
+        ))
+    }
 }
 
-#[pymethods]
 impl Search {
-    /// Initialize an empty search buffer
-    #[new]
-    fn new() -> Self {
-        Self {
-            inner: instant_distance::Search::default(),
-            cur: None,
+    /// Prepare to iterate over the results of a search on `hnsw`
+    ///
+    /// Warns if the search skipped invalid links, since its results may be incomplete.
+    fn start<P: Point>(&mut self, hnsw: &instant_distance::Hnsw<P>) -> PyResult<()> {
+        let results = (0..self.inner.len()).filter_map(|i| self.inner.get(i));
+        let original = Vec::from_iter(results.map(|c| hnsw.original_index(c.pid)));
+        self.original = original;
+        self.cur = Some(0);
+        match self.inner.invalid_links() {
+            0 => Ok(()),
+            skipped => Python::with_gil(|py| {
+                let category = py.import("builtins")?.getattr("RuntimeWarning")?;
+                let message = format!("search skipped {} invalid links", skipped);
+                PyErr::warn(py, category, &message, 1)
+            }),
         }
     }
 }
 
+#[pyproto]
+impl PySequenceProtocol for Search {
+    /// The number of results from the last search
+    ///
+    /// This does not depend on how many results have already been iterated over.
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+}
+
 #[pyproto]
 impl PyIterProtocol for Search {
     fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
@@ -122,6 +1426,7 @@ impl PyIterProtocol for Search {
         slf.cur = Some(idx + 1);
         Some(Candidate {
             pid: candidate.pid.into_inner(),
+            original_index: slf.original[idx],
             distance: candidate.distance(),
         })
     }
@@ -150,6 +1455,20 @@ struct Config {
     /// in order to get better results on clustered data points.
     #[pyo3(get, set)]
     heuristic: Option<Heuristic>,
+    /// Whether to store points that are exactly equal to an earlier point only once
+    ///
+    /// Duplicate points get the same id as their first occurrence in the returned ids.
+    /// Only bit-exact duplicates are detected, not points that are merely very close.
+    #[pyo3(get, set)]
+    dedup_exact: bool,
+    /// Whether to skip building a graph, comparing queries against every point instead
+    ///
+    /// Searches always find the exact nearest neighbors, and can be faster than searching a
+    /// graph for small datasets (up to a few thousand points).
+    #[pyo3(get, set)]
+    flat: bool,
+    metric: Metric,
+    accumulator: Accumulator,
 }
 
 #[pymethods]
@@ -165,8 +1484,59 @@ impl Config {
             ml,
             seed,
             heuristic,
+            dedup_exact: false,
+            flat: false,
+            metric: Metric::default(),
+            accumulator: Accumulator::default(),
         }
     }
+
+    /// Distance metric: "euclidean" (squared Euclidean distance), "cosine", "angular" or
+    /// "pearson"
+    ///
+    /// The cosine distance is one minus the cosine similarity. Vector norms are computed once
+    /// for every point, so it is about as fast as the default "euclidean" metric.
+    ///
+    /// The angular distance matches Annoy's definition: `sqrt(2 - 2 * cos(a, b))`, which is the
+    /// Euclidean distance between the normalized vectors. It ranks results the same way as the
+    /// cosine distance, but ranges from 0 to 2. Like in Annoy, the distance to a zero vector is
+    /// `sqrt(2)`.
+    ///
+    /// The Pearson distance is one minus the Pearson correlation: points and queries are
+    /// mean-centered when they are passed in, after which it is the same as the cosine
+    /// distance. The mean only covers the values that were passed in, not the zero padding of
+    /// points shorter than 300 values.
+    ///
+    /// Metrics implemented in Python can be added through `register_metric()`, after which
+    /// they can be selected by name.
+    #[getter]
+    fn metric(&self) -> &'static str {
+        self.metric.name()
+    }
+
+    #[setter]
+    fn set_metric(&mut self, metric: &str) -> PyResult<()> {
+        self.metric = metric.parse()?;
+        Ok(())
+    }
+
+    /// Float type used to add up distances: "f32" (the default) or "f64"
+    ///
+    /// Accumulating in f64 loses less precision to rounding, which can change the order of
+    /// results that are almost equally far away. It uses a scalar loop instead of the SIMD
+    /// kernel, so distances are several times slower to compute. For 300 dimensions, f32 is
+    /// accurate enough for almost all data. Points are always stored as f32, so there is no
+    /// integer accumulator.
+    #[getter]
+    fn accumulator(&self) -> &'static str {
+        self.accumulator.name()
+    }
+
+    #[setter]
+    fn set_accumulator(&mut self, accumulator: &str) -> PyResult<()> {
+        self.accumulator = accumulator.parse()?;
+        Ok(())
+    }
 }
 
 impl From<&Config> for instant_distance::Builder {
@@ -177,6 +1547,10 @@ impl From<&Config> for instant_distance::Builder {
             ml,
             seed,
             heuristic,
+            dedup_exact: _,
+            flat,
+            metric: _,
+            accumulator: _,
         } = *py;
         Self::default()
             .ef_search(ef_search)
@@ -184,6 +1558,7 @@ impl From<&Config> for instant_distance::Builder {
             .ml(ml)
             .seed(seed)
             .select_heuristic(heuristic.map(|h| h.into()))
+            .flat(flat)
     }
 }
 
@@ -225,6 +1600,19 @@ impl Default for Heuristic {
     }
 }
 
+impl From<instant_distance::Heuristic> for Heuristic {
+    fn from(heuristic: instant_distance::Heuristic) -> Self {
+        let instant_distance::Heuristic {
+            extend_candidates,
+            keep_pruned,
+        } = heuristic;
+        Self {
+            extend_candidates,
+            keep_pruned,
+        }
+    }
+}
+
 impl From<Heuristic> for instant_distance::Heuristic {
     fn from(py: Heuristic) -> Self {
         let Heuristic {
@@ -244,76 +1632,741 @@ struct Candidate {
     /// Identifier for the neighboring point
     #[pyo3(get)]
     pid: u32,
+    /// Position of the neighboring point in the input list passed to `build()`
+    #[pyo3(get)]
+    original_index: usize,
     /// Distance to the neighboring point
     #[pyo3(get)]
     distance: f32,
 }
 
+/// Recall and throughput measured by `Hnsw.evaluate()`
+#[pyclass]
+struct EvalReport {
+    /// Mean recall over all queries
+    #[pyo3(get)]
+    mean_recall: f32,
+    /// Lowest recall for any query
+    #[pyo3(get)]
+    min_recall: f32,
+    /// Highest recall for any query
+    #[pyo3(get)]
+    max_recall: f32,
+    /// Lower and upper bound of a 95% confidence interval for the mean recall
+    ///
+    /// This is the Wilson score interval for the fraction of true neighbors found; see the
+    /// Rust documentation of `EvalReport`.
+    #[pyo3(get)]
+    recall_ci: (f32, f32),
+    /// Number of queries searched per second
+    #[pyo3(get)]
+    qps: f64,
+}
+
+impl From<instant_distance::EvalReport> for EvalReport {
+    fn from(report: instant_distance::EvalReport) -> Self {
+        let instant_distance::EvalReport {
+            mean_recall,
+            min_recall,
+            max_recall,
+            recall_ci,
+            qps,
+        } = report;
+        Self {
+            mean_recall,
+            min_recall,
+            max_recall,
+            recall_ci,
+            qps,
+        }
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for EvalReport {
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!(
+            "instant_distance.EvalReport(mean_recall={}, min_recall={}, max_recall={}, \
+             recall_ci=({}, {}), qps={})",
+            self.mean_recall,
+            self.min_recall,
+            self.max_recall,
+            self.recall_ci.0,
+            self.recall_ci.1,
+            self.qps
+        ))
+    }
+}
+
 #[pyproto]
 impl PyObjectProtocol for Candidate {
     fn __repr__(&self) -> PyResult<String> {
         Ok(format!(
-            "instant_distance.Candidate(pid={}, distance={})",
-            self.pid, self.distance
+            "instant_distance.Candidate(pid={}, original_index={}, distance={})",
+            self.pid, self.original_index, self.distance
         ))
     }
 }
 
-#[repr(align(32))]
+/// A 300-element float vector, along with the metric used to compare it to other vectors
+///
+/// Only the values are serialized; `Hnsw` stores the metric, accumulator and (for metrics that
+/// use them) the norms separately, and restores them when loading an index.
+///
+/// The distance kernels use aligned loads, which rely on `repr(C, align(32))` (keeping `values`
+/// at the start of the struct) and on `DIMENSIONS % 8 == 4` (so the trailing four values start
+/// on a 16-byte boundary). Every vector passed in from Python, points and queries alike, is
+/// copied into a `FloatArray` first, so the kernels never see a caller-provided buffer.
+#[repr(C, align(32))]
 #[derive(Clone, Deserialize, Serialize)]
-struct FloatArray(#[serde(with = "BigArray")] [f32; DIMENSIONS]);
+struct FloatArray {
+    #[serde(with = "BigArray")]
+    values: [f32; DIMENSIONS],
+    /// L2 norm of `values` (after mean-centering, for the Pearson metric)
+    ///
+    /// This is computed for every metric, since searches can override the metric per query.
+    /// Dumps only store it for metrics that use it; it is recomputed for the others on load.
+    #[serde(skip)]
+    norm: f32,
+    /// Number of values passed in, before padding; zero for deserialized points
+    #[serde(skip)]
+    len: usize,
+    #[serde(skip)]
+    metric: Metric,
+    #[serde(skip)]
+    accumulator: Accumulator,
+    /// The dimensions distances from this point are computed over, for masked queries
+    #[serde(skip)]
+    mask: Option<Arc<[usize]>>,
+}
+
+impl FloatArray {
+    /// Set the metric and accumulator, precomputing the values they depend on
+    fn with_metric(mut self, metric: Metric, accumulator: Accumulator) -> Self {
+        self.metric = metric;
+        self.accumulator = accumulator;
+        if let Metric::Pearson = metric {
+            // Deserialized points were already centered before they were stored
+            let values = &mut self.values[..self.len];
+            let mean = values.iter().sum::<f32>() / max(values.len(), 1) as f32;
+            values.iter_mut().for_each(|value| *value -= mean);
+        }
+
+        self.norm = self.dot(&self).sqrt();
+        self
+    }
+
+    /// Set the metric and accumulator, using a norm stored with the index
+    fn with_norm(mut self, metric: Metric, accumulator: Accumulator, norm: f32) -> Self {
+        self.metric = metric;
+        self.accumulator = accumulator;
+        self.norm = norm;
+        self
+    }
+
+    /// Compute distances from this point over the dimensions selected by `mask` only
+    ///
+    /// `mask` holds a flag for each of the leading dimensions; dimensions past its end are
+    /// skipped, like the ones whose flag is `false`.
+    fn with_mask(mut self, mask: &[bool]) -> PyResult<Self> {
+        if mask.len() > DIMENSIONS {
+            return Err(PyValueError::new_err("mask too long"));
+        }
+
+        let dims = Vec::from_iter((0..mask.len()).filter(|&i| mask[i]));
+        if dims.is_empty() {
+            return Err(PyValueError::new_err("mask selects no dimensions"));
+        }
+
+        self.mask = Some(dims.into());
+        Ok(self)
+    }
+
+    /// The distance over the dimensions in `dims`, as if the other dimensions were zero
+    ///
+    /// This uses a scalar loop accumulating in double precision rather than the SIMD kernels,
+    /// since the norms of `rhs` must be recomputed over the selected dimensions for every call.
+    fn masked_distance(&self, rhs: &Self, dims: &[usize]) -> f32 {
+        let pairs = dims
+            .iter()
+            .map(|&i| (self.values[i] as f64, rhs.values[i] as f64));
+        let cosine = || {
+            let (mut dot, mut lhs_norm, mut rhs_norm) = (0.0, 0.0, 0.0);
+            for (a, b) in pairs.clone() {
+                dot += a * b;
+                lhs_norm += a * a;
+                rhs_norm += b * b;
+            }
+
+            match lhs_norm * rhs_norm > 0.0 {
+                true => (dot / (lhs_norm * rhs_norm).sqrt()) as f32,
+                false => 0.0,
+            }
+        };
+
+        match self.metric {
+            Metric::Euclidean => pairs.clone().map(|(a, b)| (a - b).powi(2)).sum::<f64>() as f32,
+            Metric::Cosine | Metric::Pearson => 1.0 - cosine(),
+            Metric::Angular => (2.0 - 2.0 * cosine()).max(0.0).sqrt(),
+            Metric::Custom(id) => {
+                let masked = |point: &Self| {
+                    let mut masked = point.clone();
+                    masked.values = [0.0; DIMENSIONS];
+                    dims.iter()
+                        .for_each(|&i| masked.values[i] = point.values[i]);
+                    masked
+                };
+                custom_distance(id, &masked(self), &masked(rhs))
+            }
+        }
+    }
+
+    /// Cosine similarity, or zero if either vector is zero
+    fn cosine(&self, rhs: &Self) -> f32 {
+        let norms = self.norm * rhs.norm;
+        match norms > 0.0 {
+            true => self.dot(rhs) / norms,
+            false => 0.0,
+        }
+    }
+
+    /// Squared Euclidean distance, stopping early once the partial sum exceeds `bound`
+    ///
+    /// The partial sum is checked every `BOUND_CHECK_CHUNKS` chunks of 8 values, and only if
+    /// `bound` is finite. The F64 accumulator always computes the full distance.
+    fn squared_euclidean(&self, rhs: &Self, bound: f32) -> f32 {
+        if let Accumulator::F64 = self.accumulator {
+            let iter = self.values.iter().zip(rhs.values.iter());
+            return iter
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum::<f64>() as f32;
+        }
+
+        use std::arch::x86_64::{
+            _mm256_fmadd_ps, _mm256_load_ps, _mm256_setzero_ps, _mm256_sub_ps, _mm_fmadd_ps,
+            _mm_load_ps, _mm_sub_ps,
+        };
+        debug_assert_eq!(self.values.len() % 8, 4);
+
+        unsafe {
+            let mut acc_8x = _mm256_setzero_ps();
+            let chunks = self.values.chunks_exact(8).zip(rhs.values.chunks_exact(8));
+            for (i, (lh_slice, rh_slice)) in chunks.enumerate() {
+                let lh_8x = _mm256_load_ps(lh_slice.as_ptr());
+                let rh_8x = _mm256_load_ps(rh_slice.as_ptr());
+                let diff = _mm256_sub_ps(lh_8x, rh_8x);
+                acc_8x = _mm256_fmadd_ps(diff, diff, acc_8x);
+
+                if bound.is_finite() && (i + 1) % BOUND_CHECK_CHUNKS == 0 {
+                    let partial = horizontal_sum(fold_halves(acc_8x));
+                    if partial > bound {
+                        return partial;
+                    }
+                }
+            }
+
+            let lh_4x = _mm_load_ps(self.values[DIMENSIONS - 4..].as_ptr());
+            let rh_4x = _mm_load_ps(rhs.values[DIMENSIONS - 4..].as_ptr());
+            let diff = _mm_sub_ps(lh_4x, rh_4x);
+            horizontal_sum(_mm_fmadd_ps(diff, diff, fold_halves(acc_8x)))
+        }
+    }
+
+    fn dot(&self, rhs: &Self) -> f32 {
+        if let Accumulator::F64 = self.accumulator {
+            let iter = self.values.iter().zip(rhs.values.iter());
+            return iter.map(|(&a, &b)| a as f64 * b as f64).sum::<f64>() as f32;
+        }
+
+        use std::arch::x86_64::{
+            _mm256_fmadd_ps, _mm256_load_ps, _mm256_setzero_ps, _mm_fmadd_ps, _mm_load_ps,
+        };
+        debug_assert_eq!(self.values.len() % 8, 4);
+
+        unsafe {
+            let mut acc_8x = _mm256_setzero_ps();
+            for (lh_slice, rh_slice) in self.values.chunks_exact(8).zip(rhs.values.chunks_exact(8))
+            {
+                let lh_8x = _mm256_load_ps(lh_slice.as_ptr());
+                let rh_8x = _mm256_load_ps(rh_slice.as_ptr());
+                acc_8x = _mm256_fmadd_ps(lh_8x, rh_8x, acc_8x);
+            }
+
+            let lh_4x = _mm_load_ps(self.values[DIMENSIONS - 4..].as_ptr());
+            let rh_4x = _mm_load_ps(rhs.values[DIMENSIONS - 4..].as_ptr());
+            horizontal_sum(_mm_fmadd_ps(lh_4x, rh_4x, fold_halves(acc_8x)))
+        }
+    }
+}
+
+/// Add the upper and lower halves of `acc_8x`
+unsafe fn fold_halves(acc_8x: __m256) -> __m128 {
+    use std::arch::x86_64::{_mm256_castps256_ps128, _mm256_extractf128_ps, _mm_add_ps};
+
+    let upper = _mm256_extractf128_ps(acc_8x, 1); // upper half
+    let lower = _mm256_castps256_ps128(acc_8x); // lower half
+    _mm_add_ps(upper, lower) // sum halves
+}
+
+/// Add up all lanes of `acc_4x`
+unsafe fn horizontal_sum(mut acc_4x: __m128) -> f32 {
+    use std::arch::x86_64::{_mm_add_ps, _mm_add_ss, _mm_cvtss_f32, _mm_movehl_ps, _mm_shuffle_ps};
+
+    let lower = _mm_movehl_ps(acc_4x, acc_4x);
+    acc_4x = _mm_add_ps(acc_4x, lower);
+    let upper = _mm_shuffle_ps(acc_4x, acc_4x, 0x1);
+    acc_4x = _mm_add_ss(acc_4x, upper);
+    _mm_cvtss_f32(acc_4x)
+}
+
+impl PartialEq for FloatArray {
+    fn eq(&self, other: &Self) -> bool {
+        self.values
+            .iter()
+            .zip(other.values.iter())
+            .all(|(a, b)| a.to_bits() == b.to_bits())
+    }
+}
+
+impl Eq for FloatArray {}
+
+impl Hash for FloatArray {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for value in self.values.iter() {
+            value.to_bits().hash(state);
+        }
+    }
+}
 
 impl TryFrom<&PyAny> for FloatArray {
     type Error = PyErr;
 
     fn try_from(value: &PyAny) -> Result<Self, Self::Error> {
-        let mut new = FloatArray([0.0; DIMENSIONS]);
+        let mut new = FloatArray {
+            values: [0.0; DIMENSIONS],
+            norm: 0.0,
+            len: 0,
+            metric: Metric::default(),
+            accumulator: Accumulator::default(),
+            mask: None,
+        };
+        // Objects exposing a float32 buffer (like numpy arrays) are copied in a single pass,
+        // avoiding the per-element conversion used for other iterables. Float64 buffers are
+        // copied and then converted.
+        //
+        // Even aligned, contiguous buffers are copied: searches take a `&FloatArray`, which
+        // carries the norm, metric and mask next to the values, so a caller's buffer can't be
+        // borrowed as a query. Copying 300 values is cheap next to the search itself.
+        if let Ok(buffer) = PyBuffer::<f32>::get(value) {
+            new.len = buffer_len(&buffer)?;
+            buffer.copy_to_slice(value.py(), &mut new.values[..new.len])?;
+            return Ok(new);
+        } else if let Ok(buffer) = PyBuffer::<f64>::get(value) {
+            new.len = buffer_len(&buffer)?;
+            let values = buffer.to_vec(value.py())?;
+            for (dst, src) in new.values.iter_mut().zip(values) {
+                *dst = src as f32;
+            }
+            return Ok(new);
+        }
+
         for (i, val) in value.iter()?.enumerate() {
-            match i >= DIMENSIONS {
-                true => return Err(PyTypeError::new_err("point array too long")),
-                false => new.0[i] = val?.extract::<f32>()?,
+            if i >= DIMENSIONS {
+                return Err(PyTypeError::new_err("point array too long"));
             }
+
+            let val = val?;
+            new.values[i] = val.extract::<f32>().map_err(|_| {
+                PyTypeError::new_err(format!(
+                    "expected point values to be floats, got {}",
+                    val.get_type().name().unwrap_or("object")
+                ))
+            })?;
+            new.len = i + 1;
         }
         Ok(new)
     }
 }
 
+/// The number of values in a buffer holding a point, which must be one-dimensional
+fn buffer_len<T: pyo3::buffer::Element>(buffer: &PyBuffer<T>) -> PyResult<usize> {
+    if buffer.dimensions() != 1 {
+        return Err(PyValueError::new_err(format!(
+            "expected a 1-dimensional array for a point, got {} dimensions",
+            buffer.dimensions()
+        )));
+    }
+
+    match buffer.item_count() {
+        len if len > DIMENSIONS => Err(PyTypeError::new_err("point array too long")),
+        len => Ok(len),
+    }
+}
+
+/// A set of integers, or a MinHash signature
+#[derive(Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
+struct IntSet {
+    /// Sorted and deduplicated for sets, in slot order for signatures
+    values: Vec<u32>,
+    minhash: bool,
+}
+
+impl IntSet {
+    fn new(mut values: Vec<u32>, minhash: bool) -> PyResult<Self> {
+        if !minhash {
+            values.sort_unstable();
+            values.dedup();
+        } else if values.is_empty() {
+            return Err(PyValueError::new_err("empty MinHash signature"));
+        }
+
+        Ok(Self { values, minhash })
+    }
+}
+
+impl Point for IntSet {
+    fn distance(&self, rhs: &Self) -> f32 {
+        if self.minhash {
+            let len = self.values.len().max(rhs.values.len());
+            let same = self
+                .values
+                .iter()
+                .zip(&rhs.values)
+                .filter(|(a, b)| a == b)
+                .count();
+            return 1.0 - same as f32 / len as f32;
+        }
+
+        // Count the intersection by merging the sorted values
+        let (mut i, mut j, mut shared) = (0, 0, 0);
+        while i < self.values.len() && j < rhs.values.len() {
+            match self.values[i].cmp(&rhs.values[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    shared += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+
+        let union = self.values.len() + rhs.values.len() - shared;
+        match union {
+            0 => 0.0,
+            _ => 1.0 - shared as f32 / union as f32,
+        }
+    }
+}
+
 big_array! { BigArray; DIMENSIONS }
 
 impl Point for FloatArray {
     fn distance(&self, rhs: &Self) -> f32 {
-        use std::arch::x86_64::{
-            _mm256_castps256_ps128, _mm256_extractf128_ps, _mm256_fmadd_ps, _mm256_load_ps,
-            _mm256_setzero_ps, _mm256_sub_ps, _mm_add_ps, _mm_add_ss, _mm_cvtss_f32, _mm_fmadd_ps,
-            _mm_load_ps, _mm_movehl_ps, _mm_shuffle_ps, _mm_sub_ps,
+        if let Some(dims) = &self.mask {
+            return self.masked_distance(rhs, dims);
+        }
+
+        match self.metric {
+            Metric::Euclidean => self.squared_euclidean(rhs, f32::INFINITY),
+            Metric::Cosine | Metric::Pearson => 1.0 - self.cosine(rhs),
+            Metric::Angular => (2.0 - 2.0 * self.cosine(rhs)).max(0.0).sqrt(),
+            Metric::Custom(id) => custom_distance(id, self, rhs),
+        }
+    }
+
+    fn distance_bounded(&self, rhs: &Self, bound: f32) -> f32 {
+        match self.metric {
+            Metric::Euclidean if self.mask.is_none() => self.squared_euclidean(rhs, bound),
+            _ => self.distance(rhs),
+        }
+    }
+}
+
+impl AsRef<[f32]> for FloatArray {
+    fn as_ref(&self) -> &[f32] {
+        &self.values
+    }
+}
+
+impl Centroid for FloatArray {
+    fn centroid(points: &[&Self]) -> Self {
+        let mut values = [0.0; DIMENSIONS];
+        for point in points {
+            for (sum, value) in values.iter_mut().zip(point.values.iter()) {
+                *sum += value;
+            }
+        }
+
+        let n = points.len() as f32;
+        values.iter_mut().for_each(|value| *value /= n);
+        let (metric, accumulator) = (points[0].metric, points[0].accumulator);
+        // The points are already mean-centered for the Pearson metric, and so is their mean
+        let new = FloatArray {
+            values,
+            norm: 0.0,
+            len: 0,
+            metric,
+            accumulator,
+            mask: None,
         };
-        debug_assert_eq!(self.0.len() % 8, 4);
+        new.with_metric(metric, accumulator)
+    }
+}
 
-        unsafe {
-            let mut acc_8x = _mm256_setzero_ps();
-            for (lh_slice, rh_slice) in self.0.chunks_exact(8).zip(rhs.0.chunks_exact(8)) {
-                let lh_8x = _mm256_load_ps(lh_slice.as_ptr());
-                let rh_8x = _mm256_load_ps(rh_slice.as_ptr());
-                let diff = _mm256_sub_ps(lh_8x, rh_8x);
-                acc_8x = _mm256_fmadd_ps(diff, diff, acc_8x);
+/// Distance metric used for `FloatArray` points
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+enum Metric {
+    /// Squared Euclidean distance
+    #[default]
+    Euclidean,
+    /// One minus the cosine similarity, using precomputed vector norms
+    Cosine,
+    /// Annoy's angular distance, `sqrt(2 - 2 * cos)`
+    Angular,
+    /// One minus the Pearson correlation, using mean-centered values
+    Pearson,
+    /// A Python function passed to `register_metric()`, by position in `CUSTOM_METRICS`
+    ///
+    /// Positions are only valid within a process, so dumps store the name along with this.
+    Custom(u32),
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Euclidean => "euclidean",
+            Metric::Cosine => "cosine",
+            Metric::Angular => "angular",
+            Metric::Pearson => "pearson",
+            Metric::Custom(id) => CUSTOM_METRICS.lock().unwrap()[id as usize].name,
+        }
+    }
+
+    /// The name to store with a dump, for custom metrics
+    fn custom_name(self) -> Option<&'static str> {
+        match self {
+            Metric::Custom(_) => Some(self.name()),
+            _ => None,
+        }
+    }
+
+    /// Whether distances depend on the norms of the points, which then have to be stored
+    fn uses_norm(self) -> bool {
+        matches!(self, Metric::Cosine | Metric::Angular | Metric::Pearson)
+    }
+
+    /// The metric `name`, for searching an index built with this metric
+    ///
+    /// Pearson indexes store mean-centered points, so they can't be searched with other metrics,
+    /// and other indexes can't be searched using Pearson.
+    fn replace(self, name: &str) -> PyResult<Metric> {
+        match (self, name.parse()?) {
+            (Metric::Pearson, Metric::Pearson) => Ok(Metric::Pearson),
+            (Metric::Pearson, _) | (_, Metric::Pearson) => Err(PyValueError::new_err(format!(
+                "cannot search an index built with the {:?} metric using {:?}",
+                self.name(),
+                name
+            ))),
+            (_, metric) => Ok(metric),
+        }
+    }
+
+    /// Look up the current position of a custom metric read from a dump by its `name`
+    fn resolve(stored: Metric, name: Option<String>) -> PyResult<Metric> {
+        match (stored, name) {
+            (Metric::Custom(_), Some(name)) => name.parse().map_err(|_| {
+                PyValueError::new_err(format!(
+                    "index uses the custom metric {:?}, which is not registered",
+                    name
+                ))
+            }),
+            (Metric::Custom(_), None) => Err(PyValueError::new_err("custom metric name missing")),
+            (metric, _) => Ok(metric),
+        }
+    }
+}
+
+const BUILTIN_METRICS: [&str; 4] = ["euclidean", "cosine", "angular", "pearson"];
+
+/// Metrics registered through `register_metric()`
+static CUSTOM_METRICS: Mutex<Vec<CustomMetric>> = Mutex::new(Vec::new());
+
+struct CustomMetric {
+    /// Leaked, so that `Metric::name()` can return it; metrics are never unregistered
+    name: &'static str,
+    function: PyObject,
+}
+
+/// Compute the distance between two points using the Python function for metric `id`
+fn custom_distance(id: u32, lhs: &FloatArray, rhs: &FloatArray) -> f32 {
+    Python::with_gil(|py| {
+        let function = CUSTOM_METRICS.lock().unwrap()[id as usize]
+            .function
+            .clone_ref(py);
+        let args = (
+            PyList::new(py, &lhs.values[..]),
+            PyList::new(py, &rhs.values[..]),
+        );
+        match function.call1(py, args).and_then(|d| d.extract::<f32>(py)) {
+            Ok(distance) => distance,
+            Err(e) => {
+                e.print(py);
+                f32::INFINITY
             }
+        }
+    })
+}
 
-            let mut acc_4x = _mm256_extractf128_ps(acc_8x, 1); // upper half
-            let right = _mm256_castps256_ps128(acc_8x); // lower half
-            acc_4x = _mm_add_ps(acc_4x, right); // sum halves
+/// A file format for `Hnsw.dump()`
+#[derive(Clone, Copy, Debug)]
+enum Format {
+    /// The bincode serialization of the in-memory index
+    Bincode,
+    /// A graph dump, followed by the points truncated to their longest length
+    Compact,
+}
 
-            let lh_4x = _mm_load_ps(self.0[DIMENSIONS - 4..].as_ptr());
-            let rh_4x = _mm_load_ps(rhs.0[DIMENSIONS - 4..].as_ptr());
-            let diff = _mm_sub_ps(lh_4x, rh_4x);
-            acc_4x = _mm_fmadd_ps(diff, diff, acc_4x);
+impl Format {
+    /// Bincode files start with the format version of the serialized `Hnsw`
+    const BINCODE_MAGIC: &'static [u8] = b"IDHNSW02";
+    /// Compact files start with a graph dump, which starts with these bytes
+    const COMPACT_MAGIC: &'static [u8] = b"IDGRAPH1";
+}
+
+impl FromStr for Format {
+    type Err = PyErr;
 
-            let lower = _mm_movehl_ps(acc_4x, acc_4x);
-            acc_4x = _mm_add_ps(acc_4x, lower);
-            let upper = _mm_shuffle_ps(acc_4x, acc_4x, 0x1);
-            acc_4x = _mm_add_ss(acc_4x, upper);
-            _mm_cvtss_f32(acc_4x)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bincode" => Format::Bincode,
+            "compact" => Format::Compact,
+            _ => return Err(PyValueError::new_err(format!("unknown format: {:?}", s))),
+        })
+    }
+}
+
+/// Read an index dumped by a release that didn't version the serialized `Hnsw`
+///
+/// Those releases used the bincode format, and only wrote the index itself.
+fn load_legacy(f: &mut impl Read) -> PyResult<instant_distance::Hnsw<FloatArray>> {
+    let options = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+    let mut deserializer = bincode::Deserializer::with_reader(f, options);
+    instant_distance::Hnsw::deserialize_legacy(&mut deserializer)
+        .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))
+}
+
+/// Write the graph and points of `hnsw` in the compact format
+fn dump_compact(hnsw: &instant_distance::Hnsw<FloatArray>, f: &mut impl Write) -> PyResult<()> {
+    hnsw.dump_graph_only(&mut *f)?;
+
+    // Trailing zeros shared by all points are padding, so they don't need to be stored
+    let dim = hnsw
+        .iter()
+        .filter_map(|(_, point)| point.values.iter().rposition(|&value| value != 0.0))
+        .max()
+        .map_or(0, |last| last + 1);
+    let values = Vec::from_iter(
+        hnsw.iter()
+            .flat_map(|(_, point)| point.values[..dim].iter().copied()),
+    );
+    bincode::serialize_into(f, &(dim as u32, values))
+        .map_err(|e| PyValueError::new_err(format!("serialization error: {:?}", e)))
+}
+
+/// Read the graph and points written by `dump_compact()`
+fn load_compact(f: &mut impl Read) -> PyResult<instant_distance::Hnsw<FloatArray>> {
+    let graph = instant_distance::Hnsw::load_graph_only(&mut *f)?;
+    let (dim, values) = bincode::deserialize_from::<_, (u32, Vec<f32>)>(f)
+        .map_err(|e| PyValueError::new_err(format!("deserialization error: {:?}", e)))?;
+
+    let dim = dim as usize;
+    let len = graph.iter().count();
+    if dim > DIMENSIONS || values.len() != len * dim {
+        return Err(PyValueError::new_err(
+            "deserialization error: invalid point data",
+        ));
+    }
+
+    Ok(graph.map_points(|pid, ()| {
+        let mut point = FloatArray {
+            values: [0.0; DIMENSIONS],
+            norm: 0.0,
+            len: 0,
+            metric: Metric::default(),
+            accumulator: Accumulator::default(),
+            mask: None,
+        };
+        let start = pid.into_inner() as usize * dim;
+        point.values[..dim].copy_from_slice(&values[start..start + dim]);
+        point
+    }))
+}
+
+impl FromStr for Metric {
+    type Err = PyErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "euclidean" => Metric::Euclidean,
+            "cosine" => Metric::Cosine,
+            "angular" => Metric::Angular,
+            "pearson" => Metric::Pearson,
+            _ => match CUSTOM_METRICS
+                .lock()
+                .unwrap()
+                .iter()
+                .position(|m| m.name == s)
+            {
+                Some(id) => Metric::Custom(id as u32),
+                None => return Err(PyValueError::new_err(format!("unknown metric: {:?}", s))),
+            },
+        })
+    }
+}
+
+/// Float type used to add up the terms of a `FloatArray` distance
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+enum Accumulator {
+    /// Single precision, using the SIMD kernel
+    #[default]
+    F32,
+    /// Double precision, using a scalar loop
+    F64,
+}
+
+impl Accumulator {
+    fn name(self) -> &'static str {
+        match self {
+            Accumulator::F32 => "f32",
+            Accumulator::F64 => "f64",
         }
     }
 }
 
+impl FromStr for Accumulator {
+    type Err = PyErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "f32" => Accumulator::F32,
+            "f64" => Accumulator::F64,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "unknown accumulator: {:?}",
+                    s
+                )))
+            }
+        })
+    }
+}
+
 const DIMENSIONS: usize = 300;
+
+/// Number of 8-value chunks between checks of the partial distance against a search's bound
+///
+/// Every check costs a horizontal sum, so checking after every chunk would be slower than
+/// computing the full distance; with 300 dimensions, this checks the partial sum 3 times.
+const BOUND_CHECK_CHUNKS: usize = 12;
@@ -1,14 +1,573 @@
-import instant_distance, random
+import instant_distance, json, math, os, random, struct, tempfile, warnings
+from array import array
+from typing import List
 
-def main():
+def main() -> None:
     points = [[random.random() for _ in range(300)] for _ in range(1024)]
     config = instant_distance.Config()
     (hnsw, ids) = instant_distance.Hnsw.build(points, config)
     p = [random.random() for _ in range(300)]
     search = instant_distance.Search()
     hnsw.search(p, search)
-    for candidate in search:
+    (pids, distances) = search.as_arrays()
+    candidates = list(search)
+    for candidate in candidates:
         print(candidate)
 
+    assert list(pids) == [candidate.pid for candidate in candidates]
+    assert list(distances) == [candidate.distance for candidate in candidates]
+    assert hnsw.search_ids(p, 10) == [candidate.pid for candidate in candidates[:10]]
+    # The count is a lower bound, which includes every search result within the radius
+    radius = candidates[9].distance
+    within = [q for q in points if sum((a - b) ** 2 for (a, b) in zip(p, q)) <= radius * 1.001]
+    assert 10 <= hnsw.count_within_radius(p, radius) <= len(within)
+    assert len(candidates) <= hnsw.count_within_radius(p, math.inf) <= len(points)
+    assert all(ids[candidate.original_index] == candidate.pid for candidate in candidates)
+    assert all(ids[c.original_index] == c.pid for c in hnsw.search_pooled(p, 10))
+    distances = hnsw.distances_to(p, [candidate.pid for candidate in candidates[:10]])
+    assert list(distances) == [candidate.distance for candidate in candidates[:10]]
+
+    k_distances = hnsw.k_distances(5)
+    assert len(k_distances) == len(points)
+    assert all(distance > 0 for distance in k_distances)
+
+    top = hnsw.layer_of(0)
+    assert hnsw.layer_points(0) == list(range(len(points)))
+    assert 0 < len(hnsw.layer_points(top)) < len(points) // 10
+    assert all(hnsw.layer_of(pid) == top for pid in hnsw.layer_points(top))
+    assert hnsw.layer_points(top + 1) == []
+
+    fetched = hnsw.get_many([c.pid for c in candidates[:3]])
+    assert fetched == array("f", [x for c in candidates[:3] for x in points[c.original_index]])
+    try:
+        hnsw.get_many([0, len(points), len(points) + 1])
+        assert False, "expected an error for unknown ids"
+    except ValueError as e:
+        assert str(e) == f"unknown id: {len(points)}"
+
+    (sample_ids, sample_points) = hnsw.sample(100)
+    assert len(sample_ids) == 100 and len(sample_points) == 100 * 300
+    assert set(hnsw.layer_points(top)) <= set(sample_ids)
+    row = list(sample_ids).index(0)
+    assert sample_points[row * 300 : (row + 1) * 300] == array("f", points[ids.index(0)])
+    assert hnsw.sample(100, uniform=True, seed=1) == hnsw.sample(100, uniform=True, seed=1)
+
+    check_angular()
+    check_accumulator()
+    check_pearson()
+    check_centroid()
+    check_load_metric()
+    check_export()
+    check_cosine_round_trip()
+    check_buffers()
+    check_dedup()
+    check_filtered()
+    check_keyed()
+    check_max_distance()
+    check_custom_metric()
+    check_traced()
+    check_objects()
+    check_metric_override()
+    check_mask()
+    check_evaluate()
+    check_config()
+    check_formats()
+    check_integrity()
+    check_legacy()
+
+def check_legacy() -> None:
+    # Dumped by instant-distance 0.3.0, which didn't version its format, from these points
+    points = [[(i * 0.618 + j * 0.271) % 1 for j in range(8)] for i in range(16)]
+    ids = [8, 1, 15, 9, 4, 14, 5, 6, 7, 13, 10, 2, 11, 3, 0, 12]
+    fname = os.path.join(os.path.dirname(__file__), "data", "legacy.idx")
+    hnsw = instant_distance.Hnsw.load(fname)
+    assert hnsw.verify_integrity() == []
+
+    search = instant_distance.Search()
+    for (point, pid) in zip(points, ids):
+        hnsw.search(point, search)
+        assert next(iter(search)).pid == pid
+    hnsw.search(points[3], search)
+    assert [c.pid for c in search][:5] == [9, 2, 5, 7, 0]
+
+    # Dumping writes the current format, which loads the same index
+    with tempfile.TemporaryDirectory() as tmp:
+        current = os.path.join(tmp, "current.idx")
+        hnsw.dump(current)
+        loaded = instant_distance.Hnsw.load(current)
+        loaded.search(points[3], search)
+        assert [c.pid for c in search][:5] == [9, 2, 5, 7, 0]
+
+def check_formats() -> None:
+    points = [[random.random() for _ in range(8)] for _ in range(256)]
+    config = instant_distance.Config()
+    config.metric = "cosine"
+    attributes = {"even": [i % 2 == 0 for i in range(256)]}
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config, attributes)
+    query = [random.random() for _ in range(8)]
+    search = instant_distance.Search()
+    hnsw.search(query, search)
+    results = [(c.pid, c.distance) for c in search]
+
+    with tempfile.TemporaryDirectory() as tmp:
+        (full, compact) = (os.path.join(tmp, "full.idx"), os.path.join(tmp, "compact.idx"))
+        hnsw.dump(full)
+        hnsw.dump(compact, format="compact")
+        assert os.path.getsize(compact) < os.path.getsize(full) // 4
+        for fname in (full, compact):
+            loaded = instant_distance.Hnsw.load(fname)
+            loaded.search(query, search)
+            assert [(c.pid, c.distance) for c in search] == results
+            loaded.search_filtered(query, {"even": True}, search)
+            assert all(ids.index(c.pid) % 2 == 0 for c in search)
+
+        try:
+            hnsw.dump(compact, format="mmap")
+            assert False, "expected an error for an unknown format"
+        except ValueError:
+            pass
+
+def check_integrity() -> None:
+    points = [[random.random() for _ in range(8)] for _ in range(64)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    assert hnsw.verify_integrity() == []
+
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "corrupt.idx")
+        hnsw.dump(fname)
+        with open(fname, "rb") as f:
+            data = bytearray(f.read())
+
+        # Point the first link of every node past the end of the index. The zero layer follows
+        # the format version, `ef_search`, the points (padded to 300 values), the input indexes
+        # and the layer's variant and length; check that the dump starts with exactly these.
+        original = sorted(range(64), key=lambda i: ids[i])
+        prefix = b"IDHNSW02" + struct.pack("<QQ", hnsw.config.ef_search, 64)
+        for i in original:
+            prefix += struct.pack("<300f", *points[i], *[0.0] * 292)
+        prefix += struct.pack("<Q64I", 64, *original) + struct.pack("<IQ", 0, 64)
+        assert data.startswith(prefix)
+        start = len(prefix)
+        for i in range(64):
+            data[start + i * 256 : start + i * 256 + 4] = (1000 + i).to_bytes(4, "little")
+        with open(fname, "wb") as f:
+            f.write(data)
+        corrupt = instant_distance.Hnsw.load(fname)
+
+    problems = corrupt.verify_integrity()
+    assert len(problems) == 64 and "1000" in problems[0]
+    search = instant_distance.Search()
+    search.skip_invalid_links = True
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        corrupt.search(points[0], search)
+    assert len(search) > 0 and all(c.pid < 64 for c in search)
+    assert [w.category for w in caught] == [RuntimeWarning]
+
+def check_config() -> None:
+    config = instant_distance.Config()
+    config.ef_search = 37
+    config.seed = 5
+    config.metric = "cosine"
+    points = [[random.random() for _ in range(8)] for _ in range(64)]
+    (hnsw, _) = instant_distance.Hnsw.build(points, config)
+
+    for format in ["bincode", "compact"]:
+        with tempfile.TemporaryDirectory() as tmp:
+            fname = os.path.join(tmp, "config.idx")
+            hnsw.dump(fname, format)
+            loaded = instant_distance.Hnsw.load(fname).config
+
+        assert (loaded.ef_search, loaded.seed, loaded.metric) == (37, 5, "cosine")
+        assert loaded.ef_construction == config.ef_construction
+        assert abs(loaded.ml - config.ml) < 1e-6
+        assert loaded.heuristic is not None and loaded.heuristic.keep_pruned
+
+def check_evaluate() -> None:
+    points = [[random.random() for _ in range(8)] for _ in range(256)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    queries = [[random.random() for _ in range(8)] for _ in range(20)]
+    search = instant_distance.Search()
+    truth = []
+    for query in queries:
+        hnsw.search(query, search)
+        truth.append([c.pid for c in search][:10])
+
+    report = hnsw.evaluate(queries, truth, 10, 100)
+    (low, high) = report.recall_ci
+    assert 0.0 <= low <= report.mean_recall <= high <= 1.0
+    assert low < high
+    assert "recall_ci=" in repr(report)
+
+    # Any sequence of queries is accepted
+    report = hnsw.evaluate(tuple(queries), truth, 10, 100)
+    assert report.mean_recall == 1.0
+
+def check_metric_override() -> None:
+    points = [[random.random() for _ in range(8)] for _ in range(256)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    query = [random.random() for _ in range(8)]
+
+    def cosine(a: List[float], b: List[float]) -> float:
+        dot = sum(x * y for (x, y) in zip(a, b))
+        return 1 - dot / math.sqrt(sum(x * x for x in a) * sum(y * y for y in b))
+
+    search = instant_distance.Search()
+    hnsw.search(query, search)
+    nearest = {c.pid for c in search}
+    hnsw.search(query, search, metric="cosine")
+    results = list(search)
+    assert {c.pid for c in results} == nearest
+    assert all(a.distance <= b.distance for (a, b) in zip(results, results[1:]))
+    assert all(abs(c.distance - cosine(points[c.original_index], query)) < 1e-5 for c in results)
+
+    try:
+        hnsw.search(query, search, metric="pearson")
+        assert False, "expected an error for the pearson metric"
+    except ValueError:
+        pass
+
+def check_mask() -> None:
+    rng = random.Random(198)
+    points = [[rng.random() for _ in range(8)] for _ in range(256)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    query = [rng.random() for _ in range(8)]
+    mask = [True, False, True, False, True, True]
+
+    def masked(a: List[float], b: List[float]) -> float:
+        return sum((x - y) ** 2 for (x, y, keep) in zip(a, b, mask) if keep)
+
+    search = instant_distance.Search()
+    search.k = 10
+    hnsw.search(query, search, mask=mask)
+    results = list(search)
+    assert all(abs(c.distance - masked(points[c.original_index], query)) < 1e-5 for c in results)
+    expected = sorted(range(len(points)), key=lambda i: masked(points[i], query))[:10]
+    assert len({c.original_index for c in results} & set(expected)) >= 8
+
+    # Masked distances are computed separately from the SIMD kernels, so they may differ
+    # slightly, which can swap results at nearly equal distances
+    hnsw.search(query, search)
+    unmasked = {c.pid: c.distance for c in search}
+    hnsw.search(query, search, mask=[True] * 8)
+    full = {c.pid: c.distance for c in search}
+    assert len(full.keys() & unmasked.keys()) >= 9
+    assert all(abs(full[pid] - unmasked[pid]) < 1e-5 for pid in full.keys() & unmasked.keys())
+
+    try:
+        hnsw.search(query, search, mask=[False] * 8)
+        assert False, "expected an error for an empty mask"
+    except ValueError:
+        pass
+
+class Place:
+    def __init__(self, x: float, y: float) -> None:
+        self.x, self.y = x, y
+
+    def distance(self, other: "Place") -> float:
+        return math.hypot(self.x - other.x, self.y - other.y)
+
+def check_objects() -> None:
+    places = [Place(random.random(), random.random()) for _ in range(256)]
+    (hnsw, ids) = instant_distance.ObjectHnsw.build(places, instant_distance.Config())
+    query = Place(0.5, 0.5)
+    search = instant_distance.Search()
+    search.k = 10
+    hnsw.search(query, search)
+    results = [(c.original_index, c.distance) for c in search]
+    expected = sorted(range(256), key=lambda i: places[i].distance(query))[:10]
+    assert [i for (i, _) in results] == expected
+    assert hnsw.get(ids[expected[0]]) is places[expected[0]]
+
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "objects.idx")
+        hnsw.dump(fname)
+        loaded = instant_distance.ObjectHnsw.load(fname)
+        loaded.search(query, search)
+        assert [(c.original_index, c.distance) for c in search] == results
+        assert loaded.get(ids[3]).x == places[3].x
+
+def check_traced() -> None:
+    points = [[random.random() for _ in range(4)] for _ in range(256)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    search = instant_distance.Search()
+    search.k = 10
+    trace = hnsw.search_traced([0.5] * 4, search)
+    json.dumps(trace)
+
+    assert [r["pid"] for r in trace["results"]] == [c.pid for c in search]
+    steps = trace["steps"]
+    top = hnsw.layer_of(0)
+    assert steps[0]["kind"] == "visit" and steps[0]["pid"] == 0 and steps[0]["layer"] == top
+    assert [s["layer"] for s in steps if s["kind"] == "stop"] == list(range(top, -1, -1))
+    visited = {s["pid"] for s in steps if s["kind"] == "visit"}
+    assert all(r["pid"] in visited for r in trace["results"])
+
+def check_custom_metric() -> None:
+    def manhattan(a: List[float], b: List[float]) -> float:
+        return sum(abs(x - y) for (x, y) in zip(a, b))
+
+    instant_distance.register_metric("manhattan", manhattan)
+    config = instant_distance.Config()
+    config.metric = "manhattan"
+    assert config.metric == "manhattan"
+
+    points = [[random.random() for _ in range(4)] for _ in range(64)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    query = [0.5] * 4
+    search = instant_distance.Search()
+    hnsw.search(query, search)
+    results = [(ids.index(c.pid), c.distance) for c in search]
+    expected = sorted(range(64), key=lambda i: manhattan(points[i], query))
+    assert [i for (i, _) in results] == expected
+    assert all(abs(d - manhattan(points[i], query)) < 1e-4 for (i, d) in results)
+
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "custom.idx")
+        hnsw.dump(fname)
+        loaded = instant_distance.Hnsw.load(fname)
+        loaded.search(query, search)
+        assert [(ids.index(c.pid), c.distance) for c in search] == results
+
+    try:
+        instant_distance.register_metric("cosine", manhattan)
+        assert False, "expected an error for a built-in metric name"
+    except ValueError:
+        pass
+
+def check_max_distance() -> None:
+    points = [[float(i)] for i in range(100)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    search = instant_distance.Search()
+    # Euclidean distances are squared
+    search.max_distance = 4.5
+    hnsw.search([50.0], search)
+    assert sorted(ids.index(c.pid) for c in search) == [48, 49, 50, 51, 52]
+    assert len(search) == 5
+
+    search.k = 3
+    hnsw.search([50.0], search)
+    assert [c.distance for c in search] == [0.0, 1.0, 1.0]
+
+def check_keyed() -> None:
+    entries = [
+        ("point-%d" % i, [random.random() for _ in range(300)], "value-%d" % i)
+        for i in range(256)
+    ]
+    entries.append(("point-0", [0.5] * 300, "replaced"))
+    keyed = instant_distance.KeyedHnsw.build(entries, instant_distance.Config())
+    assert len(keyed) == 256
+    assert "point-1" in keyed and "point-256" not in keyed
+    assert keyed.get("point-0") == "replaced"
+    assert keyed.get("point-256") is None
+
+    (key, value, distance) = keyed.search([0.5] * 300, 1)[0]
+    assert (key, value, distance) == ("point-0", "replaced", 0.0)
+    results = keyed.search(entries[5][1], 10)
+    assert results[0][:2] == ("point-5", "value-5")
+    assert len(results) == 10
+
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "keyed.idx")
+        keyed.dump(fname)
+        loaded = instant_distance.KeyedHnsw.load(fname)
+        assert loaded.search(entries[5][1], 10) == results
+
+def check_angular() -> None:
+    # Expected distances as computed by Annoy's angular metric
+    def unit(*values: float) -> List[float]:
+        return list(values) + [0.0] * (300 - len(values))
+
+    points = [unit(1.0), unit(0.0, 1.0), unit(1.0, 1.0), unit(-2.0), unit(3.0)]
+    expected = [0.0, math.sqrt(2), math.sqrt(2 - math.sqrt(2)), 2.0, 0.0]
+
+    config = instant_distance.Config()
+    config.metric = "angular"
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    search = instant_distance.Search()
+    hnsw.search(unit(1.0), search)
+    found = {candidate.pid: candidate.distance for candidate in search}
+    for (pid, distance) in zip(ids, expected):
+        assert abs(found[pid] - distance) < 1e-6, (found[pid], distance)
+
+def check_pearson() -> None:
+    # Pearson correlations computed by hand, on windows shorter than 300 values
+    x = [1.0, 2.0, 3.0, 4.0]
+    points = [[2.0, 4.0, 5.0, 9.0], [4.0, 3.0, 2.0, 1.0], [11.0, 12.0, 13.0, 14.0]]
+    expected = [1 - 11 / math.sqrt(130), 2.0, 0.0]
+
+    config = instant_distance.Config()
+    config.metric = "pearson"
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    search = instant_distance.Search()
+    hnsw.search(x, search)
+    found = {candidate.pid: candidate.distance for candidate in search}
+    for (pid, distance) in zip(ids, expected):
+        assert abs(found[pid] - distance) < 1e-6, (found[pid], distance)
+
+def check_centroid() -> None:
+    points = [[0.0, 0.0], [2.0, 0.0], [1.0, 3.0]]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    centroid = hnsw.centroid(ids)
+    assert len(centroid) == 300
+    assert centroid[:3] == [1.0, 1.0, 0.0], centroid[:3]
+
+    for call in (lambda: hnsw.centroid([3]), lambda: hnsw.layer_of(3), lambda: hnsw.original_index(3)):
+        try:
+            call()
+            assert False, "expected an error for an unknown id"
+        except ValueError:
+            pass
+
+def check_load_metric() -> None:
+    # Search a Euclidean graph using the cosine metric instead
+    points = [[1.0, 0.0], [0.0, 2.0], [3.0, 3.0]]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "index.bin")
+        hnsw.dump(fname)
+        cosine = instant_distance.Hnsw.load(fname, metric="cosine")
+        try:
+            instant_distance.Hnsw.load(fname, metric="pearson")
+            assert False, "expected an error for the Pearson metric"
+        except ValueError:
+            pass
+
+    # The same swap on an index in memory, which must leave the original alone
+    copy = hnsw.with_metric("cosine")
+    try:
+        hnsw.with_metric("pearson")
+        assert False, "expected an error for the Pearson metric"
+    except ValueError:
+        pass
+
+    expected = [0.0, 1.0, 1 - 1 / math.sqrt(2)]
+    for index in [cosine, copy]:
+        search = instant_distance.Search()
+        index.search([2.0, 0.0], search)
+        found = {candidate.pid: candidate.distance for candidate in search}
+        for (pid, distance) in zip(ids, expected):
+            assert abs(found[pid] - distance) < 1e-6, (found[pid], distance)
+
+    search = instant_distance.Search()
+    hnsw.search([2.0, 0.0], search)
+    found = {candidate.pid: candidate.distance for candidate in search}
+    assert abs(found[ids[0]] - 1.0) < 1e-6, found
+
+def check_export() -> None:
+    points = [[random.random() for _ in range(300)] for _ in range(64)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    vectors = hnsw.export_vectors()
+    assert len(vectors) == 64 * 300
+    for (point, pid) in zip(points, ids):
+        row = vectors[pid * 300:(pid + 1) * 300]
+        assert all(abs(a - b) < 1e-6 for (a, b) in zip(row, point))
+
+    assert hnsw.component_sizes() == [64]
+
+    adjacency = hnsw.export_adjacency()
+    assert len(adjacency) == 64
+    assert all(neighbors and pid not in neighbors for (pid, neighbors) in enumerate(adjacency))
+
+def check_cosine_round_trip() -> None:
+    # Norms are stored with the index, so reloading must not change any distance
+    points = [[random.random() for _ in range(300)] for _ in range(256)]
+    config = instant_distance.Config()
+    config.metric = "cosine"
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "index.bin")
+        hnsw.dump(fname)
+        loaded = instant_distance.Hnsw.load(fname)
+
+    query = [random.random() for _ in range(300)]
+    (before, after) = (instant_distance.Search(), instant_distance.Search())
+    hnsw.search(query, before)
+    loaded.search(query, after)
+    expected = [(candidate.pid, candidate.distance) for candidate in before]
+    assert expected == [(candidate.pid, candidate.distance) for candidate in after]
+
+def check_filtered() -> None:
+    points = [[random.random() for _ in range(300)] for _ in range(512)]
+    attributes = {
+        "color": [["red", "green", "blue"][i % 3] for i in range(512)],
+        "year": [2000 + i % 20 for i in range(512)],
+        "active": [i % 2 == 0 for i in range(512)],
+    }
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config(), attributes)
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "index.bin")
+        hnsw.dump(fname)
+        hnsw = instant_distance.Hnsw.load(fname)
+
+    def matches(i: int) -> bool:
+        return i % 3 == 1 and 2005 <= 2000 + i % 20 <= 2010 and i % 2 == 0
+
+    search = instant_distance.Search()
+    filters = {"color": "green", "year": (2005, 2010), "active": True}
+    hnsw.search_filtered([random.random() for _ in range(300)], filters, search)
+    results = list(search)
+    assert results and all(matches(candidate.original_index) for candidate in results)
+
+    hnsw.search_filtered(points[0], {"color": "purple"}, search)
+    assert len(search) == 0
+
+    try:
+        hnsw.search_filtered(points[0], {"size": 1}, search)
+        assert False, "expected an error for an unknown attribute"
+    except ValueError:
+        pass
+
+def check_buffers() -> None:
+    # float32 and float64 buffers are read directly, other objects are iterated over
+    points = [[random.random() for _ in range(300)] for _ in range(64)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    query = [random.random() for _ in range(300)]
+    expected = hnsw.distances_to(query, ids)
+    assert hnsw.distances_to(array("f", query), ids) == expected
+    assert hnsw.distances_to(array("d", query), ids) == expected
+    assert hnsw.distances_to(tuple(query), ids) == expected
+
+    matrix = memoryview(array("f", query)).cast("B").cast("f", (2, 150))
+    for (invalid, error) in [(matrix, ValueError), (["a"] * 300, TypeError)]:
+        try:
+            hnsw.distances_to(invalid, ids)
+            assert False, "expected an error for an invalid point"
+        except error:
+            pass
+
+def check_dedup() -> None:
+    # Four copies of every point, of which only one should be returned
+    points = [[random.random() for _ in range(300)] for _ in range(128)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points * 4, instant_distance.Config())
+    search = instant_distance.Search()
+    search.k = 10
+    search.dedup_epsilon = 1e-6
+    hnsw.search([random.random() for _ in range(300)], search)
+    results = list(search)
+    assert len(results) == 10
+    assert len({candidate.original_index % 128 for candidate in results}) == 10
+
+def check_accumulator() -> None:
+    # Accumulating distances in f64 should barely change the exact nearest neighbors
+    points = [[random.random() for _ in range(300)] for _ in range(1024)]
+    queries = [[random.random() for _ in range(300)] for _ in range(10)]
+    results = []
+    for accumulator in ("f32", "f64"):
+        config = instant_distance.Config()
+        config.flat = True
+        config.accumulator = accumulator
+        (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+        search = instant_distance.Search()
+        search.k = 10
+        found = []
+        for query in queries:
+            hnsw.search(query, search)
+            found.append({ids.index(candidate.pid) for candidate in search})
+        results.append(found)
+
+    shared = sum(len(a & b) for (a, b) in zip(*results))
+    assert shared >= 95, shared
+
 if __name__ == '__main__':
     main()
@@ -2,22 +2,111 @@ use bencher::{benchmark_group, benchmark_main, Bencher};
 use rand::rngs::{StdRng, ThreadRng};
 use rand::{Rng, SeedableRng};
 
-use instant_distance::Builder;
+use instant_distance::{Builder, InsertionOrder, Point as _, Search};
 
 benchmark_main!(benches);
-benchmark_group!(benches, build_heuristic);
+benchmark_group!(
+    benches,
+    build_heuristic,
+    build_morton,
+    search_heuristic,
+    search_morton,
+    distance_768,
+    distance_768_bounded
+);
 
 fn build_heuristic(bench: &mut Bencher) {
     let seed = ThreadRng::default().gen::<u64>();
     let mut rng = StdRng::seed_from_u64(seed);
     let points = (0..1024)
-        .into_iter()
         .map(|_| Point(rng.gen(), rng.gen()))
         .collect::<Vec<_>>();
 
     bench.iter(|| Builder::default().seed(seed).build(&points))
 }
 
+fn build_morton(bench: &mut Bencher) {
+    let seed = ThreadRng::default().gen::<u64>();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    points.sort_unstable_by_key(Point::morton);
+
+    let builder = || Builder::default().order(InsertionOrder::Input);
+    bench.iter(|| builder().seed(seed).build(&points))
+}
+
+fn search_heuristic(bench: &mut Bencher) {
+    let seed = ThreadRng::default().gen::<u64>();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let points = (0..16384)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    let (hnsw, _) = Builder::default().seed(seed).build(&points);
+    let mut search = Search::default();
+    bench.iter(|| {
+        let query = Point(rng.gen(), rng.gen());
+        hnsw.search(&query, &mut search).next()
+    })
+}
+
+fn search_morton(bench: &mut Bencher) {
+    let seed = ThreadRng::default().gen::<u64>();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut points = (0..16384)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    points.sort_unstable_by_key(Point::morton);
+
+    let builder = Builder::default().order(InsertionOrder::Input);
+    let (hnsw, _) = builder.seed(seed).build(&points);
+    let mut search = Search::default();
+    bench.iter(|| {
+        let query = Point(rng.gen(), rng.gen());
+        hnsw.search(&query, &mut search).next()
+    })
+}
+
+fn distance_768(bench: &mut Bencher) {
+    let mut rng = StdRng::seed_from_u64(ThreadRng::default().gen::<u64>());
+    let points = (0..1024)
+        .map(|_| Vector::random(&mut rng))
+        .collect::<Vec<_>>();
+    let query = Vector::random(&mut rng);
+
+    bench.iter(|| {
+        points
+            .iter()
+            .map(|point| query.distance(point))
+            .fold(0.0, f32::max)
+    })
+}
+
+fn distance_768_bounded(bench: &mut Bencher) {
+    let mut rng = StdRng::seed_from_u64(ThreadRng::default().gen::<u64>());
+    let points = (0..1024)
+        .map(|_| Vector::random(&mut rng))
+        .collect::<Vec<_>>();
+    let query = Vector::random(&mut rng);
+
+    // Bound the distances like a search does once it holds 100 results
+    let mut distances = points
+        .iter()
+        .map(|point| query.distance(point))
+        .collect::<Vec<_>>();
+    distances.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let bound = distances[100];
+
+    bench.iter(|| {
+        points
+            .iter()
+            .map(|point| query.distance_bounded(point, bound))
+            .fold(0.0, f32::max)
+    })
+}
+
 /*
 fn randomized(builder: Builder) -> (u64, usize) {
     let query = Point(rng.gen(), rng.gen());
@@ -49,9 +138,54 @@ fn randomized(builder: Builder) -> (u64, usize) {
 #[derive(Clone, Copy, Debug)]
 struct Point(f32, f32);
 
+impl Point {
+    /// Interleave the bits of both coordinates to get a position on the Morton curve
+    fn morton(&self) -> u64 {
+        let (x, y) = ((self.0 * 65536.0) as u64, (self.1 * 65536.0) as u64);
+        (0..16).fold(0, |code, bit| {
+            code | ((x >> bit) & 1) << (2 * bit) | ((y >> bit) & 1) << (2 * bit + 1)
+        })
+    }
+}
+
 impl instant_distance::Point for Point {
     fn distance(&self, other: &Self) -> f32 {
         // Euclidean distance metric
         ((self.0 - other.0).powi(2) + (self.1 - other.1).powi(2)).sqrt()
     }
 }
+
+#[derive(Clone, Debug)]
+struct Vector(Vec<f32>);
+
+impl Vector {
+    fn random(rng: &mut impl Rng) -> Self {
+        Self((0..768).map(|_| rng.gen()).collect())
+    }
+
+    /// Squared Euclidean distance, checking `bound` after every 64 dimensions
+    fn squared_euclidean(&self, other: &Self, bound: f32) -> f32 {
+        let mut sum = 0.0;
+        for (lhs, rhs) in self.0.chunks(64).zip(other.0.chunks(64)) {
+            sum += lhs
+                .iter()
+                .zip(rhs)
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f32>();
+            if sum > bound {
+                break;
+            }
+        }
+        sum
+    }
+}
+
+impl instant_distance::Point for Vector {
+    fn distance(&self, other: &Self) -> f32 {
+        self.squared_euclidean(other, f32::INFINITY)
+    }
+
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        self.squared_euclidean(other, bound)
+    }
+}
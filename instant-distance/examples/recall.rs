@@ -0,0 +1,105 @@
+//! Measure recall@10 against queries per second for a range of `ef_search` values
+//!
+//! Runs over 10,000 random 32-dimensional points by default. Alternatively, pass the path to
+//! an `.fvecs` file; its last 100 vectors are used as queries and the rest are indexed.
+//!
+//!     cargo run --release --example recall [base.fvecs]
+
+use std::convert::TryInto;
+use std::fs;
+use std::time::Instant;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use instant_distance::Builder;
+
+fn main() {
+    let (points, queries) = match std::env::args().nth(1) {
+        Some(path) => {
+            let mut points = read_fvecs(&path);
+            let queries = points.split_off(points.len() - QUERIES);
+            (points, queries)
+        }
+        None => {
+            let mut rng = StdRng::seed_from_u64(SEED);
+            let mut random = |n| (0..n).map(|_| Point::random(&mut rng)).collect::<Vec<_>>();
+            (random(POINTS), random(QUERIES))
+        }
+    };
+
+    let start = Instant::now();
+    let (hnsw, _) = Builder::default().seed(SEED).build(&points);
+    println!(
+        "built index over {} points in {:.2?}",
+        points.len(),
+        start.elapsed()
+    );
+
+    let truth = queries
+        .iter()
+        .map(|query| {
+            hnsw.exact_search(query, K)
+                .into_iter()
+                .map(|candidate| candidate.pid)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    println!("{:>10} {:>10} {:>10}", "ef_search", "recall@10", "qps");
+    for &ef in &[10, 20, 40, 80, 160, 320] {
+        let report = hnsw.evaluate(&queries, &truth, K, ef);
+        println!(
+            "{:>10} {:>10.3} {:>10.0}",
+            ef, report.mean_recall, report.qps
+        );
+    }
+}
+
+/// Read vectors from a file in the `.fvecs` format
+///
+/// Each vector is stored as a little-endian `i32` dimension followed by that many `f32` values.
+fn read_fvecs(path: &str) -> Vec<Point> {
+    let data = fs::read(path).expect("failed to read fvecs file");
+    let mut points = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let (dim, tail) = rest.split_at(4);
+        let dim = i32::from_le_bytes(dim.try_into().unwrap()) as usize;
+        let (values, tail) = tail.split_at(dim * 4);
+        points.push(Point(
+            values
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+        ));
+        rest = tail;
+    }
+    points
+}
+
+#[derive(Clone, Debug)]
+struct Point(Vec<f32>);
+
+impl Point {
+    fn random(rng: &mut StdRng) -> Self {
+        Self((0..DIMENSIONS).map(|_| rng.gen()).collect())
+    }
+}
+
+impl instant_distance::Point for Point {
+    fn distance(&self, other: &Self) -> f32 {
+        // Squared Euclidean distance metric
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum()
+    }
+}
+
+const POINTS: usize = 10_000;
+const QUERIES: usize = 100;
+const DIMENSIONS: usize = 32;
+const K: usize = 10;
+const SEED: u64 = 42;
@@ -0,0 +1,124 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::PointId;
+
+/// Structured attributes for every point in an index, stored as typed columns
+///
+/// Attributes are used to restrict a search to the points matching a set of `Condition`s,
+/// through `Hnsw::search_filtered()`. The conditions are evaluated while traversing the graph,
+/// so points that don't match are still used to route the search, but never returned.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default)]
+pub struct Attributes {
+    /// Columns of values, ordered by `PointId`
+    columns: Vec<Column>,
+}
+
+impl Attributes {
+    /// Create `Attributes` from columns holding a value for every input point
+    ///
+    /// The values in each column are in the order of the points passed to the `Builder`, and
+    /// `ids` is the list of ids returned by it, which is used to reorder the values to match the
+    /// index. For indexes built with `Builder::build_dedup()`, each point gets the values of its
+    /// first occurrence in the input. Panics if a column's length doesn't match `ids`.
+    pub fn new(columns: Vec<Column>, ids: &[PointId]) -> Self {
+        let len = ids.iter().map(|pid| pid.0 as usize + 1).max().unwrap_or(0);
+        let columns = columns
+            .into_iter()
+            .map(|column| {
+                assert_eq!(column.len(), ids.len(), "expected a value for every point");
+                match column {
+                    Column::Category(values) => Column::Category(reorder(values, ids, len)),
+                    Column::Int(values) => Column::Int(reorder(values, ids, len)),
+                    Column::Flag(values) => Column::Flag(reorder(values, ids, len)),
+                }
+            })
+            .collect();
+
+        Self { columns }
+    }
+
+    /// Whether the point `pid` satisfies all of the given `conditions`
+    ///
+    /// Points without a value in a column never match conditions on that column. This is the
+    /// case for points added to the index after the `Attributes` were created (for example,
+    /// through `Hnsw::append()`), and for all points if the columns are too short for the index.
+    /// Panics if a condition refers to a column that doesn't exist.
+    pub fn matches(&self, pid: PointId, conditions: &[Condition]) -> bool {
+        conditions.iter().all(|condition| {
+            let (column, min, max) = match *condition {
+                Condition::Equals { column, value } => (column, value, value),
+                Condition::Range { column, min, max } => (column, min, max),
+            };
+
+            match self.columns[column].get(pid) {
+                Some(value) => min <= value && value <= max,
+                None => false,
+            }
+        })
+    }
+
+    /// The columns, in the order they were passed to `Attributes::new()`
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+}
+
+/// A column of attribute values, one for every point
+///
+/// Conditions compare values as `i64`, so categories are compared by their number and flags
+/// are treated as 0 (`false`) or 1 (`true`).
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug)]
+pub enum Column {
+    /// Categories, such as the variants of an enum
+    Category(Vec<u32>),
+    /// Integers, such as timestamps
+    Int(Vec<i64>),
+    /// Boolean flags
+    Flag(Vec<bool>),
+}
+
+impl Column {
+    /// The number of values in the column
+    pub fn len(&self) -> usize {
+        match self {
+            Column::Category(values) => values.len(),
+            Column::Int(values) => values.len(),
+            Column::Flag(values) => values.len(),
+        }
+    }
+
+    /// Whether the column holds no values
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, pid: PointId) -> Option<i64> {
+        let idx = pid.0 as usize;
+        match self {
+            Column::Category(values) => values.get(idx).map(|&value| value as i64),
+            Column::Int(values) => values.get(idx).copied(),
+            Column::Flag(values) => values.get(idx).map(|&value| value as i64),
+        }
+    }
+}
+
+/// A condition on the value of a single attribute column
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// The value in `column` equals `value`
+    Equals { column: usize, value: i64 },
+    /// The value in `column` lies within `min..=max`
+    Range { column: usize, min: i64, max: i64 },
+}
+
+/// Move `values` from input order to `PointId` order, keeping the first value for every id
+fn reorder<T: Copy + Default>(values: Vec<T>, ids: &[PointId], len: usize) -> Vec<T> {
+    let mut reordered = vec![T::default(); len];
+    for (value, pid) in values.into_iter().zip(ids).rev() {
+        reordered[pid.0 as usize] = value;
+    }
+    reordered
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Builder, Compressed, Hnsw, Point, PointId, Search};
+
+/// An index of points identified by application keys, each carrying a value
+///
+/// This wraps an `Hnsw` along with the keys and values of its points, so that callers never
+/// have to deal with `PointId`s: searches yield `(key, value, distance)` tuples. Create one
+/// through `Builder::build_keyed()`, and add or replace entries with `upsert()`.
+///
+/// Keys can be chosen freely, while the `PointId`s of an `Hnsw` are dense and assigned by the
+/// index. Using keys as stable, externally chosen ids (for example, to replay an event log
+/// idempotently) costs a hash map lookup from key to `PointId`, plus a copy of every key.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "K: Serialize, P: Serialize, V: Serialize",
+        deserialize = "K: Deserialize<'de> + Eq + Hash, P: Deserialize<'de>, V: Deserialize<'de>"
+    ))
+)]
+pub struct KeyedHnsw<K, P, V> {
+    hnsw: Hnsw<P>,
+    /// Key of each point, ordered by `PointId`
+    keys: Vec<K>,
+    /// Value of each point, ordered by `PointId`
+    values: Vec<V>,
+    index: HashMap<K, PointId>,
+}
+
+impl<K, P, V> KeyedHnsw<K, P, V>
+where
+    K: Clone + Eq + Hash,
+    P: Point,
+{
+    pub(crate) fn new(entries: Vec<(K, P, V)>, builder: Builder) -> Self {
+        // Later entries replace earlier entries with the same key
+        let mut positions = HashMap::with_capacity(entries.len());
+        for (i, (key, _, _)) in entries.iter().enumerate() {
+            positions.insert(key.clone(), i);
+        }
+
+        let mut kept = Vec::with_capacity(positions.len());
+        let mut points = Vec::with_capacity(positions.len());
+        for (i, (key, point, value)) in entries.into_iter().enumerate() {
+            if positions[&key] == i {
+                kept.push((key, value));
+                points.push(point);
+            }
+        }
+
+        let (hnsw, ids) = builder.build(&points);
+        let mut slots = Vec::with_capacity(kept.len());
+        slots.resize_with(kept.len(), || None);
+        for ((key, value), pid) in kept.into_iter().zip(&ids) {
+            slots[pid.0 as usize] = Some((key, value));
+        }
+
+        let (keys, values): (Vec<_>, Vec<_>) = slots.into_iter().map(Option::unwrap).unzip();
+        let index = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.clone(), PointId(i as u32)))
+            .collect();
+
+        Self {
+            hnsw,
+            keys,
+            values,
+            index,
+        }
+    }
+
+    /// Search the index for the entries nearest to `point`, nearest first
+    ///
+    /// Yields the key, value and distance of each result. The number of results is governed
+    /// by `search`, like for `Hnsw::search()`.
+    pub fn search<'a>(
+        &'a self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = (&'a K, &'a V, f32)> + 'a {
+        self.hnsw.search(point, search).map(move |candidate| {
+            let idx = candidate.pid.0 as usize;
+            (&self.keys[idx], &self.values[idx], candidate.distance())
+        })
+    }
+
+    /// Store `point` and `value` for `key`, replacing the entry for `key` if there is one
+    ///
+    /// An existing entry keeps its `PointId` and is moved using `Hnsw::update()`, while new
+    /// entries are added using `Hnsw::append()`; see there for how this affects the graph.
+    /// Upserting the same entry again leaves the keys, points and values unchanged, so replaying
+    /// a sequence of upserts yields the same entries. The point is still relinked, though, so the
+    /// graph's edges (and with them, search results) may change. Returns the `PointId` of the
+    /// entry. Fails if the graph is compressed, leaving the index unchanged.
+    pub fn upsert(
+        &mut self,
+        key: K,
+        point: P,
+        value: V,
+        search: &mut Search,
+    ) -> Result<PointId, Compressed> {
+        if let Some(&pid) = self.index.get(&key) {
+            self.hnsw.update(pid, point, search)?;
+            self.values[pid.0 as usize] = value;
+            return Ok(pid);
+        }
+
+        let pid = self.hnsw.append(point, search)?;
+        self.keys.push(key.clone());
+        self.values.push(value);
+        self.index.insert(key, pid);
+        Ok(pid)
+    }
+
+    /// The point and value stored for `key`, if any
+    pub fn get(&self, key: &K) -> Option<(&P, &V)> {
+        let pid = *self.index.get(key)?;
+        Some((&self.hnsw[pid], &self.values[pid.0 as usize]))
+    }
+
+    /// Whether the index holds an entry for `key`
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Iterate over the key, point and value of every entry
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &P, &V)> {
+        self.keys
+            .iter()
+            .zip(self.hnsw.iter())
+            .zip(&self.values)
+            .map(|((key, (_, point)), value)| (key, point, value))
+    }
+
+    /// The number of entries in the index
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether the index holds no entries
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The underlying `Hnsw`, for operations that work with `PointId`s
+    pub fn hnsw(&self) -> &Hnsw<P> {
+        &self.hnsw
+    }
+
+    /// Replace every point in the index, keeping the graph, keys and values as they are
+    ///
+    /// See `Hnsw::map_points()`; the new points must yield the same distances as the old ones.
+    pub fn map_points<Q: Point>(self, map: impl FnMut(PointId, P) -> Q) -> KeyedHnsw<K, Q, V> {
+        KeyedHnsw {
+            hnsw: self.hnsw.map_points(map),
+            keys: self.keys,
+            values: self.values,
+            index: self.index,
+        }
+    }
+}
@@ -1,34 +1,74 @@
-use std::cmp::{max, Ordering, Reverse};
+use std::cmp::{max, min, Ordering, Reverse};
 use std::collections::BinaryHeap;
-use std::collections::HashSet;
-#[cfg(feature = "indicatif")]
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::iter;
+use std::ops::{Deref, DerefMut};
+#[cfg(any(feature = "indicatif", feature = "tracing"))]
 use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(feature = "indicatif")]
 use indicatif::ProgressBar;
 use ordered_float::OrderedFloat;
 use parking_lot::{Mutex, RwLock};
 use rand::rngs::SmallRng;
+use rand::seq::{index, SliceRandom};
 use rand::{Rng, SeedableRng};
-use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use rayon::{ThreadPool, ThreadPoolBuilder};
 #[cfg(feature = "serde")]
-use serde::{Deserialize, Serialize};
-
+use serde::{Deserialize, Deserializer, Serialize};
+
+mod attributes;
+mod keyed;
+mod output;
+mod params;
+#[cfg(feature = "testdata")]
+pub mod testdata;
 mod types;
+pub use attributes::{Attributes, Column, Condition};
+pub use keyed::KeyedHnsw;
+pub use output::BuildOutput;
+pub use params::BuildParams;
 pub use types::PointId;
-use types::{Candidate, Layer, LayerId, UpperNode, Visited, ZeroNode, INVALID};
+use types::{
+    Candidate, FormatVersion, Layer, LayerId, Nodes, UpperNode, Visited, ZeroNode, INVALID,
+};
 
 /// Parameters for building the `Hnsw`
 pub struct Builder {
     ef_search: usize,
     ef_construction: usize,
     heuristic: Option<Heuristic>,
-    ml: f32,
+    max_connections: usize,
+    max_connections_0: Option<usize>,
+    min_connections: usize,
+    layer_distribution: LayerDistribution,
+    order: InsertionOrder,
+    entry_point: EntryStrategy,
     seed: u64,
+    layer_seed: Option<u64>,
+    shuffle_seed: Option<u64>,
+    deterministic: bool,
+    require_connected: bool,
+    compress_graph: bool,
+    flat: bool,
+    thread_pool: Option<Arc<ThreadPool>>,
     #[cfg(feature = "indicatif")]
     progress: Option<ProgressBar>,
+    stats: Option<(usize, StatsCallback)>,
+    warning: Option<WarningCallback>,
 }
 
+type StatsCallback = Box<dyn FnMut(&BuildStats) + Send>;
+type WarningCallback = Box<dyn Fn(Warning) + Send>;
+
 impl Builder {
     /// Set the `efConstruction` parameter from the paper
     pub fn ef_construction(mut self, ef_construction: usize) -> Self {
@@ -50,11 +90,81 @@ impl Builder {
         self
     }
 
+    /// Set the maximum number of neighbors per node on layers above the zero layer
+    ///
+    /// This is the `M` parameter from the paper, and defaults to 32, which is also the
+    /// maximum value: node storage is sized for 32 neighbors at compile time.
+    pub fn max_connections(mut self, m: usize) -> Self {
+        assert!(
+            m > 0 && m <= M,
+            "max_connections must be between 1 and {}",
+            M
+        );
+        self.max_connections = m;
+        self
+    }
+
+    /// Set the maximum number of neighbors per node on the zero layer
+    ///
+    /// This is the `Mmax0` parameter from the paper. If unset, it defaults to twice the value
+    /// of `max_connections`. Since the zero layer usually determines the quality of search
+    /// results, this has much more impact on recall (and memory use) than `max_connections`.
+    /// The maximum value is 64.
+    pub fn max_connections_0(mut self, m0: usize) -> Self {
+        assert!(
+            m0 > 0 && m0 <= M * 2,
+            "max_connections_0 must be between 1 and {}",
+            M * 2
+        );
+        self.max_connections_0 = Some(m0);
+        self
+    }
+
+    /// Set the minimum number of neighbors kept per node by the selection heuristic
+    ///
+    /// The heuristic prunes candidates that are closer to an already selected neighbor than to
+    /// the node itself. If pruned candidates are not kept (see `Heuristic::keep_pruned`), nodes
+    /// in sparse regions, like outliers, can end up with only one or two links, which makes
+    /// them hard to reach. With a minimum set, the nearest pruned candidates are added back
+    /// until every node has at least `m_min` neighbors (or has run out of candidates), at the
+    /// expense of some redundant links. This has no effect when pruned candidates are kept,
+    /// which fills neighbor lists to capacity, or when no heuristic is used. Values above the
+    /// maximum number of neighbors on a layer are capped to it. Defaults to 0.
+    pub fn min_connections(mut self, m_min: usize) -> Self {
+        self.min_connections = m_min;
+        self
+    }
+
     /// Set the `mL` parameter from the paper
     ///
-    /// If the `mL` parameter is not already set, it defaults to `1.0 / ln(M)`.
+    /// If the `mL` parameter is not already set, it defaults to `1.0 / ln(M)`. This is a
+    /// shorthand for selecting `LayerDistribution::Exponential` with the given `ml`.
     pub fn ml(mut self, ml: f32) -> Self {
-        self.ml = ml;
+        self.layer_distribution = LayerDistribution::Exponential { ml };
+        self
+    }
+
+    /// Set the strategy used to distribute points over the layers of the hierarchy
+    ///
+    /// Defaults to `LayerDistribution::Exponential` with `ml` set to `1.0 / ln(M)`.
+    pub fn layer_distribution(mut self, distribution: LayerDistribution) -> Self {
+        self.layer_distribution = distribution;
+        self
+    }
+
+    /// Set the order in which `PointId`s are assigned to points on the same layer
+    ///
+    /// Defaults to `InsertionOrder::Random`.
+    pub fn order(mut self, order: InsertionOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Set the strategy used to pick the entry point, where every search starts
+    ///
+    /// Defaults to `EntryStrategy::Random`.
+    pub fn entry_point(mut self, strategy: EntryStrategy) -> Self {
+        self.entry_point = strategy;
         self
     }
 
@@ -66,6 +176,102 @@ impl Builder {
         self
     }
 
+    /// Set a separate seed for assigning points to layers
+    ///
+    /// This determines which points end up on which layer. Defaults to the `seed` value.
+    pub fn layer_seed(mut self, seed: u64) -> Self {
+        self.layer_seed = Some(seed);
+        self
+    }
+
+    /// Set a separate seed for shuffling the insertion order of points within each layer
+    ///
+    /// Unless this is set, the insertion order is derived from the layer assignment, so that a
+    /// single `seed` controls both. Has no effect with `InsertionOrder::Input`.
+    pub fn shuffle_seed(mut self, seed: u64) -> Self {
+        self.shuffle_seed = Some(seed);
+        self
+    }
+
+    /// Build an identical index regardless of the number of threads
+    ///
+    /// By default, points are inserted concurrently, so the neighbors found for a point depend
+    /// on which other points happened to be inserted before it. With this option, points are
+    /// inserted in batches: the neighbors for every point in a batch are searched in parallel
+    /// against the graph as it was before the batch, and the points are then linked into the
+    /// graph one by one, in order. Together with a fixed `seed`, this produces the same index
+    /// on every run. Building takes longer, since linking is serialized, and recall can be
+    /// slightly lower, since points don't see the other points in their batch while searching.
+    ///
+    /// Batch boundaries only depend on the layer sizes (and thus on the `seed` and the number of
+    /// points), not on the checkpoint or `stats()` intervals: checkpoints and stats callbacks
+    /// are deferred to the end of the batch in progress, so they may come slightly later than
+    /// every `every` points. A build resumed from a checkpoint yields the same index as an
+    /// uninterrupted one. Defaults to `false`.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Store neighbor lists using the smallest integer width that fits the number of points
+    ///
+    /// Neighbor indices are packed into 2, 3 or 4 bytes each (chosen once construction has
+    /// finished), which saves up to half of the graph memory for smaller indexes at the cost
+    /// of slightly slower traversal. Defaults to `false`.
+    pub fn compress_graph(mut self, compress: bool) -> Self {
+        self.compress_graph = compress;
+        self
+    }
+
+    /// Store the points without building a graph, so that searches compare against every point
+    ///
+    /// For small datasets (up to a few thousand points), such a linear scan can be faster than
+    /// traversing a graph, and it always finds the exact nearest neighbors. The index keeps the
+    /// points in input order, so each point's `PointId` matches its input index. Defaults to
+    /// `false`.
+    pub fn flat(mut self, flat: bool) -> Self {
+        self.flat = flat;
+        self
+    }
+
+    /// Fail the build if some points can't be reached from the entry point
+    ///
+    /// Unreachable points (see `Hnsw::unreachable()`) are never returned by searches. With this
+    /// option, `try_build()` returns an error listing them, and `build()` and the methods based
+    /// on it (`build_flat()`, `build_dedup()` and `build_keyed()`) panic. Checkpointed builds
+    /// are not checked. The check takes a pass over all links, so it is off by default.
+    pub fn require_connected(mut self, require: bool) -> Self {
+        self.require_connected = require;
+        self
+    }
+
+    /// Build the index using `threads` threads
+    ///
+    /// This creates a thread pool for the builds done with this `Builder`. Without it, builds
+    /// run on the rayon pool of the calling context: the global pool (sized through
+    /// `rayon::ThreadPoolBuilder::build_global()` or the `RAYON_NUM_THREADS` environment
+    /// variable, and by default one thread per CPU), or the pool a build is called from through
+    /// `ThreadPool::install()`. A pool set here takes precedence over both. To cap the total
+    /// number of threads used by concurrent builds, share a single pool through
+    /// `thread_pool()` instead. Panics if the thread pool can't be created.
+    pub fn threads(self, threads: usize) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("failed to create thread pool");
+        self.thread_pool(Arc::new(pool))
+    }
+
+    /// Build the index on the given rayon thread pool
+    ///
+    /// Builds that share a pool also share its threads, so this can be used to limit the total
+    /// parallelism of several builds running at the same time. See `threads()` for how this
+    /// relates to the global rayon configuration.
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(pool);
+        self
+    }
+
     /// A `ProgressBar` to track `Hnsw` construction progress
     #[cfg(feature = "indicatif")]
     pub fn progress(mut self, bar: ProgressBar) -> Self {
@@ -73,22 +279,179 @@ impl Builder {
         self
     }
 
+    /// Call `callback` with a snapshot of the build progress after every `every` inserted points
+    ///
+    /// The callback is also called once when the build has finished. Construction pauses
+    /// while the callback runs, so it should return quickly.
+    pub fn stats_callback(
+        mut self,
+        every: usize,
+        callback: impl FnMut(&BuildStats) + Send + 'static,
+    ) -> Self {
+        assert!(every > 0, "stats interval must be at least 1");
+        self.stats = Some((every, Box::new(callback)));
+        self
+    }
+
+    /// Call `callback` for every `Warning` about settings or data that may reduce recall
+    ///
+    /// Warnings are only checked for when a callback is set. Checking happens once the graph
+    /// has been built, which takes a pass over all links; flat indexes are not checked.
+    pub fn on_warning(mut self, callback: impl Fn(Warning) + Send + 'static) -> Self {
+        self.warning = Some(Box::new(callback));
+        self
+    }
+
     /// Build the `Hnsw` with the given set of points
+    ///
+    /// The returned `Vec<PointId>` has an entry for every input point, in input order:
+    /// `ids[i]` is the `PointId` assigned to `points[i]`. `PointId`s are assigned in
+    /// construction order, so they generally differ from the input indices; use
+    /// `Hnsw::original_index()` to map a `PointId` back to its input index. `build_output()`
+    /// returns both as a single `BuildOutput`, which documents this relationship.
+    ///
+    /// Panics if the index doesn't meet the requirement set through `require_connected()`;
+    /// use `try_build()` to handle that case.
     pub fn build<P: Point>(self, points: &[P]) -> (Hnsw<P>, Vec<PointId>) {
-        Hnsw::new(points, self)
+        match self.try_build(points) {
+            Ok(built) => built,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Build the `Hnsw` like `build()`, returning it along with the ids as a `BuildOutput`
+    pub fn build_output<P: Point>(self, points: &[P]) -> BuildOutput<P> {
+        self.build(points).into()
+    }
+
+    /// Build the `Hnsw` like `build()`, returning an error if it is not fully connected
+    ///
+    /// Connectivity is only checked if enabled through `require_connected()`.
+    pub fn try_build<P: Point>(
+        self,
+        points: &[P],
+    ) -> Result<(Hnsw<P>, Vec<PointId>), Disconnected> {
+        let require_connected = self.require_connected;
+        let (hnsw, ids) = Hnsw::new(points, self);
+        if require_connected {
+            let unreachable = hnsw.unreachable();
+            if !unreachable.is_empty() {
+                return Err(Disconnected { unreachable });
+            }
+        }
+
+        Ok((hnsw, ids))
+    }
+
+    /// Build the `Hnsw` from a single slice of `dim`-element rows
+    ///
+    /// Each row of `data` is converted into a point, so callers with contiguous data (for
+    /// example, from C or Arrow) don't have to split it up first. Panics if `dim` is zero or the
+    /// length of `data` is not a multiple of `dim`.
+    pub fn build_flat<P>(self, data: &[f32], dim: usize) -> (Hnsw<P>, Vec<PointId>)
+    where
+        P: Point + for<'a> From<&'a [f32]>,
+    {
+        assert!(dim > 0, "dimension must be at least 1");
+        assert!(
+            data.chunks_exact(dim).remainder().is_empty(),
+            "data length {} is not a multiple of dimension {}",
+            data.len(),
+            dim
+        );
+
+        let points = data.chunks_exact(dim).map(P::from).collect::<Vec<_>>();
+        self.build(&points)
+    }
+
+    /// Build the `Hnsw`, passing a `Checkpoint` to `save` after every `every` inserted points
+    ///
+    /// The checkpoint can be stored (for example, with the `serde` feature) and passed to
+    /// `resume()` to continue an interrupted build. Taking a checkpoint briefly pauses
+    /// construction, so `every` should not be too small.
+    pub fn build_checkpointed<P: Point>(
+        self,
+        points: &[P],
+        every: usize,
+        mut save: impl FnMut(&Checkpoint<P>),
+    ) -> (Hnsw<P>, Vec<PointId>) {
+        assert!(every > 0, "checkpoint interval must be at least 1");
+        if points.is_empty() || self.flat {
+            return Hnsw::new(points, self);
+        }
+
+        Hnsw::construct(Checkpoint::new(points, &self), self, every, &mut save)
+    }
+
+    /// Continue building the `Hnsw` from a `Checkpoint`
+    ///
+    /// The layer assignment and insertion order are taken from the checkpoint, but the other
+    /// parameters are not stored, so the `Builder` should be configured like the one that
+    /// produced the checkpoint. Further checkpoints are passed to `save` as in
+    /// `build_checkpointed()`.
+    pub fn resume<P: Point>(
+        self,
+        checkpoint: Checkpoint<P>,
+        every: usize,
+        mut save: impl FnMut(&Checkpoint<P>),
+    ) -> (Hnsw<P>, Vec<PointId>) {
+        assert!(every > 0, "checkpoint interval must be at least 1");
+        Hnsw::construct(checkpoint, self, every, &mut save)
+    }
+
+    /// Build the `Hnsw` with the given set of points, skipping exact duplicates
+    ///
+    /// Points that are equal to an earlier point (according to their `Hash` and `Eq`
+    /// implementations) are only stored once. The returned `PointId`s still correspond to the
+    /// input points, so duplicates map to the `PointId` of the first occurrence. Note that
+    /// this only catches exact duplicates: points that are merely very close are kept.
+    pub fn build_dedup<P: Point + Hash + Eq>(self, points: &[P]) -> (Hnsw<P>, Vec<PointId>) {
+        let mut unique = Vec::new();
+        let mut first = HashMap::new();
+        let indices = points
+            .iter()
+            .map(|point| {
+                *first.entry(point).or_insert_with(|| {
+                    unique.push(point.clone());
+                    unique.len() - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (mut hnsw, pids) = self.build(&unique);
+        let mut firsts = vec![0; unique.len()];
+        for (i, &idx) in indices.iter().enumerate().rev() {
+            firsts[idx] = i as u32;
+        }
+        for original in hnsw.original.iter_mut() {
+            *original = firsts[*original as usize];
+        }
+
+        (hnsw, indices.into_iter().map(|idx| pids[idx]).collect())
+    }
+
+    /// Build a `KeyedHnsw` from `(key, point, value)` entries
+    ///
+    /// If several entries share a key, only the last one is kept.
+    pub fn build_keyed<K, P, V>(self, entries: Vec<(K, P, V)>) -> KeyedHnsw<K, P, V>
+    where
+        K: Clone + Eq + Hash,
+        P: Point,
+    {
+        KeyedHnsw::new(entries, self)
     }
 
+    /// Returns `(ef_search, ef_construction, ml, seed)`, where `ml` is NaN for
+    /// `LayerDistribution::Uniform`, which doesn't use it
     #[doc(hidden)]
     pub fn into_parts(self) -> (usize, usize, f32, u64) {
-        let Self {
-            ef_search,
-            ef_construction,
-            heuristic: _,
-            ml,
-            seed,
-            ..
-        } = self;
-        (ef_search, ef_construction, ml, seed)
+        let params = BuildParams::from(&self);
+        (
+            params.ef_search,
+            params.ef_construction,
+            params.ml().unwrap_or(f32::NAN),
+            params.seed,
+        )
     }
 }
 
@@ -98,14 +461,153 @@ impl Default for Builder {
             ef_search: 100,
             ef_construction: 100,
             heuristic: Some(Heuristic::default()),
-            ml: 1.0 / (M as f32).ln(),
+            max_connections: M,
+            max_connections_0: None,
+            min_connections: 0,
+            layer_distribution: LayerDistribution::default(),
+            order: InsertionOrder::Random,
+            entry_point: EntryStrategy::Random,
             seed: rand::random(),
+            layer_seed: None,
+            shuffle_seed: None,
+            deterministic: false,
+            require_connected: false,
+            compress_graph: false,
+            flat: false,
+            thread_pool: None,
             #[cfg(feature = "indicatif")]
             progress: None,
+            stats: None,
+            warning: None,
+        }
+    }
+}
+
+/// Strategy for distributing points over the layers of the hierarchy
+///
+/// Each point is present on the zero layer, and every layer above it holds a subset of the
+/// points on the layer below. The distribution determines how quickly layers shrink, and
+/// thereby the height of the hierarchy.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Copy, Clone, Debug)]
+pub enum LayerDistribution {
+    /// Each layer holds `ml` times as many points as the layer below it
+    ///
+    /// This is the distribution from the paper. Layers are added until the next layer would
+    /// hold fewer than `M` points, so the height grows logarithmically with the number of
+    /// points.
+    Exponential { ml: f32 },
+    /// Like `Exponential`, but with at most `max_layers` layers (including the zero layer)
+    ///
+    /// This bounds the height of the hierarchy for very large indexes; the top layer will
+    /// hold more points than it would otherwise, making the first steps of a search slower.
+    Capped { ml: f32, max_layers: usize },
+    /// Points are spread uniformly over a fixed number of `layers`
+    ///
+    /// Layer sizes decrease linearly instead of exponentially, so the height is fixed
+    /// regardless of the number of points, and upper layers are much denser than usual.
+    Uniform { layers: usize },
+}
+
+impl LayerDistribution {
+    /// Number of points on each layer, starting with all `len` points on the zero layer
+    fn layer_sizes(&self, len: usize) -> Vec<usize> {
+        let mut sizes = vec![len];
+        match *self {
+            LayerDistribution::Exponential { ml } => exponential(&mut sizes, ml, usize::MAX),
+            LayerDistribution::Capped { ml, max_layers } => exponential(&mut sizes, ml, max_layers),
+            LayerDistribution::Uniform { layers } => {
+                for layer in 1..layers {
+                    match len * (layers - layer) / layers {
+                        0 => break,
+                        next => sizes.push(next),
+                    }
+                }
+            }
+        }
+
+        sizes
+    }
+}
+
+/// Add exponentially shrinking layer sizes to `sizes` until the layer would be smaller than `M`
+fn exponential(sizes: &mut Vec<usize>, ml: f32, max_layers: usize) {
+    while sizes.len() < max_layers {
+        let num = *sizes.last().unwrap();
+        let next = (num as f32 * ml) as usize;
+        if next < M || next >= num {
+            break;
+        }
+        sizes.push(next);
+    }
+}
+
+impl Default for LayerDistribution {
+    fn default() -> Self {
+        LayerDistribution::Exponential {
+            ml: 1.0 / (M as f32).ln(),
         }
     }
 }
 
+/// Order in which `PointId`s are assigned to the points on each layer
+///
+/// Points are inserted in order of their `PointId`, and the `Hnsw` stores points and their
+/// neighbor lists in the same order. Which points end up on which layer is randomized
+/// (controlled by the `seed`, or `layer_seed` if set) regardless of the order.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Copy, Clone, Debug)]
+pub enum InsertionOrder {
+    /// Points on each layer are shuffled randomly
+    Random,
+    /// Points on each layer keep their relative order from the input
+    ///
+    /// When the input is sorted for locality (for example, along a Hilbert or Morton curve),
+    /// nearby points get nearby `PointId`s and are stored close together, which can improve
+    /// cache efficiency during construction and search. Note that `PointId`s are still not
+    /// equal to input indexes, since points on higher layers get lower `PointId`s.
+    ///
+    /// The index relies on that numbering: a point's layer follows from its `PointId`, and the
+    /// entry point is always `PointId` 0. Use `Hnsw::original_index()` (or the ids returned by
+    /// the `Builder`) to map between the two. Inserting points in a non-random order can lower
+    /// recall when the input is ordered by something correlated with position (for example,
+    /// sorted clusters), because early points then get linked among themselves before the rest
+    /// of the space is populated; compare against `Random` on a sample of queries.
+    Input,
+}
+
+/// Strategy for picking the entry point, the point at the top of the hierarchy
+///
+/// Every search and every insertion starts out from the entry point, so a point close to the
+/// center of the data can shorten the routes through the upper layers.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Copy, Clone, Debug)]
+pub enum EntryStrategy {
+    /// Use whichever point is randomly assigned to the top of the hierarchy
+    Random,
+    /// Use the approximate medoid of the points: out of a random `sample` of points, the one
+    /// with the smallest total distance to the rest of the sample
+    ///
+    /// This computes `sample * sample` distances before construction starts. The medoid swaps
+    /// places with the randomly chosen entry point, so the layer sizes stay the same.
+    Medoid { sample: usize },
+}
+
+/// How `Hnsw::sample()` picks points
+#[derive(Copy, Clone, Debug)]
+pub enum Sampling {
+    /// Take whole layers from the top of the hierarchy down, sampling randomly from the first
+    /// layer that doesn't fit
+    ///
+    /// Upper layers are sparse, roughly uniform samples of the data, so this yields points
+    /// spread over the whole dataset, and repeated calls with a larger `n` yield a superset of
+    /// the points from a smaller `n`.
+    Layers,
+    /// Sample uniformly at random from all points
+    Uniform,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[derive(Copy, Clone, Debug)]
 pub struct Heuristic {
     pub extend_candidates: bool,
@@ -121,12 +623,36 @@ impl Default for Heuristic {
     }
 }
 
+/// An index of points using hierarchical navigable small world graphs
+///
+/// Searching only requires a shared reference to the index: all mutable state used while
+/// searching lives in the caller-provided `Search`. `Hnsw<P>` is `Send` and `Sync` whenever
+/// `P` is, so a single index can be shared between threads (for example, via an `Arc`) and
+/// searched concurrently without any locking.
+///
+/// The serialized layout starts with a format version. Dumps written by earlier releases don't
+/// have one and use an older layout, without `original_index()` or packed layers: `Deserialize`
+/// rejects them, so read them with `Hnsw::deserialize_legacy()` instead.
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone)]
 pub struct Hnsw<P> {
+    version: FormatVersion,
     ef_search: usize,
     points: Vec<P>,
-    zero: Vec<ZeroNode>,
-    layers: Vec<Vec<UpperNode>>,
+    /// Index of each point in the input passed to the `Builder`
+    ///
+    /// Dumps from before the format was versioned don't have this, so `deserialize_legacy()`
+    /// fills in the identity.
+    original: Vec<u32>,
+    zero: Nodes<ZeroNode>,
+    layers: Vec<Nodes<UpperNode>>,
+    /// The parameters the index was built with, unless it was read by `load_graph_only()`
+    ///
+    /// Not serialized: a dump only holds what searches need, while the parameters are only used
+    /// to modify the index. Callers that do modify it store them alongside the dump, as
+    /// described on `build_params()`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    params: Option<BuildParams>,
 }
 
 impl<P> Hnsw<P>
@@ -138,45 +664,1991 @@ where
     }
 
     fn new(points: &[P], builder: Builder) -> (Self, Vec<PointId>) {
+        if builder.flat {
+            // Flat indexes are recognized by their empty zero layer.
+            assert!(points.len() < u32::MAX as usize);
+            return (
+                Self {
+                    version: FormatVersion,
+                    ef_search: builder.ef_search,
+                    zero: Nodes::Full(Vec::new()),
+                    points: points.to_vec(),
+                    original: (0..points.len() as u32).collect(),
+                    layers: Vec::new(),
+                    params: Some(BuildParams::from(&builder)),
+                },
+                (0..points.len() as u32).map(PointId).collect(),
+            );
+        }
+
+        if points.is_empty() {
+            return (
+                Self {
+                    version: FormatVersion,
+                    ef_search: builder.ef_search,
+                    zero: Nodes::Full(Vec::new()),
+                    points: Vec::new(),
+                    original: Vec::new(),
+                    layers: Vec::new(),
+                    params: Some(BuildParams::from(&builder)),
+                },
+                Vec::new(),
+            );
+        }
+
+        let checkpoint = Checkpoint::new(points, &builder);
+        Self::construct(checkpoint, builder, usize::MAX, &mut |_| {})
+    }
+
+    /// Insert the remaining points from `checkpoint`, calling `save` after every `every` points
+    fn construct(
+        checkpoint: Checkpoint<P>,
+        builder: Builder,
+        every: usize,
+        save: &mut dyn FnMut(&Checkpoint<P>),
+    ) -> (Self, Vec<PointId>) {
+        let params = BuildParams::from(&builder);
         let ef_search = builder.ef_search;
         let ef_construction = builder.ef_construction;
-        let ml = builder.ml;
         let heuristic = builder.heuristic;
-        let mut rng = SmallRng::seed_from_u64(builder.seed);
+        let max_connections = builder.max_connections;
+        let max_connections_0 = builder
+            .max_connections_0
+            .unwrap_or(max_connections * 2)
+            .min(M * 2);
+        let compress = builder.compress_graph;
+        let deterministic = builder.deterministic;
+        let thread_pool = builder.thread_pool.as_deref();
+        let warning = builder.warning;
+        if let Some(warn) = &warning {
+            if ef_construction < ef_search {
+                warn(Warning::LowEfConstruction {
+                    ef_construction,
+                    ef_search,
+                });
+            }
+        }
+
+        let Checkpoint {
+            mut points,
+            mut out,
+            mut sizes,
+            zero,
+            mut layers,
+            mut done,
+        } = checkpoint;
+        let mut zero = zero.into_iter().map(RwLock::new).collect::<Vec<_>>();
+
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::info_span!("build", points = points.len(), resumed_at = done).entered();
+
+        #[cfg(feature = "indicatif")]
+        let progress = builder.progress;
+        #[cfg(feature = "indicatif")]
+        if let Some(bar) = &progress {
+            bar.set_draw_delta(1_000);
+            bar.set_length(points.len() as u64);
+            bar.set_message("Build index (preparation)");
+        }
+
+        // The layer from the first node is our top layer.
+
+        let top = LayerId(sizes.len() - 1);
+
+        // Figure out how many nodes will go on each layer. This helps us allocate memory capacity
+        // for each layer in advance, and also helps enable batch insertion of points.
+
+        let num_layers = sizes.len();
+        let mut ranges = Vec::with_capacity(top.0);
+        for (i, &(size, cumulative)) in sizes.iter().enumerate() {
+            let start = cumulative - size;
+            // Skip the first point, since we insert the enter point separately
+            ranges.push((LayerId(num_layers - i - 1), max(start, 1)..cumulative));
+        }
+
+        let pool = SearchPool::new(points.len(), builder.min_connections);
+        #[cfg(any(feature = "indicatif", feature = "tracing"))]
+        let counter = AtomicUsize::new(done);
+        let mut next_checkpoint = done.saturating_add(every);
+        let mut stats = builder.stats;
+        let mut next_stats = match &stats {
+            Some((every, _)) => done.saturating_add(*every),
+            None => usize::MAX,
+        };
+        for (layer, range) in ranges {
+            let num = if layer.is_zero() { M * 2 } else { M };
+            #[cfg(feature = "indicatif")]
+            if let Some(bar) = &progress {
+                bar.set_message(&format!("Building index (layer {})", layer.0));
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(layer = layer.0, points = range.end, "building layer");
+
+            // Insert the points in chunks, so that the state in between chunks can be saved.
+            while done < range.end {
+                let start = max(range.start, done);
+                let mut end = min(range.end, min(next_checkpoint, next_stats));
+                if deterministic {
+                    // Extend the chunk to the end of a batch, so chunks don't split batches
+                    let mut batch = start;
+                    while batch < end {
+                        batch = min(range.end, batch + batch_size(batch));
+                    }
+                    end = batch;
+                }
+                let descend = |pid: PointId, search: &mut Search| {
+                    let point = &points.as_slice()[pid];
+                    search.reset();
+                    search.push(PointId(0), point, &points);
+
+                    for cur in top.descend() {
+                        search.ef = if cur <= layer { ef_construction } else { 1 };
+                        match cur > layer {
+                            true => {
+                                search.search(point, layers[cur.0 - 1].as_slice(), &points, num);
+                                search.cull();
+                            }
+                            false => {
+                                search.search(point, zero.as_slice(), &points, num);
+                                break;
+                            }
+                        }
+                    }
+                };
+
+                let inserted = || {
+                    #[cfg(any(feature = "indicatif", feature = "tracing"))]
+                    let value = counter.fetch_add(1, atomic::Ordering::Relaxed);
+                    #[cfg(feature = "indicatif")]
+                    if let Some(bar) = &progress {
+                        #[allow(clippy::manual_is_multiple_of)]
+                        if value % 1000 == 0 {
+                            bar.set_position(value as u64);
+                        }
+                    }
+                    #[cfg(feature = "tracing")]
+                    #[allow(clippy::manual_is_multiple_of)]
+                    if value % 100_000 == 0 {
+                        tracing::info!(inserted = value, total = points.len(), "inserting points");
+                    }
+                };
+
+                install(thread_pool, || match deterministic {
+                    false => (start..end).into_par_iter().for_each(|pid| {
+                        let pid = PointId(pid as u32);
+                        let node = zero.as_slice()[pid].write();
+                        let (mut search, mut insertion) = pool.pop();
+                        descend(pid, &mut search);
+                        insertion.ef = ef_construction;
+                        insert(
+                            pid,
+                            node,
+                            &mut insertion,
+                            &mut search,
+                            &zero,
+                            &points,
+                            &heuristic,
+                            max_connections_0,
+                        );
+                        inserted();
+                        pool.push((search, insertion));
+                    }),
+                    true => {
+                        let (mut search, mut insertion) = pool.pop();
+                        insertion.ef = ef_construction;
+                        let mut batch = start;
+                        while batch < end {
+                            let batch_end = min(end, batch + batch_size(batch));
+                            let found = (batch..batch_end)
+                                .into_par_iter()
+                                .map(|pid| {
+                                    let (mut search, insertion) = pool.pop();
+                                    descend(PointId(pid as u32), &mut search);
+                                    let nearest = search.nearest.clone();
+                                    pool.push((search, insertion));
+                                    nearest
+                                })
+                                .collect::<Vec<_>>();
+
+                            for (pid, nearest) in (batch..batch_end).zip(found) {
+                                let pid = PointId(pid as u32);
+                                search.restore(&nearest, ef_construction);
+                                insert(
+                                    pid,
+                                    zero.as_slice()[pid].write(),
+                                    &mut insertion,
+                                    &mut search,
+                                    &zero,
+                                    &points,
+                                    &heuristic,
+                                    max_connections_0,
+                                );
+                                inserted();
+                            }
+                            batch = batch_end;
+                        }
+                        pool.push((search, insertion));
+                    }
+                });
+
+                done = end;
+                if let Some((every, callback)) = &mut stats {
+                    if done >= next_stats && done < points.len() {
+                        callback(&BuildStats::new(done, &sizes));
+                        next_stats = done.saturating_add(*every);
+                    }
+                }
+
+                if done >= next_checkpoint && done < points.len() {
+                    let checkpoint = Checkpoint {
+                        points,
+                        out,
+                        sizes,
+                        zero: zero.into_iter().map(RwLock::into_inner).collect(),
+                        layers,
+                        done,
+                    };
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(inserted = done, "saving checkpoint");
+                    save(&checkpoint);
+                    points = checkpoint.points;
+                    out = checkpoint.out;
+                    sizes = checkpoint.sizes;
+                    zero = checkpoint.zero.into_iter().map(RwLock::new).collect();
+                    layers = checkpoint.layers;
+                    next_checkpoint = done.saturating_add(every);
+                }
+            }
+
+            // For layers above the zero layer, make a copy of the current state of the zero layer
+            // with `nearest` truncated to `max_connections` elements. A resumed build may already
+            // have copied this layer before the checkpoint was taken.
+            if !layer.is_zero() && layers[layer.0 - 1].is_empty() {
+                let mut upper = Vec::new();
+                install(thread_pool, || {
+                    (&zero[..range.end])
+                        .into_par_iter()
+                        .map(|zero| UpperNode::from_zero(&zero.read(), max_connections))
+                        .collect_into_vec(&mut upper)
+                });
+                layers[layer.0 - 1] = upper;
+            }
+        }
+
+        #[cfg(feature = "indicatif")]
+        if let Some(bar) = progress {
+            bar.finish();
+        }
+
+        if let Some((_, callback)) = &mut stats {
+            callback(&BuildStats::new(points.len(), &sizes));
+        }
+
+        let mut original = vec![0; points.len()];
+        for (idx, pid) in out.iter().enumerate() {
+            original[pid.0 as usize] = idx as u32;
+        }
+
+        let zero = zero.into_iter().map(|node| node.into_inner()).collect();
+        let hnsw = Self {
+            version: FormatVersion,
+            ef_search,
+            zero: Nodes::new(zero, compress, points.len()),
+            layers: layers
+                .into_iter()
+                .map(|layer| Nodes::new(layer, compress, points.len()))
+                .collect(),
+            points,
+            original,
+            params: Some(params),
+        };
+
+        if let Some(warn) = &warning {
+            hnsw.check(warn);
+        }
+        (hnsw, out)
+    }
+
+    /// Report problems with the finished graph through `warn`
+    fn check(&self, warn: &dyn Fn(Warning)) {
+        // Neighbors are sorted nearest first, so only the first one can be a duplicate.
+        let duplicates = (0..self.points.len())
+            .map(|i| PointId(i as u32))
+            .filter(|&pid| match (&self.zero).nearest_iter(pid).next() {
+                Some(neighbor) => self[pid].distance(&self[neighbor]) == 0.0,
+                None => false,
+            })
+            .count();
+        if duplicates > 0 {
+            warn(Warning::DuplicatePoints { count: duplicates });
+        }
+
+        let components = self.connected_components();
+        if components.len() > 1 {
+            warn(Warning::Disconnected {
+                components: components.len(),
+                largest: components[0].len(),
+            });
+        }
+    }
+
+    /// Search the index for the points nearest to the reference point `point`
+    ///
+    /// The results are yielded nearest first, with ties between points at the same distance
+    /// broken by `PointId`. Searching is deterministic, so repeating a search on the same index
+    /// yields the same results in the same order. The number of results is `ef_search`, unless
+    /// limited through `Search::set_k()`.
+    pub fn search<'a>(
+        &self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Candidate> + 'a {
+        self.search_where(point, search, |_| true);
+        search.iter()
+    }
+
+    /// Search for `point` like `search()`, only returning points that match all `conditions`
+    ///
+    /// The conditions are evaluated against `attributes` while traversing the zero layer: points
+    /// that don't match are still used to route the search, but are not counted as results. This
+    /// means restrictive conditions make the search visit more nodes; if it finds fewer results
+    /// than expected, consider raising `ef_search`. Points that have no value in `attributes`
+    /// never match (see `Attributes::matches()`). Panics if a condition refers to a column that
+    /// doesn't exist in `attributes`.
+    pub fn search_filtered<'a>(
+        &self,
+        point: &P,
+        attributes: &Attributes,
+        conditions: &[Condition],
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Candidate> + 'a {
+        self.search_where(point, search, |pid| attributes.matches(pid, conditions));
+        search.iter()
+    }
+
+    /// Search for `point`, only retaining zero layer results for which `accept` returns `true`
+    ///
+    /// If the `Search` has a deduplication epsilon set, results within that distance of a
+    /// nearer result are dropped, and the search is repeated with a doubled `ef` until enough
+    /// results remain or all points have been considered.
+    fn search_where(&self, point: &P, search: &mut Search, accept: impl Fn(PointId) -> bool) {
+        let epsilon = match search.dedup_epsilon {
+            Some(epsilon) => epsilon,
+            None => {
+                self.traverse(point, search, accept);
+                search.apply_max_distance();
+                return;
+            }
+        };
+
+        let ef_search = search.ef_search;
+        let mut ef = ef_search.unwrap_or(self.ef_search);
+        let target = search.k.unwrap_or(ef);
+        loop {
+            search.ef_search = Some(ef);
+            self.traverse(point, search, &accept);
+            search.dedup(&self.points, epsilon);
+            if search.nearest.len() >= target || ef >= self.points.len() {
+                break;
+            }
+            ef = min(ef * 2, self.points.len());
+        }
+
+        search.nearest.truncate(target);
+        search.ef_search = ef_search;
+        search.apply_max_distance();
+    }
+
+    /// Search all layers for `point`, as described for `search_where()`
+    fn traverse(&self, point: &P, search: &mut Search, accept: impl Fn(PointId) -> bool) {
+        search.reset();
+        if self.points.is_empty() {
+            return;
+        }
+
+        if self.is_flat() {
+            search.ef = search.ef_search.unwrap_or(self.ef_search);
+            search.apply_min_distance();
+            search.scan(point, &self.points, accept);
+            return;
+        }
+
+        search.visited.reserve_capacity(self.points.len());
+        search.layer = self.layers.len();
+        search.push(PointId(0), point, &self.points);
+        for cur in LayerId(self.layers.len()).descend() {
+            search.layer = cur.0;
+            let (ef, num) = match cur.is_zero() {
+                true => (search.ef_search.unwrap_or(self.ef_search), M * 2),
+                false => (1, M),
+            };
+
+            search.ef = ef;
+            if cur.is_zero() {
+                search.apply_min_distance();
+                search.nearest.retain(|candidate| accept(candidate.pid));
+            }
+
+            match cur.0 {
+                0 => search.search_where(point, &self.zero, &self.points, num, &accept),
+                l => search.search(point, &self.layers[l - 1], &self.points, num),
+            }
+
+            if !cur.is_zero() {
+                search.cull();
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            ef = search.ef,
+            distances = search.distances,
+            results = search.nearest.len(),
+            "search"
+        );
+    }
+
+    /// Search for `point` like `search()`, yielding only the `PointId`s of the results
+    ///
+    /// This is convenient for callers that look up results by id elsewhere and have no use
+    /// for the distances.
+    pub fn search_ids<'a>(
+        &self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = PointId> + 'a {
+        self.search(point, search).map(|candidate| candidate.pid)
+    }
+
+    /// Search for `point` like `search()`, yielding only results close to the nearest one
+    ///
+    /// Yields the results whose distance is at most `factor` times the distance to the nearest
+    /// result, which adapts to the scale of distances around each query where an absolute
+    /// radius can't. The band is taken from the results of a regular search, so it contains at
+    /// most `ef_search` points (or `k`, if set through `Search::set_k()`): if every result falls
+    /// within the band, it may extend further and `ef_search` should be raised to see all of it.
+    /// If the nearest result has distance 0, only points at distance 0 are yielded. Panics if
+    /// `factor` is less than 1.
+    pub fn search_relative<'a>(
+        &self,
+        point: &P,
+        factor: f32,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Candidate> + 'a {
+        assert!(factor >= 1.0, "factor must be at least 1");
+        let len = {
+            let mut results = self.search(point, search);
+            match results.next() {
+                Some(nearest) => {
+                    let limit = nearest.distance() * factor;
+                    1 + results.take_while(|c| c.distance() <= limit).count()
+                }
+                None => 0,
+            }
+        };
+        search.iter().take(len)
+    }
+
+    /// Search for `point` like `search()`, recording every step the search takes
+    ///
+    /// The returned `SearchTrace` lists the nodes visited and expanded on each layer, in order,
+    /// and why the search stopped on each layer, along with the results and the highest layer
+    /// at which each of them was first reached. A result that was only found on the zero layer
+    /// was reached by exploring the zero layer, while results found higher up were already seen
+    /// while routing through the upper layers. This is a debugging tool for individual queries:
+    /// recording allocates for every step, so use `search()` unless you need the trace. On flat
+    /// indexes, no steps are recorded and all results are reported at layer 0.
+    pub fn search_traced(&self, point: &P, search: &mut Search) -> SearchTrace {
+        search.trace = Some(Vec::new());
+        let _ = self.search(point, search);
+        let steps = search.trace.take().unwrap();
+
+        let mut found_at = HashMap::new();
+        for step in &steps {
+            if let TraceStep::Visit { layer, pid, .. } = *step {
+                found_at.entry(pid).or_insert(layer);
+            }
+        }
+
+        let results = search
+            .iter()
+            .map(|candidate| {
+                let layer = found_at.get(&candidate.pid).copied().unwrap_or(0);
+                (candidate, layer)
+            })
+            .collect();
+        SearchTrace { results, steps }
+    }
+
+    /// Search for `point` like `search()`, exploring every point reachable in the graph
+    ///
+    /// This raises `ef_search` to the number of points for this search, so the zero layer is
+    /// walked until no unvisited neighbors are left. It visits O(N) points, which makes it
+    /// only suitable for small indexes or for debugging: if the graph is fully connected, the
+    /// results equal those of `exact_search()`, so any remaining difference points to
+    /// connectivity problems rather than to a too small `ef_search`.
+    pub fn search_exhaustive<'a>(
+        &self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Candidate> + 'a {
+        let ef_search = search.ef_search.replace(max(self.points.len(), 1));
+        let _ = self.search(point, search);
+        search.ef_search = ef_search;
+        search.iter()
+    }
+
+    /// Search for `point`, then reorder the nearest `fetch_k` results using `rerank`
+    ///
+    /// This implements two-stage retrieval: the index selects `fetch_k` candidates, which are
+    /// then scored by a (typically more expensive) `rerank` function. The results are sorted
+    /// by ascending score, so lower scores rank higher. If `fetch_k` is larger than `ef_search`,
+    /// `ef_search` is raised to `fetch_k` for this search.
+    ///
+    /// `fetch_k` should exceed the number of results you intend to use, since candidates that
+    /// the first stage ranks beyond `fetch_k` are never seen by `rerank`.
+    pub fn search_rerank(
+        &self,
+        point: &P,
+        search: &mut Search,
+        fetch_k: usize,
+        mut rerank: impl FnMut(PointId) -> f32,
+    ) -> Vec<(PointId, f32)> {
+        let (ef_search, k) = (search.ef_search, search.k.take());
+        search.ef_search = Some(max(ef_search.unwrap_or(self.ef_search), fetch_k));
+        let mut results = self
+            .search(point, search)
+            .take(fetch_k)
+            .map(|candidate| (candidate.pid, rerank(candidate.pid)))
+            .collect::<Vec<_>>();
+        search.ef_search = ef_search;
+        search.k = k;
+
+        results.sort_by_key(|&(_, score)| OrderedFloat(score));
+        results
+    }
+
+    /// Search for `point`, then score the results with `distance` instead of the index's metric
+    ///
+    /// This is meant for experiments comparing metrics on a single index: the graph is
+    /// traversed using `Point::distance()` as usual, after which the distance of each result is
+    /// replaced by `distance(pid)` and the results are reordered accordingly. `Search::set_k()`
+    /// and `Search::set_max_distance()` apply to the new distances, while the options that steer
+    /// the traversal (like `Search::set_min_distance()`) apply to the original ones. Since the
+    /// graph was built for a different metric, recall is usually lower than that of an index
+    /// built for `distance` itself.
+    pub fn search_rescored<'a>(
+        &self,
+        point: &P,
+        search: &'a mut Search,
+        mut distance: impl FnMut(PointId) -> f32,
+    ) -> impl ExactSizeIterator<Item = Candidate> + 'a {
+        let max_distance = search.max_distance.take();
+        self.search_where(point, search, |_| true);
+        for candidate in &mut search.nearest {
+            candidate.distance = OrderedFloat(distance(candidate.pid));
+        }
+
+        search.nearest.sort_unstable();
+        search.max_distance = max_distance;
+        search.apply_max_distance();
+        search.iter()
+    }
+
+    /// The distance from `point` to its `k`th nearest neighbor in the index
+    ///
+    /// This is the k-distance used for density estimation (for example, in the local outlier
+    /// factor). If `k` is larger than `ef_search`, `ef_search` is raised to `k` for this search.
+    /// Returns `None` if the search finds fewer than `k` points. Panics if `k` is zero.
+    pub fn k_distance(&self, point: &P, k: usize, search: &mut Search) -> Option<f32> {
+        assert!(k > 0, "k must be at least 1");
+        let (ef_search, limit) = (search.ef_search, search.k.take());
+        search.ef_search = Some(max(ef_search.unwrap_or(self.ef_search), k));
+        let distance = self.search(point, search).nth(k - 1);
+        search.ef_search = ef_search;
+        search.k = limit;
+        distance.map(|candidate| candidate.distance())
+    }
+
+    /// The k-distance of every point in the index, in `PointId` order
+    ///
+    /// Like `k_distance()`, but for the points in the index themselves: a point is not counted
+    /// as its own neighbor, though other points at distance zero are. Points for which fewer than
+    /// `k` neighbors are found get `f32::INFINITY`. Points are searched in parallel. Panics if
+    /// `k` is zero.
+    pub fn k_distances(&self, k: usize) -> Vec<f32> {
+        assert!(k > 0, "k must be at least 1");
+        let ef = max(self.ef_search, k + 1);
+        (0..self.points.len())
+            .into_par_iter()
+            .map_init(Search::default, |search, i| {
+                let pid = PointId(i as u32);
+                search.ef_search = Some(ef);
+                self.search(&self.points[i], search)
+                    .filter(|candidate| candidate.pid != pid)
+                    .nth(k - 1)
+                    .map_or(f32::INFINITY, |candidate| candidate.distance())
+            })
+            .collect()
+    }
+
+    /// Approximately count the points within `radius` of `point`, without collecting them
+    ///
+    /// This runs a regular search for `point` (using the `ef_search` of the index, or the one
+    /// set on `search`), and then walks the zero layer graph outward from every result within
+    /// the radius, following links only from points within the radius. Points are counted as
+    /// they are reached, so nothing beyond the search results is stored or sorted, and the cost
+    /// of the walk grows with the count. This is useful for density estimates, or to check how
+    /// many results a search with a large radius would return before running it. The count is
+    /// a lower bound, but never lower than the number of search results within the radius:
+    /// points that can only be reached through points outside the radius are missed, as are
+    /// all points if the search finds none within it. For flat indexes, every point is
+    /// compared, so the count is exact. `search` only provides the buffers, and holds no
+    /// results afterwards.
+    pub fn count_within_radius(&self, point: &P, radius: f32, search: &mut Search) -> usize {
+        if self.is_flat() {
+            let within = |other: &&P| point.distance(other) <= radius;
+            return self.points.iter().filter(within).count();
+        }
+
+        let min_distance = search.min_distance.take();
+        self.traverse(point, search, |_| true);
+        search.min_distance = min_distance;
+
+        // Use `working` as the stack of points within the radius that are yet to be expanded,
+        // starting from all results within it (`nearest` is sorted nearest first)
+        let within = search
+            .nearest
+            .iter()
+            .take_while(|candidate| candidate.distance.0 <= radius)
+            .count();
+        search.visited.clear();
+        search.working.clear();
+        for &candidate in &search.nearest[..within] {
+            search.visited.insert(candidate.pid);
+            search.working.push(candidate);
+        }
+        let mut count = 0;
+        while let Some(Candidate { pid, .. }) = search.working.pop() {
+            count += 1;
+            for neighbor in (&self.zero).nearest_iter(pid) {
+                if !search.visited.insert(neighbor) {
+                    continue;
+                }
+
+                let distance = point.distance(&self[neighbor]);
+                if distance <= radius {
+                    search.working.push(Candidate {
+                        distance: OrderedFloat(distance),
+                        pid: neighbor,
+                    });
+                }
+            }
+        }
+
+        search.reset();
+        count
+    }
+
+    /// Search for the `k` points nearest to `point`, widening the search until it converges
+    ///
+    /// Instead of using a fixed `ef_search`, the search starts out with `ef_search` set to `k`
+    /// and doubles it until at least a `target` fraction of the nearest `k` results stays the
+    /// same between two rounds, or the search covers the whole index. Results that no longer
+    /// change suggest that a wider search is unlikely to find closer points, so `target`
+    /// roughly corresponds to the recall aimed for. This is a best-effort heuristic: the
+    /// actual recall is not guaranteed to reach `target`. The results are sorted nearest first.
+    pub fn search_with_recall_target(
+        &self,
+        point: &P,
+        search: &mut Search,
+        k: usize,
+        target: f32,
+    ) -> Vec<Candidate> {
+        let (ef_search, limit) = (search.ef_search, search.k.take());
+        let mut ef = max(k, 1);
+        let mut previous = HashSet::new();
+        let results = loop {
+            search.ef_search = Some(ef);
+            let current = self.search(point, search).take(k).collect::<Vec<_>>();
+            let stable = current
+                .iter()
+                .filter(|candidate| previous.contains(&candidate.pid))
+                .count();
+
+            let converged = !previous.is_empty() && stable as f32 >= target * current.len() as f32;
+            if converged || ef >= self.points.len() {
+                break current;
+            }
+
+            previous = current.iter().map(|candidate| candidate.pid).collect();
+            ef *= 2;
+        };
+
+        search.ef_search = ef_search;
+        search.k = limit;
+        results
+    }
+
+    /// Compute the distance from `point` to each of the points in `targets`, in order
+    ///
+    /// This is useful for re-ranking a list of candidates, for example the results of a search
+    /// over a compressed representation. Panics if a `PointId` doesn't refer to a point in this
+    /// index; use `point_id()` to validate untrusted ids first.
+    pub fn distances_to(&self, point: &P, targets: &[PointId]) -> Vec<f32> {
+        targets
+            .iter()
+            .map(|&pid| point.distance(&self[pid]))
+            .collect()
+    }
+
+    /// Find the `k` points nearest to `point` by comparing it to every point in the index
+    ///
+    /// This is much slower than `search()`, but the results are exact, which makes it useful
+    /// as ground truth for measuring the recall of approximate searches. The results are
+    /// sorted nearest first.
+    pub fn exact_search(&self, point: &P, k: usize) -> Vec<Candidate> {
+        let mut nearest = BinaryHeap::with_capacity(k + 1);
+        for (i, other) in self.points.iter().enumerate() {
+            nearest.push(Candidate {
+                distance: OrderedFloat::from(point.distance(other)),
+                pid: PointId(i as u32),
+            });
+
+            if nearest.len() > k {
+                nearest.pop();
+            }
+        }
+
+        nearest.into_sorted_vec()
+    }
+
+    /// Measure the recall and throughput of searching for `queries`
+    ///
+    /// `ground_truth` holds the true nearest neighbors for each query, nearest first (for
+    /// example, as computed by `exact_search()`). Each query is searched for with the given
+    /// `ef` (`ef_search`) value, and its recall is the fraction of the first `k` true neighbors
+    /// found in the first `k` results. Queries are processed sequentially on the current thread.
+    ///
+    /// The report includes a 95% confidence interval for the mean recall, which tells whether
+    /// the difference between two evaluations on samples of queries is likely to be real.
+    pub fn evaluate(
+        &self,
+        queries: &[P],
+        ground_truth: &[Vec<PointId>],
+        k: usize,
+        ef: usize,
+    ) -> EvalReport {
+        assert_eq!(queries.len(), ground_truth.len());
+        let mut search = Search::default();
+        search.set_ef_search(Some(ef));
+
+        let start = Instant::now();
+        let (mut hits, mut total) = (0, 0);
+        let recalls = queries
+            .iter()
+            .zip(ground_truth)
+            .map(|(query, truth)| {
+                let truth = &truth[..min(k, truth.len())];
+                if truth.is_empty() {
+                    return 1.0;
+                }
+
+                let found = self
+                    .search(query, &mut search)
+                    .take(k)
+                    .filter(|candidate| truth.contains(&candidate.pid))
+                    .count();
+                hits += found;
+                total += truth.len();
+                found as f32 / truth.len() as f32
+            })
+            .collect::<Vec<_>>();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let mean_recall = recalls.iter().sum::<f32>() / max(recalls.len(), 1) as f32;
+        EvalReport {
+            mean_recall,
+            min_recall: recalls.iter().copied().fold(1.0, f32::min),
+            max_recall: recalls.iter().copied().fold(0.0, f32::max),
+            recall_ci: confidence_interval(hits, total),
+            qps: queries.len() as f64 / elapsed,
+        }
+    }
+
+    /// Find approximately the `k` points farthest away from `point`, farthest first
+    ///
+    /// The graph is built to find near points, so this is only a heuristic: it walks the zero
+    /// layer from several evenly spread starting points, moving to the farthest neighbors first,
+    /// and can get stuck in local maxima. For flat indexes (see `Builder::flat()`), all points
+    /// are compared, so the results are exact.
+    pub fn search_farthest(&self, point: &P, k: usize) -> Vec<Candidate> {
+        let distance = |pid: PointId| Candidate {
+            distance: OrderedFloat::from(point.distance(&self[pid])),
+            pid,
+        };
+
+        let ef = max(k, self.ef_search);
+        let mut farthest = BinaryHeap::<Reverse<Candidate>>::with_capacity(ef + 1);
+        if self.is_flat() {
+            for i in 0..self.points.len() {
+                farthest.push(Reverse(distance(PointId(i as u32))));
+                if farthest.len() > k {
+                    farthest.pop();
+                }
+            }
+        } else {
+            let mut visited = Visited::with_capacity(self.points.len());
+            let mut candidates = BinaryHeap::new();
+            let starts = min(FARTHEST_STARTS, self.points.len());
+            for i in 0..starts {
+                let pid = PointId((i * self.points.len() / starts) as u32);
+                visited.insert(pid);
+                candidates.push(distance(pid));
+                farthest.push(Reverse(distance(pid)));
+            }
+
+            while let Some(candidate) = candidates.pop() {
+                if let Some(Reverse(nearest)) = farthest.peek() {
+                    if farthest.len() >= ef && candidate.distance < nearest.distance {
+                        break;
+                    }
+                }
+
+                for pid in (&self.zero).nearest_iter(candidate.pid) {
+                    if !visited.insert(pid) {
+                        continue;
+                    }
+
+                    let new = distance(pid);
+                    if let Some(Reverse(nearest)) = farthest.peek() {
+                        if farthest.len() >= ef && new.distance <= nearest.distance {
+                            continue;
+                        }
+                    }
+
+                    candidates.push(new);
+                    farthest.push(Reverse(new));
+                    if farthest.len() > ef {
+                        farthest.pop();
+                    }
+                }
+            }
+        }
+
+        let mut farthest = farthest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(candidate)| candidate)
+            .collect::<Vec<_>>();
+        farthest.truncate(k);
+        farthest
+    }
+
+    /// Write the graph in Graphviz DOT format, for visualizing small indexes
+    ///
+    /// Only the first `max_nodes` points are included, along with the links between them.
+    /// Since points on higher layers get the lowest `PointId`s, this subgraph always contains
+    /// the upper layers first. Nodes are colored by their highest layer; links are labeled
+    /// with their distance and colored by the layer they belong to.
+    pub fn export_dot(&self, w: impl io::Write, max_nodes: usize) -> io::Result<()> {
+        self.write_dot(w, max_nodes, |a, b| Some(self[a].distance(&self[b])))
+    }
+
+    /// Convert the index into its most compact form for read-only use
+    ///
+    /// Packs all neighbor lists as if the index was built with `Builder::compress_graph()`, and
+    /// releases spare capacity. Search results are unaffected.
+    pub fn finalize(self) -> Self {
+        let Self {
+            version,
+            ef_search,
+            mut points,
+            original,
+            zero,
+            layers,
+            params,
+        } = self;
+
+        points.shrink_to_fit();
+        let num_points = points.len();
+        Self {
+            version,
+            ef_search,
+            points,
+            original,
+            zero: zero.compress(num_points),
+            layers: layers
+                .into_iter()
+                .map(|layer| layer.compress(num_points))
+                .collect(),
+            params,
+        }
+    }
+
+    /// Replace the point `pid` with `point`, keeping its `PointId`
+    ///
+    /// The new position is searched for on every layer the point is present on, starting from
+    /// the current graph. The point's old edges, in both directions, are then replaced by links
+    /// to its new neighbors, which also get a link back to the point. Finding the links other
+    /// points have to the point scans the whole layer, so this takes time linear in the size of
+    /// the index. Neighbors are selected using the `Heuristic` the index was built with (or the
+    /// default one, for indexes built without a heuristic), neighbor lists are limited to its
+    /// `max_connections`, and the point gets at least its `min_connections`. Points that only
+    /// linked to the old position lose that link, so they may end up with fewer. The point
+    /// stays on the same layers. Fails if the graph is compressed, since packed neighbor lists
+    /// can't be modified. Panics if `pid` is not in the index (see `contains()`).
+    pub fn update(
+        &mut self,
+        pid: PointId,
+        point: P,
+        search: &mut Search,
+    ) -> Result<(), Compressed> {
+        let graph = !self.is_flat();
+        if graph && matches!(self.zero, Nodes::Packed(_)) {
+            return Err(Compressed);
+        }
+
+        assert!(self.contains(pid), "point ID out of range");
+        self.points[pid.0 as usize] = point;
+        if graph {
+            self.link(pid, search);
+        }
+        Ok(())
+    }
+
+    /// Link the point `pid` to its nearest neighbors on every layer it is present on
+    fn link(&mut self, pid: PointId, search: &mut Search) {
+        let top = self.layer_of(pid);
+        let Self {
+            ef_search,
+            points,
+            zero,
+            layers,
+            params,
+            ..
+        } = self;
+
+        let point = &points[pid.0 as usize];
+        let (links, links_0, min_links) = match params {
+            Some(params) => (
+                params.max_connections,
+                params.max_connections_0,
+                params.min_connections,
+            ),
+            None => (M, M * 2, 0),
+        };
+        let heuristic = params
+            .and_then(|params| params.heuristic)
+            .unwrap_or_default();
+
+        // Find the new neighbors on each layer from the top layer the point is present on.
+        search.reset();
+        search.visited.reserve_capacity(points.len());
+        search.push(PointId(0), point, points);
+        let mut found = vec![Vec::new(); top + 1];
+        for cur in LayerId(layers.len()).descend() {
+            let num = if cur.is_zero() { M * 2 } else { M };
+            search.ef = if cur.0 <= top { *ef_search } else { 1 };
+            match cur.0 {
+                0 => search.search(point, &*zero, points, num),
+                l => search.search(point, &layers[l - 1], points, num),
+            }
+
+            if cur.0 <= top {
+                found[cur.0] = search
+                    .nearest
+                    .iter()
+                    .filter(|candidate| candidate.pid != pid)
+                    .take(num)
+                    .copied()
+                    .collect::<Vec<_>>();
+            }
+
+            if !cur.is_zero() {
+                search.cull();
+            }
+        }
+
+        // Keep the minimum number of links the index was built with, like `SearchPool` does
+        let min_links = std::mem::replace(&mut search.min_links, min_links);
+        for (layer, found) in found.iter().enumerate() {
+            match layer {
+                0 => relink(zero, pid, found, points, search, heuristic, links_0),
+                l => relink(
+                    &mut layers[l - 1],
+                    pid,
+                    found,
+                    points,
+                    search,
+                    heuristic,
+                    links,
+                ),
+            }
+        }
+        search.min_links = min_links;
+    }
+
+    /// Add `point` to the index, returning the `PointId` it was assigned
+    ///
+    /// The point gets the next `PointId` after the existing points, and its `original_index()`
+    /// is that same number, so it refers to a position past the end of the original input.
+    /// `PointId`s are dense: layer membership is determined by `PointId` ranges, fixed when the
+    /// index is built, so appended points only join the zero layer. Their neighbors are found
+    /// and linked like for `update()`. Since the upper layers don't grow along, search speed
+    /// and recall degrade as the share of appended points grows; rebuild the index (see
+    /// `rebuild_with()`) once it has grown significantly. Fails if the graph is compressed.
+    ///
+    /// Flat indexes stay flat, so the point is only added to the list that searches scan.
+    /// `Attributes` created before the point was appended have no values for it, so it never
+    /// matches a filtered search until the attributes are created again, including the point.
+    pub fn append(&mut self, point: P, search: &mut Search) -> Result<PointId, Compressed> {
+        let pid = PointId(self.points.len() as u32);
+        let flat = self.is_flat();
+        let nodes = match &mut self.zero {
+            _ if flat => None,
+            Nodes::Full(nodes) => Some(nodes),
+            Nodes::Packed(_) => return Err(Compressed),
+        };
+
+        self.points.push(point);
+        self.original.push(pid.0);
+        if let Some(nodes) = nodes {
+            nodes.push(ZeroNode::default());
+            self.link(pid, search);
+        }
+        Ok(pid)
+    }
+
+    /// Build a new index over the points in this one, using the parameters from `builder`
+    ///
+    /// This makes it easy to compare parameters on the same data without keeping the input
+    /// around. The new graph assigns `PointId`s in its own construction order, so the returned
+    /// `Vec<PointId>` maps the ids from this index to the new ones: point `pid` here is point
+    /// `ids[pid.into_inner()]` in the new index. `original_index()` is carried over, so it still
+    /// refers to the input this index was built from.
+    pub fn rebuild_with(&self, builder: Builder) -> (Self, Vec<PointId>) {
+        let (mut hnsw, ids) = builder.build(&self.points);
+        for (&original, new) in self.original.iter().zip(&ids) {
+            hnsw.original[new.0 as usize] = original;
+        }
+        (hnsw, ids)
+    }
+}
+
+impl<P> Hnsw<P> {
+    /// Replace every point in the index, keeping the graph as it is
+    ///
+    /// This makes it possible to keep only the graph in memory: after building, the points can
+    /// be replaced by small handles whose `Point::distance()` fetches the vectors from an external
+    /// store (caching them as it sees fit). Searches call `distance()` with the query as `self`
+    /// and stored points as `other`, so the handle type has to be able to represent queries as
+    /// well. The new points must yield the same distances as the old ones; otherwise the graph no
+    /// longer matches the data and search quality suffers. This also attaches points to a graph
+    /// read by `Hnsw::load_graph_only()`, making it searchable again.
+    pub fn map_points<Q: Point>(self, mut map: impl FnMut(PointId, P) -> Q) -> Hnsw<Q> {
+        let Self {
+            version,
+            ef_search,
+            points,
+            original,
+            zero,
+            layers,
+            params,
+        } = self;
+
+        Hnsw {
+            version,
+            ef_search,
+            points: points
+                .into_iter()
+                .enumerate()
+                .map(|(i, p)| map(PointId(i as u32), p))
+                .collect(),
+            original,
+            zero,
+            layers,
+            params,
+        }
+    }
+
+    /// The parameters the index was built with
+    ///
+    /// Returns `None` for indexes that were deserialized or read by `load_graph_only()`, since
+    /// the parameters are not part of the serialized index. Store them separately
+    /// (`BuildParams` is serializable) and restore them with `set_build_params()` to keep them
+    /// across a dump.
+    pub fn build_params(&self) -> Option<&BuildParams> {
+        self.params.as_ref()
+    }
+
+    /// Restore the parameters the index was built with, as returned by `build_params()`
+    ///
+    /// Updates and appended points are linked using these parameters, and `is_flat()` is taken
+    /// from them, so they must be the ones this index was actually built with.
+    pub fn set_build_params(&mut self, params: BuildParams) {
+        self.params = Some(params);
+    }
+
+    /// Whether the index was built without a graph, using `Builder::flat()`
+    ///
+    /// This is taken from the `build_params()` when available. Otherwise, it is inferred from
+    /// the graph, which can't tell an empty flat index from an empty graph index; appending
+    /// to an empty index without parameters builds a graph.
+    pub fn is_flat(&self) -> bool {
+        match &self.params {
+            Some(params) => params.flat,
+            None => self.zero.len() != self.points.len(),
+        }
+    }
+
+    /// Whether `pid` refers to a point in this index
+    pub fn contains(&self, pid: PointId) -> bool {
+        (pid.0 as usize) < self.points.len()
+    }
+
+    /// The `PointId` for the raw id `raw`, if it refers to a point in this index
+    ///
+    /// Use this to validate ids from untrusted sources (like `PointId::into_inner()` values
+    /// sent back by a client) before passing them to methods that index into the points.
+    pub fn point_id(&self, raw: u32) -> Option<PointId> {
+        let pid = PointId(raw);
+        match self.contains(pid) {
+            true => Some(pid),
+            false => None,
+        }
+    }
+
+    /// The highest layer the point `pid` was assigned to during construction
+    ///
+    /// Every point is present on the zero layer; a point on layer `n` is also present on all
+    /// layers below `n`.
+    pub fn layer_of(&self, pid: PointId) -> usize {
+        self.layers
+            .iter()
+            .take_while(|layer| (pid.0 as usize) < layer.len())
+            .count()
+    }
+
+    /// The points present on `layer`, ordered by `PointId`
+    ///
+    /// Layer 0 holds all points. Higher layers hold increasingly sparse, roughly uniform samples
+    /// of the data, so the points on an upper layer make a cheap set of representatives for
+    /// coarse clustering or routing. The entry point is on the top layer, so the top layer is
+    /// `layer_of()` the first point. Returns an empty list for layers above the top layer.
+    pub fn layer_points(&self, layer: usize) -> Vec<PointId> {
+        let len = match layer {
+            0 => self.points.len(),
+            _ => self.layers.get(layer - 1).map_or(0, |nodes| nodes.len()),
+        };
+        (0..len).map(|i| PointId(i as u32)).collect()
+    }
+
+    /// A sample of up to `n` points, for visualizing the data without reading all of it
+    ///
+    /// `sampling` selects whether upper layers are preferred or all points are equally likely,
+    /// and `seed` seeds the random choices, so the same seed yields the same sample. Returns
+    /// all points if there are at most `n`. The sample is ordered by `PointId`.
+    pub fn sample(&self, n: usize, sampling: Sampling, seed: u64) -> Vec<(PointId, &P)> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut pids = match sampling {
+            Sampling::Layers => {
+                // Upper layers hold the lowest `PointId`s, so each layer extends the one above
+                let mut pids = Vec::with_capacity(min(n, self.points.len()));
+                let sizes = self.layers.iter().rev().map(|layer| layer.len());
+                for len in sizes.chain(iter::once(self.points.len())) {
+                    let start = pids.len();
+                    if len - start <= n - start {
+                        pids.extend((start..len).map(|i| PointId(i as u32)));
+                        continue;
+                    }
+
+                    let sample = index::sample(&mut rng, len - start, n - start);
+                    pids.extend(sample.into_iter().map(|i| PointId((start + i) as u32)));
+                    break;
+                }
+                pids
+            }
+            Sampling::Uniform => {
+                let len = self.points.len();
+                let sample = index::sample(&mut rng, len, min(n, len));
+                sample.into_iter().map(|i| PointId(i as u32)).collect()
+            }
+        };
+
+        pids.sort_unstable();
+        pids.into_iter()
+            .map(|pid| (pid, &self.points[pid.0 as usize]))
+            .collect()
+    }
+
+    /// The index of the point `pid` in the slice of points the index was built from
+    ///
+    /// This is the inverse of the `Vec<PointId>` returned by `Builder::build()`:
+    /// `hnsw.original_index(ids[i]) == i`. For indexes built with `Builder::build_dedup()`,
+    /// this is the index of the first occurrence of the point. Indexes read by
+    /// `Hnsw::deserialize_legacy()` don't know the input order, and return `pid` itself.
+    pub fn original_index(&self, pid: PointId) -> usize {
+        self.original[pid.0 as usize] as usize
+    }
+
+    /// The mean of the points with the given ids, as computed by `Centroid::centroid()`
+    ///
+    /// This is useful for relevance feedback: searching for the centroid of results a user
+    /// selected finds more points like them. Panics if `pids` is empty.
+    pub fn centroid(&self, pids: &[PointId]) -> P
+    where
+        P: Centroid,
+    {
+        assert!(!pids.is_empty(), "centroid of an empty set of points");
+        let points = pids.iter().map(|&pid| &self[pid]).collect::<Vec<_>>();
+        P::centroid(&points)
+    }
+
+    /// The points with the given ids, in the order of `pids`
+    ///
+    /// This is useful for fetching the points of search results in one call, for example to
+    /// re-rank them. Panics if a `PointId` doesn't refer to a point in this index; use
+    /// `point_id()` to validate untrusted ids first.
+    pub fn get_points(&self, pids: &[PointId]) -> Vec<&P> {
+        pids.iter().map(|&pid| &self[pid]).collect()
+    }
+
+    /// Iterate over the keys and values in this index
+    pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
+        self.points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (PointId(i as u32), p))
+    }
+
+    /// Check the graph structure for corruption, without searching
+    ///
+    /// Indexes built by this crate always pass; this is meant for indexes read from storage,
+    /// where a truncated or damaged file can yield neighbor lists that refer to points that
+    /// don't exist, which make searches panic. Returns every problem found. An index that fails
+    /// can still be searched on a best-effort basis using `Search::set_skip_invalid_links()`.
+    pub fn verify_integrity(&self) -> Result<(), Vec<Corruption>> {
+        let mut found = Vec::new();
+        if self.original.len() != self.points.len() {
+            found.push(Corruption::OriginalSize {
+                len: self.original.len(),
+            });
+        }
+
+        let len = self.points.len();
+        if self.zero.len() != 0 && self.zero.len() != len {
+            found.push(Corruption::LayerSize {
+                layer: 0,
+                len: self.zero.len(),
+                expected: len,
+            });
+        } else if !self.is_flat() {
+            verify_layer(&self.zero, 0, len, len, &mut found);
+        }
+
+        let mut below = match self.is_flat() {
+            true => 0,
+            false => len,
+        };
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.len() > below {
+                found.push(Corruption::LayerSize {
+                    layer: i + 1,
+                    len: layer.len(),
+                    expected: below,
+                });
+            }
+
+            below = min(layer.len(), below);
+            verify_layer(layer, i + 1, layer.len(), below, &mut found);
+        }
+
+        match found.is_empty() {
+            true => Ok(()),
+            false => Err(found),
+        }
+    }
+
+    /// Partition the points into connected components of the zero layer graph
+    ///
+    /// Links are treated as undirected, so two points are in the same component if there is a
+    /// path of links between them in either direction. A well-connected graph has a single
+    /// component; points in smaller components can be hard or impossible to reach, which caps
+    /// the recall of searches for them. Components are sorted by descending size, and the
+    /// points within each component by `PointId`. For flat indexes, all points form a single
+    /// component, since every search compares the query to all of them.
+    pub fn connected_components(&self) -> Vec<Vec<PointId>> {
+        if self.is_flat() {
+            return match self.points.is_empty() {
+                true => Vec::new(),
+                false => vec![(0..self.points.len() as u32).map(PointId).collect()],
+            };
+        }
+
+        // Union-find over all links, with path halving
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+
+        let mut parent = (0..self.points.len()).collect::<Vec<_>>();
+        for i in 0..self.points.len() {
+            for neighbor in (&self.zero).nearest_iter(PointId(i as u32)) {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, neighbor.0 as usize));
+                parent[max(a, b)] = min(a, b);
+            }
+        }
+
+        let mut components = HashMap::<usize, Vec<PointId>>::new();
+        for i in 0..self.points.len() {
+            let root = root(&mut parent, i);
+            components.entry(root).or_default().push(PointId(i as u32));
+        }
+
+        let mut components = components.into_values().collect::<Vec<_>>();
+        components.sort_unstable_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+        components
+    }
+
+    /// The points that can't be reached from the entry point by following zero layer links
+    ///
+    /// Searches enter the zero layer close to the query, but can only move along links, so
+    /// these points are never returned by any search. Unlike `connected_components()`, this
+    /// follows links in their direction only. For flat indexes, this is always empty.
+    pub fn unreachable(&self) -> Vec<PointId> {
+        if self.is_flat() || self.points.is_empty() {
+            return Vec::new();
+        }
+
+        let mut reached = vec![false; self.points.len()];
+        reached[0] = true;
+        let mut stack = vec![PointId(0)];
+        while let Some(pid) = stack.pop() {
+            for neighbor in (&self.zero).nearest_iter(pid) {
+                if !reached[neighbor.0 as usize] {
+                    reached[neighbor.0 as usize] = true;
+                    stack.push(neighbor);
+                }
+            }
+        }
+
+        (0..self.points.len())
+            .filter(|&i| !reached[i])
+            .map(|i| PointId(i as u32))
+            .collect()
+    }
+
+    /// Copy all points into a single slice of rows, in `PointId` order
+    ///
+    /// This is the counterpart of `Builder::build_flat()`, for handing the data to other tools
+    /// (like faiss) that expect a contiguous row-major array. Row `i` holds the point with
+    /// `PointId` `i`; use `original_index()` to map rows back to the input order.
+    pub fn export_vectors(&self) -> Vec<f32>
+    where
+        P: AsRef<[f32]>,
+    {
+        self.points
+            .iter()
+            .flat_map(|point| point.as_ref().iter().copied())
+            .collect()
+    }
+
+    /// The neighbors of every point on the zero layer, in `PointId` order
+    ///
+    /// Neighbor lists are ordered nearest first. For flat indexes, all lists are empty.
+    pub fn export_adjacency(&self) -> Vec<Vec<PointId>> {
+        if self.is_flat() {
+            return vec![Vec::new(); self.points.len()];
+        }
+
+        (0..self.points.len())
+            .map(|i| (&self.zero).nearest_iter(PointId(i as u32)).collect())
+            .collect()
+    }
+
+    /// Write the graph structure of the index to `w`, without the points
+    ///
+    /// This writes the neighbor lists of every layer (and thereby each point's layer) and the
+    /// mapping to input indexes in a compact binary format, which is much smaller than the full
+    /// index for high-dimensional points. Use `Hnsw::load_graph_only()` to read it back, for
+    /// analyzing the topology of the graph.
+    pub fn dump_graph_only(&self, mut w: impl io::Write) -> io::Result<()> {
+        w.write_all(GRAPH_MAGIC)?;
+        write_u32(&mut w, self.ef_search as u32)?;
+        write_u32(&mut w, self.points.len() as u32)?;
+        for &original in &self.original {
+            write_u32(&mut w, original)?;
+        }
+
+        write_nodes(&mut w, &self.zero)?;
+        write_u32(&mut w, self.layers.len() as u32)?;
+        for layer in &self.layers {
+            write_nodes(&mut w, layer)?;
+        }
+        Ok(())
+    }
+
+    /// Write the graph in Graphviz DOT format, labeling links with `distance` if it returns one
+    fn write_dot(
+        &self,
+        mut w: impl io::Write,
+        max_nodes: usize,
+        distance: impl Fn(PointId, PointId) -> Option<f32>,
+    ) -> io::Result<()> {
+        let len = min(max_nodes, self.points.len());
+        writeln!(w, "digraph hnsw {{")?;
+        writeln!(w, "    node [style=filled];")?;
+        for i in 0..len {
+            let layer = self.layer_of(PointId(i as u32));
+            let color = DOT_COLORS[layer % DOT_COLORS.len()];
+            writeln!(
+                w,
+                "    {} [label=\"{}\\nlayer {}\", fillcolor=\"{}\"];",
+                i, i, layer, color
+            )?;
+        }
+
+        if !self.is_flat() {
+            self.dot_edges(&mut w, &self.zero, 0, len, &distance)?;
+            for (i, layer) in self.layers.iter().enumerate() {
+                self.dot_edges(&mut w, layer, i + 1, min(len, layer.len()), &distance)?;
+            }
+        }
+
+        writeln!(w, "}}")
+    }
+
+    fn dot_edges(
+        &self,
+        w: &mut impl io::Write,
+        nodes: impl Layer,
+        layer: usize,
+        len: usize,
+        distance: impl Fn(PointId, PointId) -> Option<f32>,
+    ) -> io::Result<()> {
+        let color = DOT_COLORS[layer % DOT_COLORS.len()];
+        for i in 0..len {
+            let pid = PointId(i as u32);
+            for neighbor in nodes.nearest_iter(pid) {
+                if neighbor.0 as usize >= len {
+                    continue;
+                }
+
+                match distance(pid, neighbor) {
+                    Some(distance) => writeln!(
+                        w,
+                        "    {} -> {} [label=\"{:.3}\", color=\"{}\"];",
+                        i, neighbor.0, distance, color
+                    )?,
+                    None => writeln!(w, "    {} -> {} [color=\"{}\"];", i, neighbor.0, color)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, P: Deserialize<'de>> Hnsw<P> {
+    /// Deserialize an index written by a release without a versioned format
+    ///
+    /// These dumps didn't record the order of the input points, so `original_index()` is
+    /// taken to be the identity: `hnsw.original_index(pid) == pid.into_inner() as usize`.
+    /// Layers are read as they were stored, without packing, and `build_params()` is `None`.
+    pub fn deserialize_legacy<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Legacy {
+            ef_search,
+            points,
+            zero,
+            layers,
+        } = Legacy::deserialize(deserializer)?;
+
+        Ok(Self {
+            version: FormatVersion,
+            ef_search,
+            original: (0..points.len() as u32).collect(),
+            points,
+            zero: Nodes::Full(zero),
+            layers: layers.into_iter().map(Nodes::Full).collect(),
+            params: None,
+        })
+    }
+}
+
+/// The layout of `Hnsw` before it was versioned, read by `Hnsw::deserialize_legacy()`
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct Legacy<P> {
+    ef_search: usize,
+    points: Vec<P>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+}
+
+impl Hnsw<()> {
+    /// Read a graph written by `Hnsw::dump_graph_only()`
+    ///
+    /// The result has the same graph as the original index, but no points: each point is
+    /// replaced by `()`. It supports methods that only look at the graph, like `layer_of()`,
+    /// `connected_components()`, `export_adjacency()` and `export_dot()`, but it can't be
+    /// searched, since that requires the points to compute distances. Use `map_points()` to
+    /// attach the points, if they are stored elsewhere.
+    pub fn load_graph_only(mut r: impl io::Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != GRAPH_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a graph dump",
+            ));
+        }
+
+        let ef_search = read_u32(&mut r)? as usize;
+        let len = read_u32(&mut r)? as usize;
+        let original = (0..len)
+            .map(|_| read_u32(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        let zero = read_nodes::<ZeroNode>(&mut r, len)?;
+        let layers = (0..read_u32(&mut r)?)
+            .map(|_| read_nodes::<UpperNode>(&mut r, len))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            version: FormatVersion,
+            ef_search,
+            points: vec![(); len],
+            original,
+            zero: Nodes::Full(zero),
+            layers: layers.into_iter().map(Nodes::Full).collect(),
+            params: None,
+        })
+    }
+
+    /// Write the graph in Graphviz DOT format, for visualizing small graphs
+    ///
+    /// Works like `Hnsw::export_dot()`, but links are not labeled, since distances can't be
+    /// computed without the points.
+    pub fn export_dot(&self, w: impl io::Write, max_nodes: usize) -> io::Result<()> {
+        self.write_dot(w, max_nodes, |_, _| None)
+    }
+}
+
+/// Check the neighbor lists of the first `len` nodes on `nodes`, which holds the first `valid`
+/// points, adding any problems to `found`
+fn verify_layer(
+    nodes: impl Layer,
+    layer: usize,
+    len: usize,
+    valid: usize,
+    found: &mut Vec<Corruption>,
+) {
+    for pid in (0..len).map(|i| PointId(i as u32)) {
+        if !nodes.has_node(pid) {
+            found.push(Corruption::MissingNode { layer, pid });
+            continue;
+        }
+
+        for neighbor in nodes.nearest_iter(pid) {
+            if neighbor.0 as usize >= valid {
+                found.push(Corruption::InvalidLink {
+                    layer,
+                    pid,
+                    neighbor,
+                });
+            }
+        }
+    }
+}
+
+/// Magic bytes at the start of a graph written by `Hnsw::dump_graph_only()`
+const GRAPH_MAGIC: &[u8; 8] = b"IDGRAPH1";
+
+/// Write the number of nodes, followed by the length and contents of each neighbor list
+fn write_nodes<T>(w: &mut impl io::Write, nodes: &Nodes<T>) -> io::Result<()>
+where
+    T: Deref<Target = [PointId]>,
+{
+    write_u32(w, nodes.len() as u32)?;
+    for i in 0..nodes.len() {
+        let neighbors = nodes.nearest_iter(PointId(i as u32)).collect::<Vec<_>>();
+        write_u32(w, neighbors.len() as u32)?;
+        for pid in neighbors {
+            write_u32(w, pid.0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read nodes written by `write_nodes()`, checking that they refer to `num_points` points
+fn read_nodes<T>(r: &mut impl io::Read, num_points: usize) -> io::Result<Vec<T>>
+where
+    T: Default + DerefMut<Target = [PointId]>,
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let len = read_u32(r)? as usize;
+    if len > num_points {
+        return Err(invalid("more nodes than points"));
+    }
+
+    let mut nodes = Vec::with_capacity(len);
+    for _ in 0..len {
+        let mut node = T::default();
+        let links = read_u32(r)? as usize;
+        if links > node.len() {
+            return Err(invalid("too many neighbors"));
+        }
+
+        for slot in &mut node[..links] {
+            *slot = PointId(read_u32(r)?);
+            if slot.0 as usize >= num_points {
+                return Err(invalid("neighbor out of range"));
+            }
+        }
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+fn write_u32(w: &mut impl io::Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(r: &mut impl io::Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Replace the neighbors of `pid` in `nodes` with a selection of at most `links` from `found`
+///
+/// Removes `pid` from the neighbor lists of all other nodes, and adds it to the lists of its
+/// new neighbors. Neighbors are always selected with a `Heuristic` (`params`), so that points
+/// far away from the rest of the graph still get linked, keeping at least `search.min_links`
+/// of them. The caller must make sure that `nodes` is not compressed.
+fn relink<T, P>(
+    nodes: &mut Nodes<T>,
+    pid: PointId,
+    found: &[Candidate],
+    points: &[P],
+    search: &mut Search,
+    params: Heuristic,
+    links: usize,
+) where
+    T: DerefMut<Target = [PointId]>,
+    P: Point,
+{
+    let point = &points[pid.0 as usize];
+    search.reset();
+    search.ef = links * 2 + 1;
+    for candidate in found {
+        search.push(candidate.pid, point, points);
+    }
+    let selected = search
+        .select_heuristic(point, &*nodes, points, params, links)
+        .iter()
+        .map(|candidate| candidate.pid)
+        .collect::<Vec<_>>();
+
+    let mut back = Vec::with_capacity(selected.len());
+    for &neighbor in &selected {
+        let current = (&*nodes)
+            .nearest_iter(neighbor)
+            .filter(|&other| other != pid)
+            .collect::<Vec<_>>();
+        let found = search.add_neighbor_heuristic(
+            pid,
+            current.into_iter(),
+            &*nodes,
+            &points[neighbor.0 as usize],
+            points,
+            params,
+            links,
+        );
+        back.push((neighbor, found.iter().map(|c| c.pid).collect::<Vec<_>>()));
+    }
+    search.reset();
+
+    let nodes = match nodes {
+        Nodes::Full(nodes) => nodes,
+        Nodes::Packed(_) => unreachable!(),
+    };
+
+    // Links to the old position may come from any node, not just from the old neighbors
+    for list in nodes.iter_mut() {
+        if let Some(idx) = list.iter().position(|&other| other == pid) {
+            list[idx..].rotate_left(1);
+            *list.last_mut().unwrap() = INVALID;
+        }
+    }
+
+    fill(&mut nodes[pid.0 as usize], &selected);
+    for (neighbor, list) in back {
+        fill(&mut nodes[neighbor.0 as usize], &list);
+    }
+}
+
+/// Overwrite the neighbor list `slots` with `pids`, marking the remaining slots as unused
+fn fill(slots: &mut [PointId], pids: &[PointId]) {
+    for (i, slot) in slots.iter_mut().enumerate() {
+        *slot = pids.get(i).copied().unwrap_or(INVALID);
+    }
+}
+
+/// Colors for the layers in `Hnsw::export_dot()`, starting from the zero layer
+const DOT_COLORS: &[&str] = &["lightgray", "lightblue", "gold", "orange", "red", "purple"];
+
+/// Number of starting points for `Hnsw::search_farthest()`
+const FARTHEST_STARTS: usize = 8;
+
+/// Recall and throughput measured by `Hnsw::evaluate()`
+#[derive(Clone, Copy, Debug)]
+pub struct EvalReport {
+    /// Mean recall over all queries
+    pub mean_recall: f32,
+    /// Lowest recall for any query
+    pub min_recall: f32,
+    /// Highest recall for any query
+    pub max_recall: f32,
+    /// Lower and upper bound of a 95% confidence interval for the mean recall
+    ///
+    /// This is the Wilson score interval for the fraction of true neighbors found, over all
+    /// queries together. Unlike the normal approximation, it stays within 0 to 1 and doesn't
+    /// collapse to a single value when every query has the same recall (for example, 1.0).
+    /// The interval narrows with the square root of the number of true neighbors, so
+    /// quadrupling the sample halves its width. It treats every true neighbor as an independent
+    /// trial; since the neighbors of a single query are correlated, the actual uncertainty is
+    /// somewhat larger. If the queries have ground truth lists of different lengths, the
+    /// interval is centered on the pooled fraction rather than on `mean_recall`. Without any
+    /// ground truth, it spans the whole range.
+    pub recall_ci: (f32, f32),
+    /// Number of queries searched per second
+    pub qps: f64,
+}
+
+/// The 95% Wilson score interval for the fraction of `hits` out of `total` trials
+fn confidence_interval(hits: usize, total: usize) -> (f32, f32) {
+    if total == 0 {
+        return (0.0, 1.0);
+    }
+
+    const Z: f64 = 1.96;
+    let (n, p) = (total as f64, hits as f64 / total as f64);
+    let scale = 1.0 + Z * Z / n;
+    let center = (p + Z * Z / (2.0 * n)) / scale;
+    let margin = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt() / scale;
+    let low = if hits == 0 { 0.0 } else { center - margin };
+    let high = if hits == total { 1.0 } else { center + margin };
+    (low.max(0.0) as f32, high.min(1.0) as f32)
+}
+
+/// A non-fatal problem found while building an `Hnsw`, as passed to `Builder::on_warning()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// `ef_construction` is lower than `ef_search`, so the graph was built with less effort
+    /// than searches will spend, which usually limits recall
+    LowEfConstruction {
+        ef_construction: usize,
+        ef_search: usize,
+    },
+    /// `count` points have a neighbor at distance zero
+    ///
+    /// Duplicates take up neighbor slots without adding information; consider building with
+    /// `Builder::build_dedup()` instead.
+    DuplicatePoints { count: usize },
+    /// The zero layer graph consists of `components` separate components, the largest of which
+    /// holds `largest` points
+    ///
+    /// Points outside the component containing the entry point can't be found by searches.
+    Disconnected { components: usize, largest: usize },
+}
+
+/// The error returned by `Builder::try_build()` for graphs that are not fully connected
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disconnected {
+    /// The points that can't be reached from the entry point
+    pub unreachable: Vec<PointId>,
+}
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} points can't be reached from the entry point",
+            self.unreachable.len()
+        )
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// The error returned by `Hnsw::update()` and `Hnsw::append()` for compressed graphs
+///
+/// Neighbor lists packed by `Builder::compress_graph()` or `Hnsw::finalize()` can't be
+/// modified; rebuild the index without compression to change it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compressed;
+
+impl fmt::Display for Compressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cannot modify a compressed graph")
+    }
+}
+
+impl std::error::Error for Compressed {}
+
+/// A problem with the structure of an index, as reported by `Hnsw::verify_integrity()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Corruption {
+    /// The index holds `len` input indexes instead of one for every point
+    OriginalSize { len: usize },
+    /// Layer `layer` holds `len` neighbor lists, while it should hold `expected`
+    ///
+    /// The zero layer must hold one for every point (or none, for flat indexes); upper layers
+    /// may hold at most as many as the layer below them.
+    LayerSize {
+        layer: usize,
+        len: usize,
+        expected: usize,
+    },
+    /// The neighbor list of `pid` on `layer` can't be read
+    MissingNode { layer: usize, pid: PointId },
+    /// The neighbor list of `pid` on `layer` holds `neighbor`, which isn't on that layer
+    InvalidLink {
+        layer: usize,
+        pid: PointId,
+        neighbor: PointId,
+    },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::OriginalSize { len } => write!(f, "{} input indexes stored", len),
+            Corruption::LayerSize {
+                layer,
+                len,
+                expected,
+            } => write!(
+                f,
+                "layer {} holds {} neighbor lists, expected {}",
+                layer, len, expected
+            ),
+            Corruption::MissingNode { layer, pid } => {
+                write!(f, "layer {} has no neighbor list for {}", layer, pid.0)
+            }
+            Corruption::InvalidLink {
+                layer,
+                pid,
+                neighbor,
+            } => write!(
+                f,
+                "layer {} links {} to {}, which is not on that layer",
+                layer, pid.0, neighbor.0
+            ),
+        }
+    }
+}
+
+/// A record of a single search, as returned by `Hnsw::search_traced()`
+#[derive(Clone, Debug)]
+pub struct SearchTrace {
+    /// The results, nearest first, each with the highest layer at which it was first reached
+    pub results: Vec<(Candidate, usize)>,
+    /// The steps taken by the search, in order
+    pub steps: Vec<TraceStep>,
+}
+
+/// A single step in a `SearchTrace`
+///
+/// Layers are searched from the top down, so the steps for each layer follow those for the
+/// layer above it. Upper layers are searched for a single nearest node, which becomes the
+/// starting point on the layer below.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceStep {
+    /// The distance from the query to `pid` was computed on `layer`
+    ///
+    /// Once the search holds `ef_search` results, the computation may stop early for points
+    /// that are too far away to become results, in which case `distance` only tells that the
+    /// point is farther away than all results.
+    Visit {
+        layer: usize,
+        pid: PointId,
+        distance: f32,
+    },
+    /// The neighbors of `pid`, the nearest candidate not yet expanded, are visited next
+    Expand {
+        layer: usize,
+        pid: PointId,
+        distance: f32,
+    },
+    /// The search of `layer` finished
+    ///
+    /// `next` is the distance to the nearest candidate that was not expanded, because it is
+    /// farther away than all results; `None` means every candidate was expanded.
+    Stop { layer: usize, next: Option<f32> },
+}
+
+/// A snapshot of the progress of an `Hnsw` build, as passed to `Builder::stats_callback()`
+#[derive(Clone, Debug)]
+pub struct BuildStats {
+    /// Number of points inserted so far
+    pub inserted: usize,
+    /// Total number of points in the index being built
+    pub total: usize,
+    /// Number of points inserted so far on each layer, starting with the zero layer
+    ///
+    /// The length is the number of layers the finished index will have; layers that are
+    /// still empty have not been started yet.
+    pub layers: Vec<usize>,
+}
 
-        #[cfg(feature = "indicatif")]
-        let progress = builder.progress;
-        #[cfg(feature = "indicatif")]
-        if let Some(bar) = &progress {
-            bar.set_draw_delta(1_000);
-            bar.set_length(points.len() as u64);
-            bar.set_message("Build index (preparation)");
+impl BuildStats {
+    fn new(inserted: usize, sizes: &[(usize, usize)]) -> Self {
+        Self {
+            inserted,
+            total: sizes.last().map(|&(_, total)| total).unwrap_or(0),
+            layers: sizes
+                .iter()
+                .rev()
+                .map(|&(_, cumulative)| min(inserted, cumulative))
+                .collect(),
         }
+    }
+}
 
-        if points.is_empty() {
-            return (
-                Self {
-                    ef_search,
-                    zero: Vec::new(),
-                    points: Vec::new(),
-                    layers: Vec::new(),
-                },
-                Vec::new(),
-            );
-        }
+/// The state of a partially built `Hnsw`
+///
+/// `Builder::build_checkpointed()` passes a `Checkpoint` to its callback at regular intervals.
+/// With the `serde` feature enabled it can be written to disk, so that a build that was
+/// interrupted can be continued with `Builder::resume()` instead of starting over.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Checkpoint<P> {
+    points: Vec<P>,
+    out: Vec<PointId>,
+    sizes: Vec<(usize, usize)>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+    done: usize,
+}
+
+impl<P: Point> Checkpoint<P> {
+    /// Assign layers to the points and insert the enter point
+    fn new(points: &[P], builder: &Builder) -> Self {
+        let mut rng = SmallRng::seed_from_u64(builder.layer_seed.unwrap_or(builder.seed));
 
         // Determine the number and size of layers.
 
-        let mut sizes = Vec::new();
-        let mut num = points.len();
-        loop {
-            let next = (num as f32 * ml) as usize;
-            if next < M {
-                break;
-            }
-            sizes.push((num - next, num));
-            num = next;
-        }
+        let counts = builder.layer_distribution.layer_sizes(points.len());
+        let mut sizes = counts
+            .windows(2)
+            .map(|pair| (pair[0] - pair[1], pair[0]))
+            .collect::<Vec<_>>();
+        let num = *counts.last().unwrap();
         sizes.push((num, num));
         sizes.reverse();
 
@@ -190,182 +2662,89 @@ where
             .collect::<Vec<_>>();
         shuffled.sort_unstable();
 
-        let mut new_points = Vec::with_capacity(points.len());
-        let mut new_nodes = Vec::with_capacity(points.len());
-        let mut out = vec![INVALID; points.len()];
-        for (_, idx) in shuffled {
-            let pid = PointId(new_nodes.len() as u32);
-            let layer = sizes
-                .iter()
-                .enumerate()
-                .find_map(|(i, &size)| match (pid.0 as usize) < size.1 {
-                    true => Some(i),
-                    false => None,
-                })
-                .unwrap();
-
-            new_points.push(points[idx].clone());
-            new_nodes.push((LayerId(sizes.len() - layer - 1), pid));
-            out[idx] = pid;
-        }
-        let (points, nodes) = (new_points, new_nodes);
-        debug_assert_eq!(nodes.last().unwrap().0, LayerId(0));
-        debug_assert_eq!(nodes.first().unwrap().0, LayerId(sizes.len() - 1));
-
-        // The layer from the first node is our top layer, or the zero layer if we have no nodes.
-
-        let top = match nodes.first() {
-            Some((top, _)) => *top,
-            None => LayerId(0),
-        };
-
-        // Figure out how many nodes will go on each layer. This helps us allocate memory capacity
-        // for each layer in advance, and also helps enable batch insertion of points.
-
-        let num_layers = sizes.len();
-        let mut ranges = Vec::with_capacity(top.0);
-        for (i, (size, cumulative)) in sizes.into_iter().enumerate() {
-            let start = cumulative - size;
-            // Skip the first point, since we insert the enter point separately
-            ranges.push((LayerId(num_layers - i - 1), max(start, 1)..cumulative));
-        }
-
-        // Insert the first point so that we have an enter point to start searches with.
-
-        let mut layers = vec![vec![]; top.0];
-        let zero = points
-            .iter()
-            .map(|_| RwLock::new(ZeroNode::default()))
-            .collect::<Vec<_>>();
-
-        let pool = SearchPool::new(points.len());
-        #[cfg(feature = "indicatif")]
-        let done = AtomicUsize::new(0);
-        for (layer, range) in ranges {
-            let num = if layer.is_zero() { M * 2 } else { M };
-            #[cfg(feature = "indicatif")]
-            if let Some(bar) = &progress {
-                bar.set_message(&format!("Building index (layer {})", layer.0));
-            }
-
-            let end = range.end;
-            nodes[range].into_par_iter().for_each(|(_, pid)| {
-                let node = zero.as_slice()[*pid].write();
-                let (mut search, mut insertion) = pool.pop();
-                let point = &points.as_slice()[*pid];
-                search.reset();
-                search.push(PointId(0), point, &points);
-
-                for cur in top.descend() {
-                    search.ef = if cur <= layer { ef_construction } else { 1 };
-                    match cur > layer {
-                        true => {
-                            search.search(point, layers[cur.0 - 1].as_slice(), &points, num);
-                            search.cull();
-                        }
-                        false => {
-                            search.search(point, zero.as_slice(), &points, num);
-                            break;
-                        }
-                    }
+        match (builder.order, builder.shuffle_seed) {
+            (InsertionOrder::Input, _) => {
+                // Keep the random layer assignment, but order points within each layer by input
+                // index.
+                let mut start = 0;
+                for &(_, end) in &sizes {
+                    shuffled[start..end].sort_unstable_by_key(|&(_, idx)| idx);
+                    start = end;
                 }
-
-                insertion.ef = ef_construction;
-                insert(
-                    *pid,
-                    node,
-                    &mut insertion,
-                    &mut search,
-                    &zero,
-                    &points,
-                    &heuristic,
-                );
-
-                #[cfg(feature = "indicatif")]
-                if let Some(bar) = &progress {
-                    let value = done.fetch_add(1, atomic::Ordering::Relaxed);
-                    if value % 1000 == 0 {
-                        bar.set_position(value as u64);
-                    }
+            }
+            (InsertionOrder::Random, Some(seed)) => {
+                // Keep the layer assignment, but reshuffle points within each layer.
+                let mut order = SmallRng::seed_from_u64(seed);
+                let mut start = 0;
+                for &(_, end) in &sizes {
+                    shuffled[start..end].shuffle(&mut order);
+                    start = end;
                 }
-
-                pool.push((search, insertion));
-            });
-
-            // For layers above the zero layer, make a copy of the current state of the zero layer
-            // with `nearest` truncated to `M` elements.
-            if !layer.is_zero() {
-                let mut upper = Vec::new();
-                (&zero[..end])
-                    .into_par_iter()
-                    .map(|zero| UpperNode::from_zero(&zero.read()))
-                    .collect_into_vec(&mut upper);
-                layers[layer.0 - 1] = upper;
             }
+            (InsertionOrder::Random, None) => {}
         }
 
-        #[cfg(feature = "indicatif")]
-        if let Some(bar) = progress {
-            bar.finish();
-        }
-
-        (
-            Self {
-                ef_search,
-                zero: zero.into_iter().map(|node| node.into_inner()).collect(),
-                points,
-                layers,
-            },
-            out,
-        )
-    }
+        // The first point becomes the entry point, so move the medoid there if requested.
+
+        if let EntryStrategy::Medoid { sample } = builder.entry_point {
+            let sample = index::sample(&mut rng, points.len(), max(min(sample, points.len()), 1));
+            let sample = sample.into_vec();
+            let medoid = install(builder.thread_pool.as_deref(), || {
+                sample
+                    .par_iter()
+                    .map(|&i| {
+                        let total = sample.iter().map(|&j| points[i].distance(&points[j])).sum();
+                        (OrderedFloat::<f32>(total), i)
+                    })
+                    .min()
+                    .unwrap()
+                    .1
+            });
 
-    /// Search the index for the points nearest to the reference point `point`
-    ///
-    /// The results are returned in the `out` parameter; the number of neighbors to search for
-    /// is limited by the size of the `out` parameter, and the number of results found is returned
-    /// in the return value.
-    pub fn search<'a>(
-        &self,
-        point: &P,
-        search: &'a mut Search,
-    ) -> impl Iterator<Item = Candidate> + ExactSizeIterator + 'a {
-        search.reset();
-        if self.points.is_empty() {
-            return search.iter();
+            let pos = shuffled.iter().position(|&(_, idx)| idx == medoid).unwrap();
+            shuffled.swap(0, pos);
         }
 
-        search.visited.reserve_capacity(self.points.len());
-        search.push(PointId(0), point, &self.points);
-        for cur in LayerId(self.layers.len()).descend() {
-            let (ef, num) = match cur.is_zero() {
-                true => (self.ef_search, M * 2),
-                false => (1, M),
-            };
+        let mut new_points = Vec::with_capacity(points.len());
+        let mut out = vec![INVALID; points.len()];
+        for (_, idx) in shuffled {
+            out[idx] = PointId(new_points.len() as u32);
+            new_points.push(points[idx].clone());
+        }
 
-            search.ef = ef;
-            match cur.0 {
-                0 => search.search(point, self.zero.as_slice(), &self.points, num),
-                l => search.search(point, self.layers[l - 1].as_slice(), &self.points, num),
-            }
+        // The first point is the enter point, which starts out without any neighbors.
 
-            if !cur.is_zero() {
-                search.cull();
-            }
+        Self {
+            points: new_points,
+            out,
+            zero: vec![ZeroNode::default(); points.len()],
+            layers: vec![vec![]; sizes.len() - 1],
+            sizes,
+            done: 1,
         }
+    }
+}
 
-        search.iter()
+impl<P> Checkpoint<P> {
+    /// The number of points that have been inserted so far
+    pub fn inserted(&self) -> usize {
+        self.done
     }
 
-    /// Iterate over the keys and values in this index
-    pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
-        self.points
-            .iter()
-            .enumerate()
-            .map(|(i, p)| (PointId(i as u32), p))
+    /// The total number of points in the index being built
+    pub fn total(&self) -> usize {
+        self.points.len()
     }
 }
 
+/// The number of points in the deterministic insertion batch starting at `start`
+///
+/// Batches grow with the graph, so that the points in a batch only miss out on a small
+/// fraction of the graph while searching.
+fn batch_size(start: usize) -> usize {
+    (start / 16).clamp(1, 1024)
+}
+
 /// Insert new node in the zero layer
 ///
 /// * `new`: the `PointId` for the new node
@@ -373,9 +2752,11 @@ where
 /// * `search`: the result for searching potential neighbors for the new node
 /// *  `layer` contains all the nodes at the current layer
 /// * `points` is a slice of all the points in the index
+/// * `links`: the maximum number of neighbors per node
 ///
 /// Creates the new node, initializing its `nearest` array and updates the nearest neighbors
 /// for the new node's neighbors if necessary before appending the new node to the layer.
+#[allow(clippy::too_many_arguments)]
 fn insert<P: Point>(
     new: PointId,
     mut node: parking_lot::RwLockWriteGuard<ZeroNode>,
@@ -384,13 +2765,14 @@ fn insert<P: Point>(
     layer: &[RwLock<ZeroNode>],
     points: &[P],
     heuristic: &Option<Heuristic>,
+    links: usize,
 ) {
     let found = match heuristic {
         None => {
             let candidates = search.select_simple();
-            &candidates[..Ord::min(candidates.len(), M * 2)]
+            &candidates[..Ord::min(candidates.len(), links)]
         }
-        Some(heuristic) => search.select_heuristic(&points[new], layer, points, *heuristic),
+        Some(heuristic) => search.select_heuristic(&points[new], layer, points, *heuristic, links),
     };
 
     // Just make sure the candidates are all unique
@@ -410,6 +2792,7 @@ fn insert<P: Point>(
                 &points[pid],
                 points,
                 *heuristic,
+                links,
             );
 
             layer[pid]
@@ -433,29 +2816,44 @@ fn insert<P: Point>(
                 })
                 .unwrap_or_else(|e| e);
 
-            layer[pid].write().insert(idx, new);
+            layer[pid].write().insert(idx, new, links);
             node.set(i, pid);
         }
     }
 }
 
+/// Run `op` on `pool`, or on the rayon pool of the calling context if there is none
+fn install<R: Send>(pool: Option<&ThreadPool>, op: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
 struct SearchPool {
     pool: Mutex<Vec<(Search, Search)>>,
     len: usize,
+    min_links: usize,
 }
 
 impl SearchPool {
-    fn new(len: usize) -> Self {
+    fn new(len: usize, min_links: usize) -> Self {
         Self {
             pool: Mutex::new(Vec::new()),
             len,
+            min_links,
         }
     }
 
     fn pop(&self) -> (Search, Search) {
         match self.pool.lock().pop() {
             Some(res) => res,
-            None => (Search::new(self.len), Search::new(self.len)),
+            None => {
+                let (mut search, mut insertion) = (Search::new(self.len), Search::new(self.len));
+                search.min_links = self.min_links;
+                insertion.min_links = self.min_links;
+                (search, insertion)
+            }
         }
     }
 
@@ -468,6 +2866,10 @@ impl SearchPool {
 ///
 /// In particular, this contains most of the state used in algorithm 2. The structure is
 /// initialized by using `push()` to add the initial enter points.
+///
+/// Concurrent searches over a shared `Hnsw` should each use their own `Search`. A single
+/// `Search` can be reused across different indexes, even of different sizes: every search
+/// resets the state from the previous one and resizes the internal buffers as needed.
 pub struct Search {
     /// Nodes visited so far (`v` in the paper)
     visited: Visited,
@@ -480,8 +2882,33 @@ pub struct Search {
     /// Working set for heuristic selection
     working: Vec<Candidate>,
     discarded: Vec<Candidate>,
+    /// Minimum number of neighbors `select_heuristic()` keeps, adding back pruned candidates
+    min_links: usize,
     /// Maximum number of nearest neighbors to retain (`ef` in the paper)
     ef: usize,
+    /// Overrides the index's `ef_search` parameter, if set
+    ef_search: Option<usize>,
+    /// Maximum number of results to yield
+    k: Option<usize>,
+    /// Results closer than this distance are excluded from the zero layer search
+    min_distance: Option<OrderedFloat<f32>>,
+    /// Results farther than this distance are dropped once the search has finished
+    max_distance: Option<OrderedFloat<f32>>,
+    /// Results within this distance of a nearer result are dropped
+    dedup_epsilon: Option<f32>,
+    /// Nodes closer than this are traversed but not retained in `nearest`
+    floor: OrderedFloat<f32>,
+    /// Number of distances computed since the last reset
+    #[cfg(feature = "tracing")]
+    distances: usize,
+    /// Whether links to nodes that don't exist are skipped, rather than causing a panic
+    skip_invalid: bool,
+    /// Number of invalid links skipped since the last reset
+    invalid_links: usize,
+    /// Layer currently being searched
+    layer: usize,
+    /// Steps taken so far, only recorded by `Hnsw::search_traced()`
+    trace: Option<Vec<TraceStep>>,
 }
 
 impl Search {
@@ -507,23 +2934,68 @@ impl Search {
     /// Invariants: `self.nearest` should be in sorted (nearest first) order, and should be
     /// truncated to `self.ef`.
     fn search<L: Layer, P: Point>(&mut self, point: &P, layer: L, points: &[P], links: usize) {
+        self.search_where(point, layer, points, links, |_| true)
+    }
+
+    /// Search the given layer like `search()`, only retaining nodes for which `accept` is `true`
+    fn search_where<L: Layer, P: Point>(
+        &mut self,
+        point: &P,
+        layer: L,
+        points: &[P],
+        links: usize,
+        accept: impl Fn(PointId) -> bool,
+    ) {
+        let current = self.layer;
         while let Some(Reverse(candidate)) = self.candidates.pop() {
             if let Some(furthest) = self.nearest.last() {
                 if candidate.distance > furthest.distance {
-                    break;
+                    self.record(|| TraceStep::Stop {
+                        layer: current,
+                        next: Some(candidate.distance.0),
+                    });
+                    return;
                 }
             }
 
+            if self.skip_invalid && !layer.has_node(candidate.pid) {
+                self.invalid_links += 1;
+                continue;
+            }
+
+            self.record(|| TraceStep::Expand {
+                layer: current,
+                pid: candidate.pid,
+                distance: candidate.distance.0,
+            });
             for pid in layer.nearest_iter(candidate.pid).take(links) {
-                self.push(pid, point, points);
+                if self.skip_invalid && pid.0 as usize >= points.len() {
+                    self.invalid_links += 1;
+                    continue;
+                }
+
+                self.push_where(pid, point, points, &accept);
             }
 
             // If we don't truncate here, `furthest` will be further out than necessary, making
             // us continue looping while we could have broken out.
             self.nearest.truncate(self.ef);
         }
+
+        self.record(|| TraceStep::Stop {
+            layer: current,
+            next: None,
+        });
+    }
+
+    /// Add the step created by `step` to the trace, if one is being recorded
+    fn record(&mut self, step: impl FnOnce() -> TraceStep) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(step());
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_neighbor_heuristic<L: Layer, P: Point>(
         &mut self,
         new: PointId,
@@ -532,16 +3004,17 @@ impl Search {
         point: &P,
         points: &[P],
         params: Heuristic,
+        links: usize,
     ) -> &[Candidate] {
         self.reset();
         self.push(new, point, points);
         for pid in current {
             self.push(pid, point, points);
         }
-        self.select_heuristic(point, layer, points, params)
+        self.select_heuristic(point, layer, points, params, links)
     }
 
-    /// Heuristically sort and truncate neighbors in `self.nearest`
+    /// Heuristically sort and truncate neighbors in `self.nearest` to at most `links` nodes
     ///
     /// Invariant: `self.nearest` must be in sorted (nearest first) order.
     fn select_heuristic<L: Layer, P: Point>(
@@ -550,6 +3023,7 @@ impl Search {
         layer: L,
         points: &[P],
         params: Heuristic,
+        links: usize,
     ) -> &[Candidate] {
         self.working.clear();
         // Get input candidates from `self.nearest` and store them in `self.working`.
@@ -564,6 +3038,11 @@ impl Search {
 
                     let other = &points[hop];
                     let distance = OrderedFloat::from(point.distance(other));
+                    #[cfg(feature = "tracing")]
+                    {
+                        self.distances += 1;
+                    }
+
                     let new = Candidate { distance, pid: hop };
                     self.working.push(new);
                 }
@@ -577,7 +3056,7 @@ impl Search {
         self.nearest.clear();
         self.discarded.clear();
         for candidate in self.working.drain(..) {
-            if self.nearest.len() >= M * 2 {
+            if self.nearest.len() >= links {
                 break;
             }
 
@@ -595,14 +3074,17 @@ impl Search {
             }
         }
 
-        if params.keep_pruned {
-            // Add discarded connections from `working` (`Wd`) to `self.nearest` (`R`)
-            for candidate in self.discarded.drain(..) {
-                if self.nearest.len() >= M * 2 {
-                    break;
-                }
-                self.nearest.push(candidate);
+        // Add discarded connections from `working` (`Wd`) to `self.nearest` (`R`), to fill up
+        // the neighbor list or at least reach the minimum number of links
+        let keep = match params.keep_pruned {
+            true => links,
+            false => min(self.min_links, links),
+        };
+        for candidate in self.discarded.drain(..) {
+            if self.nearest.len() >= keep {
+                break;
             }
+            self.nearest.push(candidate);
         }
 
         &self.nearest
@@ -613,13 +3095,41 @@ impl Search {
     /// Will immediately return if the node has been considered before. This implements
     /// the inner loop from the paper's algorithm 2.
     fn push<P: Point>(&mut self, pid: PointId, point: &P, points: &[P]) {
+        self.push_where(pid, point, points, |_| true)
+    }
+
+    /// Track node `pid` like `push()`, but only as a result if `accept` returns `true`
+    fn push_where<P: Point>(
+        &mut self,
+        pid: PointId,
+        point: &P,
+        points: &[P],
+        accept: impl Fn(PointId) -> bool,
+    ) {
         if !self.visited.insert(pid) {
             return;
         }
 
-        let other = &points[pid];
-        let distance = OrderedFloat::from(point.distance(other));
+        let distance = self.distance(point, &points[pid]);
+        #[cfg(feature = "tracing")]
+        {
+            self.distances += 1;
+        }
+
+        let layer = self.layer;
+        self.record(|| TraceStep::Visit {
+            layer,
+            pid,
+            distance: distance.0,
+        });
+
         let new = Candidate { distance, pid };
+        if distance < self.floor || !accept(pid) {
+            // Not a result, but its neighbors might be
+            self.candidates.push(Reverse(new));
+            return;
+        }
+
         let idx = match self.nearest.binary_search(&new) {
             Err(idx) if idx < self.ef => idx,
             Err(_) => return,
@@ -647,22 +3157,157 @@ impl Search {
         self.visited.extend(self.nearest.iter().map(|c| c.pid));
     }
 
+    /// Reset the search to hold the `nearest` candidates found by an earlier search
+    fn restore(&mut self, nearest: &[Candidate], ef: usize) {
+        self.reset();
+        self.ef = ef;
+        self.nearest.extend_from_slice(nearest);
+        self.visited.extend(nearest.iter().map(|c| c.pid));
+    }
+
+    /// Create a `Search` with buffers sized for searching with the given `ef` and `degree`
+    ///
+    /// `ef` is the `ef_search` value searches will use, and `degree` the number of links
+    /// followed per node; on the zero layer, that is at most 64. A search holds up to
+    /// `ef + degree` results before truncating them to `ef`. The candidate heap usually stays
+    /// within the same size, but it also keeps points that were pushed out of the results, so
+    /// it can grow beyond that. Both buffers take 8 bytes per entry, so a `Search` created this
+    /// way avoids reallocating them during typical searches. The set of visited nodes is not
+    /// presized: its size depends on the `VisitedSet` in use (see `set_visited_set()`) rather
+    /// than on `ef`, and the default `Dense` set is allocated on the first search.
+    pub fn with_capacity(ef: usize, degree: usize) -> Self {
+        Self {
+            candidates: BinaryHeap::with_capacity(ef + degree),
+            nearest: Vec::with_capacity(ef + degree),
+            ..Default::default()
+        }
+    }
+
+    /// Create a `Search` that keeps track of visited nodes using the given `VisitedSet`
+    pub fn with_visited_set(visited: VisitedSet) -> Self {
+        let mut search = Self::default();
+        search.set_visited_set(visited);
+        search
+    }
+
     /// Resets the state to be ready for a new search
-    fn reset(&mut self) {
+    ///
+    /// This discards the results of the last search, but keeps options like `set_k()`. Searches
+    /// reset the `Search` themselves, so calling this is never required. The capacity of the
+    /// internal buffers is kept as well, so they don't have to be allocated again.
+    pub fn reset(&mut self) {
         let Search {
             visited,
             candidates,
             nearest,
             working,
             discarded,
+            min_links: _,
             ef: _,
+            ef_search: _,
+            k: _,
+            min_distance: _,
+            max_distance: _,
+            dedup_epsilon: _,
+            floor,
+            #[cfg(feature = "tracing")]
+            distances,
+            skip_invalid: _,
+            invalid_links,
+            layer: _,
+            trace,
         } = self;
 
+        if let Some(trace) = trace {
+            trace.clear();
+        }
         visited.clear();
         candidates.clear();
         nearest.clear();
         working.clear();
         discarded.clear();
+        *floor = OrderedFloat(f32::NEG_INFINITY);
+        *invalid_links = 0;
+        #[cfg(feature = "tracing")]
+        {
+            *distances = 0;
+        }
+    }
+
+    /// Compute the distance from `point` to `other` for a potential new result
+    ///
+    /// Once `nearest` holds `ef` results, any point farther away than the furthest of them is
+    /// discarded, so the distance computation may stop early through `Point::distance_bounded()`.
+    fn distance<P: Point>(&self, point: &P, other: &P) -> OrderedFloat<f32> {
+        let furthest = match self.nearest.len() >= self.ef {
+            true => self.nearest.last(),
+            false => None,
+        };
+
+        OrderedFloat::from(match furthest {
+            Some(furthest) => point.distance_bounded(other, furthest.distance.into_inner()),
+            None => point.distance(other),
+        })
+    }
+
+    /// Start excluding nodes closer than `min_distance` from the results, if set
+    ///
+    /// Only used for the zero layer: upper layers need the nearest nodes to route the search.
+    fn apply_min_distance(&mut self) {
+        if let Some(min) = self.min_distance {
+            self.floor = min;
+            self.nearest.retain(|candidate| candidate.distance >= min);
+        }
+    }
+
+    /// Drop the results farther than `max_distance`, if set
+    fn apply_max_distance(&mut self) {
+        if let Some(max) = self.max_distance {
+            let len = self
+                .nearest
+                .partition_point(|candidate| candidate.distance <= max);
+            self.nearest.truncate(len);
+        }
+    }
+
+    /// Compare `point` to all `points`, keeping the nearest `ef` accepted by `accept`
+    fn scan<P: Point>(&mut self, point: &P, points: &[P], accept: impl Fn(PointId) -> bool) {
+        for (i, other) in points.iter().enumerate() {
+            let pid = PointId(i as u32);
+            if !accept(pid) {
+                continue;
+            }
+
+            let distance = self.distance(point, other);
+            if distance < self.floor {
+                continue;
+            }
+
+            let new = Candidate { distance, pid };
+            let idx = match self.nearest.binary_search(&new) {
+                Ok(idx) | Err(idx) => idx,
+            };
+            if idx < self.ef {
+                self.nearest.insert(idx, new);
+                self.nearest.truncate(self.ef);
+            }
+        }
+    }
+
+    /// Drop results within `epsilon` of a nearer result, keeping the rest in order
+    fn dedup<P: Point>(&mut self, points: &[P], epsilon: f32) {
+        self.working.clear();
+        for candidate in self.nearest.drain(..) {
+            let point = &points[candidate.pid];
+            let duplicate = self
+                .working
+                .iter()
+                .any(|kept| point.distance(&points[kept.pid]) <= epsilon);
+            if !duplicate {
+                self.working.push(candidate);
+            }
+        }
+        std::mem::swap(&mut self.nearest, &mut self.working);
     }
 
     /// Selection of neighbors for insertion (algorithm 3 from the paper)
@@ -670,13 +3315,114 @@ impl Search {
         &self.nearest
     }
 
-    fn iter(&self) -> impl Iterator<Item = Candidate> + ExactSizeIterator + '_ {
-        self.nearest.iter().copied()
+    /// Override the `ef_search` parameter of the index for searches using this `Search`
+    ///
+    /// Larger values improve recall at the cost of slower searches. Pass `None` to use the
+    /// value the index was built with again.
+    pub fn set_ef_search(&mut self, ef: Option<usize>) {
+        self.ef_search = ef;
+    }
+
+    /// Exclude points closer than `min` to the query from the results
+    ///
+    /// Points closer than `min` are still traversed, so the search will continue outward until
+    /// it has found up to `ef_search` results beyond `min`. Because the nodes within `min` are
+    /// not counted, searches with a large minimum distance visit more nodes than usual;
+    /// consider raising `ef_search` if results beyond the floor are missing.
+    pub fn set_min_distance(&mut self, min: Option<f32>) {
+        self.min_distance = min.map(OrderedFloat::from);
+    }
+
+    /// Exclude points farther than `max` from the query from the results
+    ///
+    /// Unlike `set_min_distance()`, this does not change how the search traverses the graph:
+    /// the search finds the usual `ef_search` nearest points, and those beyond `max` are then
+    /// dropped. Combined with `set_k()`, this yields the nearest `k` results within `max`, which
+    /// may be fewer than `k`.
+    pub fn set_max_distance(&mut self, max: Option<f32>) {
+        self.max_distance = max.map(OrderedFloat::from);
+    }
+
+    /// Drop results within `epsilon` of a nearer result, to avoid returning near-duplicates
+    ///
+    /// Results are considered nearest first, and each is kept only if it is farther than
+    /// `epsilon` from all results kept before it. To make up for dropped results, the search is
+    /// repeated with a doubled `ef_search` until it finds `k` results (or `ef_search` results,
+    /// if `k` is not set) or has considered every point. Searches may thus look much deeper than
+    /// `ef_search`, and may still return fewer results if the index has too few distinct points.
+    pub fn set_dedup_epsilon(&mut self, epsilon: Option<f32>) {
+        self.dedup_epsilon = epsilon;
+    }
+
+    /// Keep track of visited nodes using the given `VisitedSet` from the next search on
+    ///
+    /// This replaces the current set, so memory it holds is released. Combine this with
+    /// `with_capacity()` to create a presized `Search` that uses a `VisitedSet::HashSet`.
+    pub fn set_visited_set(&mut self, visited: VisitedSet) {
+        self.visited = match visited {
+            VisitedSet::Dense => Visited::with_capacity(0),
+            VisitedSet::HashSet => Visited::Hashed(HashSet::new()),
+        };
+    }
+
+    /// Skip links to nodes that don't exist during searches, instead of panicking
+    ///
+    /// This is meant for serving indexes that fail `Hnsw::verify_integrity()` until an intact
+    /// copy can be loaded: searches check every link they follow and skip invalid ones, yielding
+    /// best-effort results from the intact part of the graph. `invalid_links()` tells whether
+    /// a search ran into any. The checks make searches slightly slower, so this is off by
+    /// default.
+    pub fn set_skip_invalid_links(&mut self, skip: bool) {
+        self.skip_invalid = skip;
+    }
+
+    /// The number of invalid links skipped by the last search
+    ///
+    /// Results may be incomplete if this is not zero. Always zero unless enabled through
+    /// `set_skip_invalid_links()`.
+    pub fn invalid_links(&self) -> usize {
+        self.invalid_links
+    }
+
+    /// Limit the number of results yielded after a search to `k`
+    ///
+    /// The search itself still considers up to `ef_search` candidates, so this only affects
+    /// how many of the nearest results are returned. Pass `None` to return all of them.
+    pub fn set_k(&mut self, k: Option<usize>) {
+        self.k = k;
+    }
+
+    /// The number of results from the last search
+    ///
+    /// This takes the limit set through `set_k()` into account, so it matches the number of
+    /// results yielded by `Hnsw::search()`.
+    pub fn len(&self) -> usize {
+        match self.k {
+            Some(k) => min(k, self.nearest.len()),
+            None => self.nearest.len(),
+        }
+    }
+
+    /// Whether the last search did not find any results
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn iter(&self) -> impl ExactSizeIterator<Item = Candidate> + '_ {
+        self.nearest.iter().take(self.len()).copied()
+    }
+
+    #[doc(hidden)]
+    pub fn capacity(&self) -> usize {
+        self.nearest.capacity()
     }
 
     #[doc(hidden)]
     pub fn get(&self, i: usize) -> Option<Candidate> {
-        self.nearest.get(i).copied()
+        match self.k {
+            Some(k) if i >= k => None,
+            _ => self.nearest.get(i).copied(),
+        }
     }
 }
 
@@ -688,13 +3434,70 @@ impl Default for Search {
             nearest: Vec::new(),
             working: Vec::new(),
             discarded: Vec::new(),
+            min_links: 0,
             ef: 1,
+            ef_search: None,
+            k: None,
+            min_distance: None,
+            max_distance: None,
+            dedup_epsilon: None,
+            floor: OrderedFloat(f32::NEG_INFINITY),
+            #[cfg(feature = "tracing")]
+            distances: 0,
+            skip_invalid: false,
+            invalid_links: 0,
+            layer: 0,
+            trace: None,
         }
     }
 }
 
+/// Data structure used by a `Search` to keep track of the nodes it has visited
+#[derive(Copy, Clone, Debug)]
+pub enum VisitedSet {
+    /// One byte for every point in the index, allocated on the first search
+    ///
+    /// Checking and marking a node is a single array access, and the set can be cleared in
+    /// constant time, so this is the fastest option when a `Search` is reused for many queries.
+    /// Its size depends on the index rather than on the search, which adds up for large
+    /// indexes with many concurrent searches. This is what `Search::default()` uses.
+    Dense,
+    /// A hash set holding only the visited nodes
+    ///
+    /// Memory use is proportional to the number of nodes a search visits (typically a small
+    /// multiple of `ef_search` times the number of links per node), regardless of the size of
+    /// the index. Every check costs a hash lookup, so searches are slower than with `Dense`.
+    HashSet,
+}
+
+/// A point that can be indexed in an `Hnsw`
+///
+/// Points only need to define their distance to other points, so they don't have to be
+/// coordinate vectors. For data where distances come from some shared source (like a
+/// precomputed distance matrix, or an external model), a point can hold an identifier and
+/// a reference to that source, for example through an `Arc`. Since points are cloned into
+/// the index, this keeps the source shared between all points rather than copied.
 pub trait Point: Clone + Sync {
     fn distance(&self, other: &Self) -> f32;
+
+    /// The distance to `other`, or any value larger than `bound` if the distance exceeds it
+    ///
+    /// Searches call this instead of `distance()` once they have found enough results, passing
+    /// the distance to the furthest of them: points farther away will be discarded, so their
+    /// exact distance is not needed. For high-dimensional vectors, implementations can stop
+    /// summing as soon as a partial distance exceeds `bound`; checking the partial sum has a
+    /// cost of its own, so this is usually not worth it for low-dimensional points. The default
+    /// implementation always computes the full distance.
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        let _ = bound;
+        self.distance(other)
+    }
+}
+
+/// A point that can be averaged with other points, for `Hnsw::centroid()`
+pub trait Centroid: Point {
+    /// Compute the mean of `points`, which contains at least one point
+    fn centroid(points: &[&Self]) -> Self;
 }
 
 /// The parameter `M` from the paper
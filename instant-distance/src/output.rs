@@ -0,0 +1,62 @@
+use std::ops::Deref;
+
+use crate::{Hnsw, PointId};
+
+/// An index along with the ids assigned to its input points, as returned by
+/// `Builder::build_output()`
+///
+/// Points are stored in construction order rather than input order, so the builder assigns
+/// each input point a `PointId` that generally differs from its position in the input. This
+/// keeps the mapping in both directions next to the index: `pid()` goes from input positions
+/// to ids, and `original_index()` from ids back to input positions. It dereferences to the
+/// `Hnsw`, so it can be searched directly; use `into_parts()` to get the same tuple that
+/// `Builder::build()` returns.
+pub struct BuildOutput<P> {
+    /// The index
+    pub index: Hnsw<P>,
+    /// The id of every input point, in input order: `ids[i]` is the id of `points[i]`
+    ///
+    /// For indexes built with `Builder::build_dedup()`, duplicate points share an id.
+    pub ids: Vec<PointId>,
+}
+
+impl<P> BuildOutput<P> {
+    /// The id assigned to the input point at position `index`
+    ///
+    /// Panics if `index` is out of bounds for the input.
+    pub fn pid(&self, index: usize) -> PointId {
+        self.ids[index]
+    }
+
+    /// The position in the input of the point with id `pid`
+    ///
+    /// This is `Hnsw::original_index()`; for duplicates, it is the position of the first one.
+    pub fn original_index(&self, pid: PointId) -> usize {
+        self.index.original_index(pid)
+    }
+
+    /// Split into the index and the ids, as returned by `Builder::build()`
+    pub fn into_parts(self) -> (Hnsw<P>, Vec<PointId>) {
+        (self.index, self.ids)
+    }
+}
+
+impl<P> Deref for BuildOutput<P> {
+    type Target = Hnsw<P>;
+
+    fn deref(&self) -> &Hnsw<P> {
+        &self.index
+    }
+}
+
+impl<P> From<(Hnsw<P>, Vec<PointId>)> for BuildOutput<P> {
+    fn from((index, ids): (Hnsw<P>, Vec<PointId>)) -> Self {
+        Self { index, ids }
+    }
+}
+
+impl<P> From<BuildOutput<P>> for (Hnsw<P>, Vec<PointId>) {
+    fn from(output: BuildOutput<P>) -> Self {
+        output.into_parts()
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Builder, EntryStrategy, Heuristic, InsertionOrder, LayerDistribution, M};
+
+/// The parameters an `Hnsw` was built with, as returned by `Hnsw::build_params()`
+///
+/// These are kept with the index, so that the way an index was built can be logged or audited
+/// later. They are not serialized as part of the index; see `Hnsw::build_params()` for how to
+/// keep them across a dump. Settings that don't affect the resulting index, like the thread
+/// pool or progress reporting, are not included. `builder()` returns a `Builder` with the same
+/// parameters, which builds the same index from the same points if `deterministic` was set.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, Debug)]
+pub struct BuildParams {
+    /// See `Builder::ef_search()`; `Search::set_ef_search()` overrides it per search
+    pub ef_search: usize,
+    /// See `Builder::ef_construction()`
+    pub ef_construction: usize,
+    /// See `Builder::select_heuristic()`
+    pub heuristic: Option<Heuristic>,
+    /// See `Builder::max_connections()`
+    pub max_connections: usize,
+    /// See `Builder::max_connections_0()`, with the default resolved
+    pub max_connections_0: usize,
+    /// See `Builder::min_connections()`
+    pub min_connections: usize,
+    /// See `Builder::layer_distribution()`
+    pub layer_distribution: LayerDistribution,
+    /// See `Builder::order()`
+    pub order: InsertionOrder,
+    /// See `Builder::entry_point()`
+    pub entry_point: EntryStrategy,
+    /// See `Builder::seed()`; this is the generated seed if none was set
+    pub seed: u64,
+    /// See `Builder::layer_seed()`
+    pub layer_seed: Option<u64>,
+    /// See `Builder::shuffle_seed()`
+    pub shuffle_seed: Option<u64>,
+    /// See `Builder::deterministic()`
+    pub deterministic: bool,
+    /// See `Builder::compress_graph()`
+    pub compress_graph: bool,
+    /// See `Builder::flat()`
+    pub flat: bool,
+}
+
+impl BuildParams {
+    /// A `Builder` configured with these parameters
+    pub fn builder(&self) -> Builder {
+        let mut builder = Builder::default()
+            .ef_search(self.ef_search)
+            .ef_construction(self.ef_construction)
+            .select_heuristic(self.heuristic)
+            .max_connections(self.max_connections)
+            .max_connections_0(self.max_connections_0)
+            .min_connections(self.min_connections)
+            .layer_distribution(self.layer_distribution)
+            .order(self.order)
+            .entry_point(self.entry_point)
+            .seed(self.seed)
+            .deterministic(self.deterministic)
+            .compress_graph(self.compress_graph)
+            .flat(self.flat);
+        builder.layer_seed = self.layer_seed;
+        builder.shuffle_seed = self.shuffle_seed;
+        builder
+    }
+
+    /// The `mL` parameter from the paper, as used by the layer distribution
+    ///
+    /// Returns `None` for `LayerDistribution::Uniform`, which doesn't use `mL`.
+    pub fn ml(&self) -> Option<f32> {
+        match self.layer_distribution {
+            LayerDistribution::Exponential { ml } | LayerDistribution::Capped { ml, .. } => {
+                Some(ml)
+            }
+            LayerDistribution::Uniform { .. } => None,
+        }
+    }
+}
+
+impl From<&Builder> for BuildParams {
+    fn from(builder: &Builder) -> Self {
+        Self {
+            ef_search: builder.ef_search,
+            ef_construction: builder.ef_construction,
+            heuristic: builder.heuristic,
+            max_connections: builder.max_connections,
+            max_connections_0: builder
+                .max_connections_0
+                .unwrap_or(builder.max_connections * 2)
+                .min(M * 2),
+            min_connections: builder.min_connections,
+            layer_distribution: builder.layer_distribution,
+            order: builder.order,
+            entry_point: builder.entry_point,
+            seed: builder.seed,
+            layer_seed: builder.layer_seed,
+            shuffle_seed: builder.shuffle_seed,
+            deterministic: builder.deterministic,
+            compress_graph: builder.compress_graph,
+            flat: builder.flat,
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Deterministic synthetic datasets for tests and benchmarks
+//!
+//! Every function returns `n` rows of `dim` values as a single row-major `Vec<f32>`, which can
+//! be passed to `Builder::build_flat()` directly. The same arguments produce the same data on
+//! every platform (for a given version of the `rand` crate), so results obtained with these
+//! datasets can be reproduced by others.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Points drawn uniformly from the unit hypercube
+pub fn uniform(n: usize, dim: usize, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n * dim).map(|_| rng.gen()).collect()
+}
+
+/// Points in `clusters` clusters, with centers drawn uniformly from the unit hypercube
+///
+/// Each point belongs to a randomly chosen cluster, and differs from its center by up to
+/// `0.05` in every dimension. Panics if `clusters` is zero.
+pub fn clustered(n: usize, dim: usize, clusters: usize, seed: u64) -> Vec<f32> {
+    assert!(clusters > 0, "number of clusters must be at least 1");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let centers = (0..clusters * dim).map(|_| rng.gen()).collect::<Vec<f32>>();
+
+    let mut data = Vec::with_capacity(n * dim);
+    for _ in 0..n {
+        let cluster = rng.gen_range(0..clusters);
+        let center = &centers[cluster * dim..(cluster + 1) * dim];
+        data.extend(center.iter().map(|c| c + rng.gen_range(-0.05..0.05)));
+    }
+    data
+}
@@ -1,38 +1,48 @@
+use std::collections::HashSet;
 use std::hash::Hash;
-use std::ops::{Deref, Index};
+use std::ops::{Deref, DerefMut, Index};
 
 use ordered_float::OrderedFloat;
 use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard};
 #[cfg(feature = "serde")]
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 #[cfg(feature = "serde-big-array")]
 use serde_big_array::big_array;
 
 use crate::{Hnsw, Point, M};
 
-pub(crate) struct Visited {
-    store: Vec<u8>,
-    generation: u8,
+pub(crate) enum Visited {
+    /// A generation number for every point, so clearing only has to bump the generation
+    Dense { store: Vec<u8>, generation: u8 },
+    /// Only the visited points, so memory use is bounded by the number of visited points
+    Hashed(HashSet<PointId>),
 }
 
 impl Visited {
     pub(crate) fn with_capacity(capacity: usize) -> Self {
-        Self {
+        Visited::Dense {
             store: vec![0; capacity],
             generation: 1,
         }
     }
 
     pub(crate) fn reserve_capacity(&mut self, capacity: usize) {
-        if self.store.len() != capacity {
-            self.store.resize(capacity, self.generation - 1);
+        if let Visited::Dense { store, generation } = self {
+            if store.len() != capacity {
+                store.resize(capacity, *generation - 1);
+            }
         }
     }
 
     pub(crate) fn insert(&mut self, pid: PointId) -> bool {
-        let slot = &mut self.store[pid.0 as usize];
-        if *slot != self.generation {
-            *slot = self.generation;
+        let (store, generation) = match self {
+            Visited::Dense { store, generation } => (store, *generation),
+            Visited::Hashed(set) => return set.insert(pid),
+        };
+
+        let slot = &mut store[pid.0 as usize];
+        if *slot != generation {
+            *slot = generation;
             true
         } else {
             false
@@ -46,15 +56,20 @@ impl Visited {
     }
 
     pub(crate) fn clear(&mut self) {
-        if self.generation < 249 {
-            self.generation += 1;
+        let (store, generation) = match self {
+            Visited::Dense { store, generation } => (store, generation),
+            Visited::Hashed(set) => return set.clear(),
+        };
+
+        if *generation < 249 {
+            *generation += 1;
             return;
         }
 
-        let len = self.store.len();
-        self.store.clear();
-        self.store.resize(len, 0);
-        self.generation = 1;
+        let len = store.len();
+        store.clear();
+        store.resize(len, 0);
+        *generation = 1;
     }
 }
 
@@ -63,19 +78,44 @@ impl Visited {
 pub(crate) struct UpperNode([PointId; M]);
 
 impl UpperNode {
-    pub(crate) fn from_zero(node: &ZeroNode) -> Self {
+    /// Copy the first `links` neighbors of a zero layer node
+    pub(crate) fn from_zero(node: &ZeroNode, links: usize) -> Self {
         let mut nearest = [INVALID; M];
-        nearest.copy_from_slice(&node.0[..M]);
+        nearest[..links].copy_from_slice(&node.0[..links]);
         Self(nearest)
     }
 }
 
+impl Default for UpperNode {
+    fn default() -> UpperNode {
+        UpperNode([INVALID; M])
+    }
+}
+
+impl Deref for UpperNode {
+    type Target = [PointId];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for UpperNode {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 impl<'a> Layer for &'a [UpperNode] {
-    type Slice = &'a [PointId];
+    type Iter = NearestIter<&'a [PointId]>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
         NearestIter::new(&self[pid.0 as usize].0)
     }
+
+    fn has_node(&self, pid: PointId) -> bool {
+        (pid.0 as usize) < self.len()
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
@@ -100,16 +140,16 @@ impl ZeroNode {
         }
     }
 
-    pub(crate) fn insert(&mut self, idx: usize, pid: PointId) {
+    /// Insert `pid` at `idx`, keeping at most `links` neighbors
+    pub(crate) fn insert(&mut self, idx: usize, pid: PointId, links: usize) {
         // It might be possible for all the neighbor's current neighbors to be closer to our
         // neighbor than to the new node, in which case we skip insertion of our new node's ID.
-        if idx >= self.0.len() {
+        if idx >= links {
             return;
         }
 
         if self.0[idx].is_valid() {
-            let end = (M * 2) - 1;
-            self.0.copy_within(idx..end, idx + 1);
+            self.0.copy_within(idx..links - 1, idx + 1);
         }
 
         self.0[idx] = pid;
@@ -134,28 +174,250 @@ impl Deref for ZeroNode {
     }
 }
 
+impl DerefMut for ZeroNode {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 impl<'a> Layer for &'a [ZeroNode] {
-    type Slice = &'a [PointId];
+    type Iter = NearestIter<&'a [PointId]>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
         NearestIter::new(&self[pid.0 as usize])
     }
+
+    fn has_node(&self, pid: PointId) -> bool {
+        (pid.0 as usize) < self.len()
+    }
 }
 
 impl<'a> Layer for &'a [RwLock<ZeroNode>] {
-    type Slice = MappedRwLockReadGuard<'a, [PointId]>;
+    type Iter = NearestIter<MappedRwLockReadGuard<'a, [PointId]>>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
         NearestIter::new(RwLockReadGuard::map(
             self[pid.0 as usize].read(),
             Deref::deref,
         ))
     }
+
+    fn has_node(&self, pid: PointId) -> bool {
+        (pid.0 as usize) < self.len()
+    }
+}
+
+/// Marks the serialized layout of `Hnsw`, written before all other fields
+///
+/// Serialized as the magic bytes `IDHNSW02` (as a little-endian `u64`), so that dumps in the
+/// unversioned layout of earlier releases are rejected instead of being misread: those start
+/// with `ef_search`, which never has this value. They can be read with
+/// `Hnsw::deserialize_legacy()`.
+#[derive(Clone, Copy)]
+pub(crate) struct FormatVersion;
+
+#[cfg(feature = "serde")]
+impl FormatVersion {
+    const MAGIC: &'static [u8; 8] = b"IDHNSW02";
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for FormatVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(u64::from_le_bytes(*Self::MAGIC))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for FormatVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u64::deserialize(deserializer)? {
+            magic if magic == u64::from_le_bytes(*Self::MAGIC) => Ok(FormatVersion),
+            _ => Err(de::Error::custom(
+                "unsupported index format, use `Hnsw::deserialize_legacy()` for older dumps",
+            )),
+        }
+    }
+}
+
+/// Neighbor lists for all nodes in a layer of a finished `Hnsw`
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone)]
+pub(crate) enum Nodes<T> {
+    Full(Vec<T>),
+    Packed(PackedNodes),
+}
+
+impl<T> Nodes<T>
+where
+    T: Deref<Target = [PointId]>,
+{
+    /// Store `nodes`, packing neighbor indices if `compress` is set
+    ///
+    /// `num_points` is the number of points in the index, which bounds the neighbor indices.
+    pub(crate) fn new(nodes: Vec<T>, compress: bool, num_points: usize) -> Self {
+        match compress {
+            true => Nodes::Packed(PackedNodes::new(&nodes, num_points)),
+            false => Nodes::Full(nodes),
+        }
+    }
+
+    /// Pack the neighbor indices, if they aren't packed already
+    pub(crate) fn compress(self, num_points: usize) -> Self {
+        match self {
+            Nodes::Full(nodes) => Self::new(nodes, true, num_points),
+            packed => packed,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Nodes::Full(nodes) => nodes.len(),
+            Nodes::Packed(packed) => packed.len(),
+        }
+    }
+}
+
+impl<'a, T> Layer for &'a Nodes<T>
+where
+    T: Deref<Target = [PointId]>,
+{
+    type Iter = NodesIter<'a>;
+
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
+        match self {
+            Nodes::Full(nodes) => NodesIter::Full(NearestIter::new(&nodes[pid.0 as usize])),
+            Nodes::Packed(packed) => NodesIter::Packed(packed.nearest_iter(pid)),
+        }
+    }
+
+    fn has_node(&self, pid: PointId) -> bool {
+        match self {
+            Nodes::Full(nodes) => (pid.0 as usize) < nodes.len(),
+            Nodes::Packed(packed) => packed.has_node(pid),
+        }
+    }
+}
+
+pub(crate) enum NodesIter<'a> {
+    Full(NearestIter<&'a [PointId]>),
+    Packed(PackedIter<'a>),
+}
+
+impl<'a> Iterator for NodesIter<'a> {
+    type Item = PointId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            NodesIter::Full(iter) => iter.next(),
+            NodesIter::Packed(iter) => iter.next(),
+        }
+    }
+}
+
+/// Neighbor lists stored using the smallest integer width that fits all `PointId`s
+///
+/// Indices are stored as little-endian 2, 3 or 4 byte integers. Unused slots are filled
+/// with the maximum value for the width, which marks the end of a neighbor list. Each node
+/// only gets as many slots as the largest neighbor list in the layer needs.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone)]
+pub(crate) struct PackedNodes {
+    /// Number of bytes used per neighbor index
+    width: u8,
+    /// Number of neighbor slots per node
+    links: usize,
+    /// Number of nodes, which can't be derived from `data` if no node has any links
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl PackedNodes {
+    fn new<T: Deref<Target = [PointId]>>(nodes: &[T], num_points: usize) -> Self {
+        let width = match num_points {
+            n if n < u16::MAX as usize => 2,
+            n if n < (1 << 24) - 1 => 3,
+            _ => 4,
+        };
+
+        let links = nodes
+            .iter()
+            .map(|node| node.iter().take_while(|pid| pid.is_valid()).count())
+            .max()
+            .unwrap_or(0);
+        let mut data = Vec::with_capacity(nodes.len() * links * width);
+        for node in nodes {
+            for pid in node.iter().take(links) {
+                let value = match pid.is_valid() {
+                    true => pid.0,
+                    false => u32::MAX,
+                };
+                data.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        }
+
+        Self {
+            width: width as u8,
+            links,
+            len: nodes.len(),
+            data,
+        }
+    }
+
+    fn nearest_iter(&self, pid: PointId) -> PackedIter<'_> {
+        let stride = self.links * self.width as usize;
+        let start = pid.0 as usize * stride;
+        PackedIter {
+            data: &self.data[start..start + stride],
+            width: self.width as usize,
+        }
+    }
+
+    /// Whether `nearest_iter()` can read the neighbor list of `pid`
+    fn has_node(&self, pid: PointId) -> bool {
+        let stride = self.links * self.width as usize;
+        (1..=4).contains(&self.width)
+            && (pid.0 as usize) < self.len
+            && self.len.saturating_mul(stride) <= self.data.len()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+pub(crate) struct PackedIter<'a> {
+    data: &'a [u8],
+    width: usize,
+}
+
+impl<'a> Iterator for PackedIter<'a> {
+    type Item = PointId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < self.width {
+            return None;
+        }
+
+        let (cur, rest) = self.data.split_at(self.width);
+        let mut buf = [0; 4];
+        buf[..self.width].copy_from_slice(cur);
+        let value = u32::from_le_bytes(buf);
+        if value == u32::MAX >> (32 - 8 * self.width) {
+            self.data = &[];
+            return None;
+        }
+
+        self.data = rest;
+        Some(PointId(value))
+    }
 }
 
 pub(crate) trait Layer {
-    type Slice: Deref<Target = [PointId]>;
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice>;
+    type Iter: Iterator<Item = PointId>;
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter;
+    /// Whether the layer holds a neighbor list for `pid`, so that `nearest_iter()` won't panic
+    fn has_node(&self, pid: PointId) -> bool;
 }
 
 pub(crate) struct NearestIter<T> {
@@ -229,6 +491,10 @@ impl Iterator for DescendingLayerIter {
 }
 
 /// A potential nearest neighbor
+///
+/// Candidates are ordered by distance, then by `PointId`, which gives search results a stable
+/// order when several points are at the same distance. The derived `Ord` relies on the order
+/// of the fields.
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Candidate {
     pub(crate) distance: OrderedFloat<f32>,
@@ -1,10 +1,17 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use ordered_float::OrderedFloat;
 use rand::rngs::{StdRng, ThreadRng};
 use rand::{Rng, SeedableRng};
 
-use instant_distance::{Builder, Point as _, Search};
+use instant_distance::{
+    Attributes, BuildOutput, Builder, Column, Compressed, Condition, Corruption, EntryStrategy,
+    Heuristic, Hnsw, InsertionOrder, KeyedHnsw, LayerDistribution, Point as _, PointId, Sampling,
+    Search, TraceStep, VisitedSet, Warning,
+};
 
 #[test]
 fn random_heuristic() {
@@ -20,11 +27,1809 @@ fn random_simple() {
     assert!(recall > 90, "expected at least 90, got {}", recall);
 }
 
+#[test]
+fn random_compressed() {
+    let (seed, recall) = randomized(Builder::default().compress_graph(true));
+    println!("compressed (seed = {}) recall = {}", seed, recall);
+    assert!(recall > 97, "expected at least 98, got {}", recall);
+}
+
+#[test]
+fn compressed_single_point() {
+    // A single point has no links, so the packed neighbor lists hold no data
+    let points = [Point(0.5, 0.5)];
+    let (hnsw, pids) = Builder::default().build(&points);
+    let finalized = hnsw.finalize();
+    assert!(!finalized.is_flat());
+    assert_eq!(finalized.verify_integrity(), Ok(()));
+
+    let mut search = Search::default();
+    let found = finalized.search(&Point(0.0, 0.0), &mut search);
+    assert_eq!(found.map(|c| c.pid).collect::<Vec<_>>(), pids);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn compressed_round_trip() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().compress_graph(true).build(&points);
+    let bytes = bincode::serialize(&hnsw).unwrap();
+    let loaded = bincode::deserialize::<Hnsw<Point>>(&bytes).unwrap();
+
+    let query = Point(rng.gen(), rng.gen());
+    let (mut first, mut second) = (Search::default(), Search::default());
+    let expected = hnsw.search(&query, &mut first).collect::<Vec<_>>();
+    let found = loaded.search(&query, &mut second).collect::<Vec<_>>();
+    assert_eq!(expected, found);
+}
+
+/// An index of 32 points built and dumped with bincode by instant-distance 0.3.0, which didn't
+/// version its serialized layout
+#[cfg(feature = "serde")]
+const LEGACY: &[u8] = include_bytes!("data/legacy.bin");
+
+#[cfg(feature = "serde")]
+#[test]
+fn legacy_format() {
+    use bincode::Options;
+
+    // Older dumps are rejected rather than misread
+    assert!(bincode::deserialize::<Hnsw<Point>>(LEGACY).is_err());
+
+    let options = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+    let mut deserializer = bincode::Deserializer::from_slice(LEGACY, options);
+    let hnsw = Hnsw::<Point>::deserialize_legacy(&mut deserializer).unwrap();
+    assert_eq!(hnsw.verify_integrity(), Ok(()));
+    assert!(hnsw.build_params().is_none());
+
+    // The points were built from `(i * 0.618, i * 0.414)` (mod 1), in some order
+    let mut expected = (0..32)
+        .map(|i| Point((i as f32 * 0.618).fract(), (i as f32 * 0.414).fract()))
+        .collect::<Vec<_>>();
+    let mut stored = hnsw.iter().map(|(_, &point)| point).collect::<Vec<_>>();
+    for points in [&mut expected, &mut stored].iter_mut() {
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    }
+    assert_eq!(stored, expected);
+
+    let mut search = Search::default();
+    for (pid, point) in hnsw.iter() {
+        assert_eq!(hnsw.original_index(pid), pid.into_inner() as usize);
+        let nearest = hnsw.search(point, &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+
+    // Once loaded, the index is written in the current format
+    let bytes = bincode::serialize(&hnsw).unwrap();
+    assert_eq!(&bytes[..8], b"IDHNSW02");
+    let loaded = bincode::deserialize::<Hnsw<Point>>(&bytes).unwrap();
+    assert_eq!(bincode::serialize(&loaded).unwrap(), bytes);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn verify_integrity() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().build(&points);
+    assert_eq!(hnsw.verify_integrity(), Ok(()));
+    let (compressed, _) = builder().compress_graph(true).build(&points);
+    assert_eq!(compressed.verify_integrity(), Ok(()));
+    let (flat, _) = builder().flat(true).build(&points);
+    assert_eq!(flat.verify_integrity(), Ok(()));
+
+    // Point the first link of every zero layer node past the end of the index. The zero layer
+    // follows the format version, `ef_search`, the points, the input indexes and the `Nodes`
+    // variant and length, and holds 64 links for every node.
+    let mut bytes = bincode::serialize(&hnsw).unwrap();
+    let stored = hnsw.iter().map(|(_, &point)| point).collect::<Vec<_>>();
+    let original = (0..1024)
+        .map(|i| hnsw.original_index(hnsw.point_id(i).unwrap()) as u32)
+        .collect::<Vec<_>>();
+    let start = 8
+        + 8
+        + bincode::serialized_size(&stored).unwrap() as usize
+        + bincode::serialized_size(&original).unwrap() as usize
+        + 4
+        + 8;
+    for i in 0..1024 {
+        let offset = start + i * 64 * 4;
+        bytes[offset..offset + 4].copy_from_slice(&(2000 + i as u32).to_le_bytes());
+    }
+
+    let corrupt = bincode::deserialize::<Hnsw<Point>>(&bytes).unwrap();
+    let found = corrupt.verify_integrity().unwrap_err();
+    assert_eq!(found.len(), 1024);
+    assert!(matches!(
+        found[0],
+        Corruption::InvalidLink { layer: 0, pid, neighbor }
+            if pid.into_inner() == 0 && neighbor.into_inner() == 2000
+    ));
+
+    // Searches skip the invalid links and still find most of the nearest points
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let expected = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(search.invalid_links(), 0);
+    search.set_skip_invalid_links(true);
+    let results = corrupt.search(&query, &mut search).collect::<Vec<_>>();
+    assert!(search.invalid_links() > 0);
+    let hits = results.iter().filter(|c| expected.contains(c)).count();
+    assert!(
+        hits * 10 >= expected.len() * 9,
+        "found {} of {}",
+        hits,
+        expected.len()
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn checkpoint_resume() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let mut saved = Vec::new();
+    let (_, expected) = builder().build_checkpointed(&points, 100, |cp| {
+        assert_eq!(cp.total(), points.len());
+        saved.push((cp.inserted(), bincode::serialize(cp).unwrap()));
+    });
+    let inserted = saved.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+    assert_eq!(inserted, (1..11).map(|i| i * 100 + 1).collect::<Vec<_>>());
+
+    let checkpoint =
+        bincode::deserialize::<instant_distance::Checkpoint<Point>>(&saved[4].1).unwrap();
+    let mut more = 0;
+    let (hnsw, pids) = builder().resume(checkpoint, 100, |_| more += 1);
+    assert_eq!(pids, expected);
+    assert_eq!(more, 5);
+
+    let hits = nearest_hits(&hnsw, &mut rng);
+    assert!(hits >= 18, "recall too low: {}/20", hits);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn finalize() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().max_connections_0(24).build(&points);
+    let size = bincode::serialized_size(&hnsw).unwrap();
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let expected = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+
+    let finalized = hnsw.finalize();
+    let compact = bincode::serialized_size(&finalized).unwrap();
+    assert!(
+        compact * 4 < size,
+        "{} bytes finalized, {} before",
+        compact,
+        size
+    );
+    let found = finalized.search(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn exact_search() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, pids) = builder().build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let mut nearest = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (OrderedFloat::from(query.distance(p)), pids[i]))
+        .collect::<Vec<_>>();
+    nearest.sort_unstable();
+
+    let found = hnsw.exact_search(&query, 10);
+    assert_eq!(found.len(), 10);
+    for (candidate, (distance, pid)) in found.iter().zip(&nearest) {
+        assert_eq!(candidate.pid, *pid);
+        assert_eq!(candidate.distance(), distance.into_inner());
+    }
+}
+
+#[test]
+fn evaluate() {
+    let (mut rng, points, builder) = seeded(1024);
+    let queries = (0..10)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    let (hnsw, _) = builder().build(&points);
+    let truth = queries
+        .iter()
+        .map(|query| {
+            hnsw.exact_search(query, 10)
+                .into_iter()
+                .map(|candidate| candidate.pid)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let report = hnsw.evaluate(&queries, &truth, 10, 100);
+    assert!(report.mean_recall > 0.9);
+    assert!(report.min_recall <= report.mean_recall && report.mean_recall <= report.max_recall);
+    let (low, high) = report.recall_ci;
+    assert!(0.0 <= low && low <= report.mean_recall);
+    assert!(report.mean_recall <= high && high <= 1.0);
+    assert!(report.qps > 0.0);
+
+    // The Wilson interval for 0 hits out of 10 is 0 to 0.278
+    let wrong = vec![vec![PointId::default()]; queries.len()];
+    let report = hnsw.evaluate(&queries, &wrong, 10, 100);
+    assert_eq!(report.max_recall, 0.0);
+    let (low, high) = report.recall_ci;
+    assert_eq!(low, 0.0);
+    assert!((high - 0.278).abs() < 0.001, "{}", high);
+
+    // Equal recalls still leave some uncertainty: for 10 hits out of 10, 0.722 to 1
+    let easy = (0..10)
+        .map(|i| hnsw.exact_search(&points[i], 10))
+        .map(|found| found.into_iter().map(|c| c.pid).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let report = hnsw.evaluate(&points[..1], &easy[..1], 10, 100);
+    assert_eq!(report.mean_recall, 1.0);
+    let (low, high) = report.recall_ci;
+    assert!((low - 0.722).abs() < 0.001, "{}", low);
+    assert_eq!(high, 1.0);
+
+    // With more queries, the interval narrows
+    let report = hnsw.evaluate(&points[..10], &easy, 10, 100);
+    assert_eq!(report.mean_recall, 1.0);
+    assert!(report.recall_ci.0 > 0.96 && report.recall_ci.1 == 1.0);
+
+    let report = hnsw.evaluate(&queries, &vec![Vec::new(); queries.len()], 10, 100);
+    assert_eq!(report.recall_ci, (0.0, 1.0));
+}
+
+#[test]
+fn flat() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, pids) = builder().ef_search(20).flat(true).build(&points);
+    assert!(hnsw.is_flat());
+    let raw = pids.iter().map(|pid| pid.into_inner()).collect::<Vec<_>>();
+    assert_eq!(raw, (0..1024).collect::<Vec<_>>());
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let found = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(found, hnsw.exact_search(&query, 20));
+
+    search.set_min_distance(Some(found[4].distance()));
+    let beyond = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(&beyond[..16], &found[4..]);
+}
+
+#[test]
+fn max_distance() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let all = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+
+    let max = all[20].distance();
+    search.set_max_distance(Some(max));
+    let within = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let expected = all.iter().filter(|c| c.distance() <= max).count();
+    assert_eq!(within, all[..expected]);
+
+    // With `k`, at most `k` results are returned, all within the maximum distance
+    search.set_k(Some(10));
+    assert_eq!(hnsw.search(&query, &mut search).len(), 10);
+    search.set_k(Some(50));
+    assert_eq!(hnsw.search(&query, &mut search).len(), expected);
+}
+
+#[test]
+fn farthest() {
+    let (mut rng, points, builder) = seeded(1024);
+    let query = Point(rng.gen(), rng.gen());
+
+    let (flat, _) = builder().flat(true).build(&points);
+    let mut exact = flat.exact_search(&query, 1024);
+    exact.reverse();
+    assert_eq!(flat.search_farthest(&query, 10), &exact[..10]);
+
+    let (hnsw, _) = builder().build(&points);
+    let found = hnsw.search_farthest(&query, 10);
+    assert_eq!(found.len(), 10);
+    assert!(found.windows(2).all(|w| w[0].distance() >= w[1].distance()));
+    assert!(found[0].distance() >= 0.9 * exact[0].distance());
+}
+
+#[test]
+fn independent_seeds() {
+    let (_, points, builder) = seeded(1024);
+
+    let build = |layer_seed, shuffle_seed| {
+        let (hnsw, ids) = builder()
+            .layer_seed(layer_seed)
+            .shuffle_seed(shuffle_seed)
+            .build(&points);
+        let layers = ids
+            .iter()
+            .map(|&pid| hnsw.layer_of(pid))
+            .collect::<Vec<_>>();
+        (layers, ids)
+    };
+
+    let (layers, ids) = build(1, 1);
+    assert_eq!(build(1, 1), (layers.clone(), ids.clone()));
+
+    // Changing the shuffle seed changes the insertion order, but not the layer assignment
+    let (reshuffled, reordered) = build(1, 2);
+    assert_eq!(reshuffled, layers);
+    assert_ne!(reordered, ids);
+
+    // Changing the layer seed changes the layer assignment
+    let (relayered, _) = build(2, 1);
+    assert_ne!(relayered, layers);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deterministic_build() {
+    let (_, points, builder) = seeded(2048);
+
+    let build = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let (hnsw, ids) = pool.install(|| builder().deterministic(true).build(&points));
+        (bincode::serialize(&hnsw).unwrap(), ids)
+    };
+
+    let (bytes, ids) = build(1);
+    for threads in [2, 8] {
+        let (other, other_ids) = build(threads);
+        assert!(
+            other == bytes,
+            "index differs when built with {} threads",
+            threads
+        );
+        assert_eq!(other_ids, ids);
+    }
+
+    // Checkpoints and stats callbacks don't change the batches
+    let mut saved = Vec::new();
+    let (hnsw, other_ids) = builder()
+        .deterministic(true)
+        .stats_callback(37, |_| {})
+        .build_checkpointed(&points, 100, |cp| {
+            saved.push(bincode::serialize(cp).unwrap())
+        });
+    assert!(bincode::serialize(&hnsw).unwrap() == bytes);
+    assert_eq!(other_ids, ids);
+
+    let checkpoint =
+        bincode::deserialize::<instant_distance::Checkpoint<Point>>(&saved[5]).unwrap();
+    let (hnsw, other_ids) = builder()
+        .deterministic(true)
+        .resume(checkpoint, 300, |_| {});
+    assert!(bincode::serialize(&hnsw).unwrap() == bytes);
+    assert_eq!(other_ids, ids);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn build_params() {
+    let (_, points, builder) = seeded(1024);
+
+    let (hnsw, ids) = builder()
+        .ef_search(50)
+        .max_connections(12)
+        .min_connections(3)
+        .ml(0.5)
+        .deterministic(true)
+        .build(&points);
+    let params = *hnsw.build_params().unwrap();
+    assert_eq!((params.ef_search, params.ef_construction), (50, 100));
+    assert_eq!((params.max_connections, params.max_connections_0), (12, 24));
+    assert_eq!((params.min_connections, params.ml()), (3, Some(0.5)));
+    assert!(params.deterministic && !params.flat);
+
+    // The parameters are not part of the serialized index, but they can be stored separately
+    // and restored
+    let bytes = bincode::serialize(&hnsw).unwrap();
+    let mut loaded = bincode::deserialize::<Hnsw<Point>>(&bytes).unwrap();
+    assert!(loaded.build_params().is_none());
+    assert_eq!(bincode::serialize(&loaded).unwrap(), bytes);
+    let stored = bincode::serialize(&params).unwrap();
+    loaded.set_build_params(bincode::deserialize(&stored).unwrap());
+    assert_eq!(loaded.build_params().unwrap().seed, params.seed);
+
+    // The parameters reproduce the index
+    let (rebuilt, rebuilt_ids) = params.builder().build(&points);
+    assert_eq!(rebuilt_ids, ids);
+    assert_eq!(bincode::serialize(&rebuilt).unwrap(), bytes);
+
+    let mut dump = Vec::new();
+    hnsw.dump_graph_only(&mut dump).unwrap();
+    let graph = Hnsw::load_graph_only(&dump[..]).unwrap();
+    assert!(graph.build_params().is_none());
+}
+
+#[test]
+fn build_output() {
+    let (_, points, builder) = seeded(256);
+
+    // Parallel builds only produce the same graph when they are deterministic
+    let output = builder().deterministic(true).build_output(&points);
+    for (i, point) in points.iter().enumerate() {
+        let pid = output.pid(i);
+        assert_eq!(output.original_index(pid), i);
+        assert_eq!(&output[pid], point);
+    }
+
+    let mut search = Search::default();
+    let nearest = output.search(&points[3], &mut search).next().unwrap();
+    assert_eq!(nearest.pid, output.pid(3));
+
+    let (hnsw, ids) = output.into_parts();
+    let (expected, expected_ids) = builder().deterministic(true).build(&points);
+    assert_eq!(ids, expected_ids);
+    assert_eq!(hnsw.export_adjacency(), expected.export_adjacency());
+    let output = BuildOutput::from((hnsw, ids));
+    assert_eq!(output.ids.len(), points.len());
+}
+
+#[test]
+fn keyed_upsert() {
+    let (mut rng, builder) = seeded_builder();
+    let entries = (0..256u32)
+        .map(|i| (i, Point(rng.gen(), rng.gen()), i))
+        .collect::<Vec<_>>();
+
+    let mut keyed = builder().build_keyed(entries);
+    let mut search = Search::default();
+    search.set_k(Some(1));
+    let events = [(7, Point(0.25, 0.75), 1000), (300, Point(0.75, 0.25), 1001)];
+    let mut pids = Vec::new();
+    // Replaying the events leaves the index in the same state
+    for _ in 0..2 {
+        pids.extend(
+            events
+                .iter()
+                .map(|&(key, point, value)| keyed.upsert(key, point, value, &mut search).unwrap()),
+        );
+        assert_eq!(keyed.len(), 257);
+        for &(key, point, value) in &events {
+            assert_eq!(keyed.get(&key), Some((&point, &value)));
+            let found = keyed.search(&point, &mut search).next().unwrap();
+            assert_eq!((*found.0, *found.1, found.2), (key, value, 0.0));
+        }
+    }
+    assert_eq!(pids[..2], pids[2..]);
+}
+
+#[test]
+fn keyed() {
+    let (mut rng, builder) = seeded_builder();
+    let mut entries = (0..1024u32)
+        .map(|i| (format!("point-{}", i), Point(rng.gen(), rng.gen()), i))
+        .collect::<Vec<_>>();
+    // Replaces the first entry
+    entries.push(("point-0".to_owned(), Point(0.5, 0.5), 1024));
+
+    let keyed = builder().build_keyed(entries);
+    assert_eq!(keyed.len(), 1024);
+    assert!(keyed.contains_key(&"point-1".to_owned()));
+    assert!(!keyed.contains_key(&"point-1024".to_owned()));
+    let (point, &value) = keyed.get(&"point-0".to_owned()).unwrap();
+    assert_eq!((point.0, point.1, value), (0.5, 0.5, 1024));
+
+    let mut search = Search::default();
+    search.set_k(Some(1));
+    let (key, &value, distance) = keyed.search(&Point(0.5, 0.5), &mut search).next().unwrap();
+    assert_eq!((key.as_str(), value, distance), ("point-0", 1024, 0.0));
+
+    // Every key maps to the value it was built with
+    for (key, _, value) in keyed.iter() {
+        assert_eq!(key, &format!("point-{}", value % 1024));
+    }
+
+    #[cfg(feature = "serde")]
+    {
+        let bytes = bincode::serialize(&keyed).unwrap();
+        let loaded = bincode::deserialize::<KeyedHnsw<String, Point, u32>>(&bytes).unwrap();
+        assert_eq!(loaded.get(&"point-0".to_owned()).unwrap().1, &1024);
+        let mut search = Search::default();
+        let found = loaded.search(&Point(0.5, 0.5), &mut search).next().unwrap();
+        assert_eq!(found.0, "point-0");
+    }
+}
+
+#[test]
+fn thread_pool() {
+    static MISMATCHED: AtomicBool = AtomicBool::new(false);
+
+    // Records whether distances were ever computed outside of a 3-thread pool
+    #[derive(Clone)]
+    struct Checked(Point);
+
+    impl instant_distance::Point for Checked {
+        fn distance(&self, other: &Self) -> f32 {
+            if rayon::current_num_threads() != 3 {
+                MISMATCHED.store(true, Ordering::Relaxed);
+            }
+            self.0.distance(&other.0)
+        }
+    }
+
+    let (mut rng, builder) = seeded_builder();
+    let points = (0..1024)
+        .map(|_| Checked(Point(rng.gen(), rng.gen())))
+        .collect::<Vec<_>>();
+
+    for deterministic in [false, true] {
+        let (hnsw, _) = builder()
+            .entry_point(EntryStrategy::Medoid { sample: 64 })
+            .deterministic(deterministic)
+            .threads(3)
+            .build(&points);
+        assert_eq!(hnsw.iter().count(), 1024);
+    }
+    assert!(!MISMATCHED.load(Ordering::Relaxed));
+}
+
+#[test]
+fn search_relative() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let all = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let band = hnsw
+        .search_relative(&query, 1.5, &mut search)
+        .collect::<Vec<_>>();
+
+    let limit = all[0].distance() * 1.5;
+    let expected = all.iter().filter(|c| c.distance() <= limit).count();
+    assert!(expected < all.len());
+    assert_eq!(band, all[..expected]);
+
+    // A factor of 1 only keeps results tied with the nearest
+    let nearest = hnsw.search_relative(&query, 1.0, &mut search);
+    assert_eq!(nearest.len(), 1);
+}
+
+#[test]
+fn min_connections() {
+    // A dense cluster with sparse outliers, whose links the heuristic prunes aggressively
+    let (mut rng, builder) = seeded_builder();
+    let mut points = (0..2000)
+        .map(|_| Point(rng.gen_range(0.45..0.55), rng.gen_range(0.45..0.55)))
+        .collect::<Vec<_>>();
+    points.extend((0..100).map(|_| Point(rng.gen_range(0.0..10.0), rng.gen_range(0.0..10.0))));
+
+    let heuristic = Heuristic {
+        extend_candidates: false,
+        keep_pruned: false,
+    };
+    let pruning = || builder().select_heuristic(Some(heuristic));
+    let min_degree = |hnsw: &Hnsw<Point>| {
+        let adjacency = hnsw.export_adjacency();
+        adjacency
+            .iter()
+            .map(|neighbors| neighbors.len())
+            .min()
+            .unwrap()
+    };
+
+    let (mut pruned, _) = pruning().build(&points);
+    assert!(min_degree(&pruned) < 6);
+    let (mut hnsw, _) = pruning().min_connections(6).build(&points);
+    assert!(min_degree(&hnsw) >= 6);
+
+    // Points added or moved later are linked with the same heuristic and minimum
+    let mut search = Search::default();
+    for _ in 0..20 {
+        let outlier = Point(rng.gen_range(0.0..10.0), rng.gen_range(0.0..10.0));
+        let pid = pruned.append(outlier, &mut search).unwrap();
+        assert!(pruned.export_adjacency()[pid.into_inner() as usize].len() < 32);
+        hnsw.append(outlier, &mut search).unwrap();
+    }
+    let pid = hnsw.point_id(5).unwrap();
+    assert!(min_degree(&hnsw) >= 6);
+    hnsw.update(pid, Point(-5.0, -5.0), &mut search).unwrap();
+    assert!(hnsw.export_adjacency()[5].len() >= 6);
+
+    // Without pruning, neighbor lists are filled up anyway
+    let (full, _) = builder().min_connections(6).build(&points);
+    assert!(min_degree(&full) > 6);
+}
+
+#[test]
+fn require_connected() {
+    let (_, points, builder) = seeded(1024);
+    let (hnsw, _) = builder()
+        .require_connected(true)
+        .try_build(&points)
+        .unwrap();
+    assert!(hnsw.unreachable().is_empty());
+
+    // Duplicates fill up the single neighbor slot, so most points are unreachable
+    let mut points = vec![Point(0.0, 0.0); 16];
+    points.extend(vec![Point(1.0, 1.0); 16]);
+    let sparse = || builder().max_connections(1).max_connections_0(1);
+
+    let (hnsw, _) = sparse().try_build(&points).unwrap();
+    let unreachable = hnsw.unreachable();
+    assert!(!unreachable.is_empty());
+
+    let error = sparse()
+        .require_connected(true)
+        .try_build(&points)
+        .err()
+        .unwrap();
+    assert_eq!(error.unreachable, unreachable);
+}
+
+#[test]
+fn filtered_search() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, ids) = builder().build(&points);
+    let categories = (0..1024).map(|i| i % 4).collect::<Vec<u32>>();
+    let timestamps = (0..1024).map(|i| i as i64 * 10).collect::<Vec<i64>>();
+    let flags = (0..1024).map(|i| i % 3 == 0).collect::<Vec<bool>>();
+    let columns = vec![
+        Column::Category(categories.clone()),
+        Column::Int(timestamps.clone()),
+        Column::Flag(flags.clone()),
+    ];
+    let attributes = Attributes::new(columns, &ids);
+
+    let conditions = [
+        Condition::Equals {
+            column: 0,
+            value: 1,
+        },
+        Condition::Range {
+            column: 1,
+            min: 1000,
+            max: 8000,
+        },
+        Condition::Equals {
+            column: 2,
+            value: 0,
+        },
+    ];
+    let matches =
+        |i: usize| categories[i] == 1 && (1000..=8000).contains(&timestamps[i]) && !flags[i];
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut nearest = (0..points.len())
+        .filter(|&i| matches(i))
+        .map(|i| (OrderedFloat::from(query.distance(&points[i])), i))
+        .collect::<Vec<_>>();
+    nearest.sort_unstable();
+    let expected = nearest
+        .iter()
+        .take(10)
+        .map(|&(_, i)| ids[i])
+        .collect::<HashSet<_>>();
+
+    let mut search = Search::default();
+    search.set_k(Some(10));
+    let results = hnsw
+        .search_filtered(&query, &attributes, &conditions, &mut search)
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 10);
+    for candidate in &results {
+        assert!(matches(hnsw.original_index(candidate.pid)));
+    }
+
+    let found = results.iter().map(|c| c.pid).collect::<HashSet<_>>();
+    assert!(found.intersection(&expected).count() >= 9);
+
+    // Points appended after creating the attributes have no values, so they never match
+    let mut appended = hnsw.clone();
+    let pid = appended.append(query, &mut Search::default()).unwrap();
+    let results = appended
+        .search_filtered(&query, &attributes, &conditions, &mut search)
+        .map(|c| c.pid)
+        .collect::<Vec<_>>();
+    assert!(!results.contains(&pid));
+    assert!(!attributes.matches(pid, &conditions[..1]));
+
+    let (flat, ids) = builder().flat(true).build(&points);
+    let attributes = Attributes::new(
+        vec![
+            Column::Category(categories),
+            Column::Int(timestamps),
+            Column::Flag(flags),
+        ],
+        &ids,
+    );
+    let results = flat.search_filtered(&query, &attributes, &conditions, &mut search);
+    let found = results
+        .map(|c| flat.original_index(c.pid))
+        .collect::<Vec<_>>();
+    let expected = nearest.iter().take(10).map(|&(_, i)| i).collect::<Vec<_>>();
+    assert_eq!(found, expected);
+}
+
+#[test]
+fn graph_only_round_trip() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, ids) = builder().compress_graph(true).build(&points);
+    let mut dump = Vec::new();
+    hnsw.dump_graph_only(&mut dump).unwrap();
+    let graph = Hnsw::load_graph_only(&dump[..]).unwrap();
+
+    assert_eq!(graph.export_adjacency(), hnsw.export_adjacency());
+    assert_eq!(graph.connected_components(), hnsw.connected_components());
+    for (i, &pid) in ids.iter().enumerate() {
+        assert_eq!(graph.layer_of(pid), hnsw.layer_of(pid));
+        assert_eq!(graph.original_index(pid), i);
+    }
+
+    let mut dot = Vec::new();
+    graph.export_dot(&mut dot, 16).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.contains(" -> ") && !dot.contains("label=\"0."));
+
+    let restored = graph.map_points(|pid, ()| hnsw[pid]);
+    let query = Point(rng.gen(), rng.gen());
+    let (mut expected, mut found) = (Search::default(), Search::default());
+    let expected = hnsw.search(&query, &mut expected).collect::<Vec<_>>();
+    let found = restored.search(&query, &mut found).collect::<Vec<_>>();
+    assert_eq!(found, expected);
+
+    assert!(Hnsw::load_graph_only(&dump[1..]).is_err());
+    assert!(Hnsw::load_graph_only(&dump[..dump.len() - 1]).is_err());
+}
+
+#[test]
+fn warnings() {
+    let (_, mut points, builder) = seeded(1024);
+    points.extend_from_within(..10);
+
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let sink = warnings.clone();
+    let _ = builder()
+        .ef_search(200)
+        .ef_construction(50)
+        .on_warning(move |warning| sink.lock().unwrap().push(warning))
+        .build(&points);
+
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(
+        warnings[0],
+        Warning::LowEfConstruction {
+            ef_construction: 50,
+            ef_search: 200
+        }
+    );
+    assert!(warnings.contains(&Warning::DuplicatePoints { count: 20 }));
+}
+
+#[test]
+fn rebuild_with() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, ids) = builder().build(&points);
+    let (rebuilt, remap) = hnsw.rebuild_with(builder().ef_construction(50).max_connections(8));
+    assert_eq!(remap.len(), points.len());
+    for (i, &pid) in ids.iter().enumerate() {
+        let new = remap[pid.into_inner() as usize];
+        assert_eq!(rebuilt[new], points[i]);
+        assert_eq!(rebuilt.original_index(new), i);
+    }
+
+    let hits = nearest_hits(&rebuilt, &mut rng);
+    assert!(hits >= 18, "recall too low: {}/20", hits);
+}
+
+#[test]
+fn distances_to() {
+    let (mut rng, points, builder) = seeded(256);
+
+    let (hnsw, ids) = builder().build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let targets = [ids[3], ids[0], ids[3], ids[200]];
+    let expected = [3, 0, 3, 200].map(|i| query.distance(&points[i]));
+    assert_eq!(hnsw.distances_to(&query, &targets), expected);
+    assert!(hnsw.distances_to(&query, &[]).is_empty());
+}
+
+#[test]
+fn dedup_epsilon() {
+    let (mut rng, builder) = seeded_builder();
+    let mut points = Vec::new();
+    for _ in 0..256 {
+        let center = Point(rng.gen(), rng.gen());
+        for _ in 0..4 {
+            let mut jitter = || rng.gen_range(-0.0001..0.0001);
+            points.push(Point(center.0 + jitter(), center.1 + jitter()));
+        }
+    }
+
+    let (hnsw, _) = builder().ef_search(16).build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    search.set_k(Some(10));
+    let clusters = |pids: &[PointId]| {
+        let clusters = pids.iter().map(|&pid| hnsw.original_index(pid) / 4);
+        clusters.collect::<HashSet<_>>().len()
+    };
+
+    let pids = hnsw.search_ids(&query, &mut search).collect::<Vec<_>>();
+    assert!(clusters(&pids) < 10);
+
+    search.set_dedup_epsilon(Some(0.001));
+    let results = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let pids = results.iter().map(|c| c.pid).collect::<Vec<_>>();
+    assert_eq!(pids.len(), 10);
+    assert_eq!(clusters(&pids), 10);
+    assert!(results
+        .windows(2)
+        .all(|w| w[0].distance() <= w[1].distance()));
+}
+
+#[test]
+fn k_distance() {
+    let (mut rng, points, builder) = seeded(512);
+
+    let (hnsw, ids) = builder().build(&points);
+    let mut search = Search::default();
+    // Searches can only miss neighbors, so the k-distance is never underestimated
+    let mut hits = 0;
+    for _ in 0..20 {
+        let query = Point(rng.gen(), rng.gen());
+        let exact = hnsw.exact_search(&query, 5)[4].distance();
+        let found = hnsw.k_distance(&query, 5, &mut search).unwrap();
+        assert!(found >= exact);
+        hits += (found == exact) as usize;
+    }
+    assert!(hits >= 18, "recall too low: {}/20", hits);
+    assert_eq!(hnsw.k_distance(&points[0], 1000, &mut search), None);
+
+    let distances = hnsw.k_distances(3);
+    assert_eq!(distances.len(), points.len());
+    let mut hits = 0;
+    for (i, &pid) in ids.iter().enumerate().take(32) {
+        // The nearest point is the point itself, which doesn't count
+        let exact = hnsw.exact_search(&points[i], 4)[3].distance();
+        let found = distances[pid.into_inner() as usize];
+        assert!(found >= exact);
+        hits += (found == exact) as usize;
+    }
+    assert!(hits >= 29, "recall too low: {}/32", hits);
+}
+
+#[test]
+fn count_within_radius() {
+    let (_, points, builder) = seeded(4096);
+
+    let (hnsw, _) = builder().build(&points);
+    let query = Point(0.5, 0.5);
+    let exact = |radius| {
+        points
+            .iter()
+            .filter(|p| query.distance(p) <= radius)
+            .count()
+    };
+    let mut search = Search::default();
+    for radius in [0.001, 0.01, 0.05, 0.1, 0.2] {
+        let count = hnsw.count_within_radius(&query, radius, &mut search);
+        let expected = exact(radius);
+        assert!(count <= expected && count * 10 >= expected * 9);
+        assert!(search.is_empty());
+
+        // All search results within the radius are counted
+        let found = hnsw.search(&query, &mut search);
+        assert!(count >= found.filter(|c| c.distance() <= radius).count());
+    }
+
+    let nearest = hnsw.exact_search(&query, 1)[0].distance();
+    assert_eq!(
+        hnsw.count_within_radius(&query, nearest / 2.0, &mut search),
+        0
+    );
+
+    let (flat, _) = builder().flat(true).build(&points);
+    assert_eq!(
+        flat.count_within_radius(&query, 0.1, &mut search),
+        exact(0.1)
+    );
+}
+
+#[test]
+fn connected_components() {
+    let (_, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().build(&points);
+    let components = hnsw.connected_components();
+    assert!(components.windows(2).all(|w| w[0].len() >= w[1].len()));
+    assert!(components.iter().all(|c| c.windows(2).all(|w| w[0] < w[1])));
+    assert!(
+        components[0].len() * 100 >= points.len() * 99,
+        "largest component has {} points",
+        components[0].len()
+    );
+
+    // Every point is in exactly one component
+    let mut all = components.concat();
+    all.sort_unstable();
+    let raw = all.iter().map(|pid| pid.into_inner());
+    assert_eq!(raw.collect::<Vec<_>>(), (0..1024).collect::<Vec<_>>());
+
+    let (flat, _) = builder().flat(true).build(&points);
+    assert_eq!(flat.connected_components(), vec![all]);
+}
+
+#[test]
+fn export_dot() {
+    let (_, points, builder) = seeded(256);
+    let (hnsw, _) = builder().build(&points);
+
+    let mut dot = Vec::new();
+    hnsw.export_dot(&mut dot, 32).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.starts_with("digraph hnsw {"));
+    assert_eq!(dot.lines().filter(|l| l.contains("fillcolor")).count(), 32);
+    assert!(dot.lines().any(|l| l.contains(" -> ")));
+    for line in dot.lines().filter(|l| l.contains(" -> ")) {
+        let mut ids = line
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|s| !s.is_empty());
+        assert!(ids.next().unwrap().parse::<usize>().unwrap() < 32);
+        assert!(ids.next().unwrap().parse::<usize>().unwrap() < 32);
+    }
+}
+
+#[test]
+fn search_ids() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let mut search = Search::default();
+    let query = Point(rng.gen(), rng.gen());
+    let expected = hnsw
+        .search(&query, &mut search)
+        .map(|candidate| candidate.pid)
+        .collect::<Vec<_>>();
+    let ids = hnsw.search_ids(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(ids, expected);
+}
+
+#[test]
+fn visited_set() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let mut search = Search::default();
+    let mut hashed = Search::with_visited_set(VisitedSet::HashSet);
+    for _ in 0..10 {
+        let query = Point(rng.gen(), rng.gen());
+        let expected = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+        let found = hnsw.search(&query, &mut hashed).collect::<Vec<_>>();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn search_traced() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let mut search = Search::default();
+    let query = Point(rng.gen(), rng.gen());
+    let traced = hnsw.search_traced(&query, &mut search);
+    let plain = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let results = traced.results.iter().map(|(c, _)| *c).collect::<Vec<_>>();
+    assert_eq!(results, plain);
+    for &(candidate, layer) in &traced.results {
+        assert!(layer <= hnsw.layer_of(candidate.pid));
+    }
+
+    // The search starts at the entry point on the top layer, and stops once on every layer
+    let entry = hnsw.point_id(0).unwrap();
+    let top = hnsw.layer_of(entry);
+    assert!(matches!(
+        traced.steps[0],
+        TraceStep::Visit { layer, pid, .. } if layer == top && pid == entry
+    ));
+    let stops = traced
+        .steps
+        .iter()
+        .filter_map(|step| match *step {
+            TraceStep::Stop { layer, .. } => Some(layer),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(stops, (0..=top).rev().collect::<Vec<_>>());
+
+    // Nodes are visited (on the same layer or a layer above) before they are expanded
+    let mut visited = HashSet::new();
+    for step in &traced.steps {
+        match *step {
+            TraceStep::Visit { pid, .. } => {
+                visited.insert(pid);
+            }
+            TraceStep::Expand { pid, .. } => assert!(visited.contains(&pid)),
+            TraceStep::Stop { .. } => {}
+        }
+    }
+}
+
+#[test]
+fn concurrent_search() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Hnsw<Point>>();
+
+    let (mut rng, points, builder) = seeded(1024);
+    let queries = (0..64)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    let (hnsw, _) = builder().build(&points);
+    let mut search = Search::default();
+    let expected = queries
+        .iter()
+        .map(|query| hnsw.search(query, &mut search).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let (hnsw, queries, expected) = (Arc::new(hnsw), Arc::new(queries), Arc::new(expected));
+    let threads = (0..8)
+        .map(|_| {
+            let (hnsw, queries, expected) = (hnsw.clone(), queries.clone(), expected.clone());
+            thread::spawn(move || {
+                let mut search = Search::default();
+                for _ in 0..10 {
+                    for (query, expected) in queries.iter().zip(expected.iter()) {
+                        let found = hnsw.search(query, &mut search).collect::<Vec<_>>();
+                        assert_eq!(&found, expected);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn reuse_search() {
+    let (mut rng, builder) = seeded_builder();
+    let mut random = |n| {
+        (0..n)
+            .map(|_| Point(rng.gen(), rng.gen()))
+            .collect::<Vec<_>>()
+    };
+
+    let (small, _) = builder().build(&random(64));
+    let (large, _) = builder().build(&random(2048));
+    let queries = random(10);
+
+    let mut search = Search::default();
+    for (i, query) in queries.iter().enumerate() {
+        let hnsw = if i % 2 == 0 { &small } else { &large };
+        let found = hnsw.search(query, &mut search).collect::<Vec<_>>();
+        let expected = hnsw
+            .search(query, &mut Search::default())
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+    }
+
+    search.reset();
+    assert!(search.is_empty());
+}
+
+#[test]
+fn search_capacity() {
+    let (mut rng, points, builder) = seeded(2048);
+    let (hnsw, _) = builder().build(&points);
+
+    let mut dense = Search::with_capacity(100, 64);
+    let mut hashed = Search::with_capacity(100, 64);
+    hashed.set_visited_set(VisitedSet::HashSet);
+    let capacity = dense.capacity();
+    assert!(capacity >= 164);
+    for _ in 0..10 {
+        let query = Point(rng.gen(), rng.gen());
+        let expected = hnsw
+            .search(&query, &mut Search::default())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hnsw.search(&query, &mut dense).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            hnsw.search(&query, &mut hashed).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    // Neither search had to grow its buffers
+    assert_eq!(dense.capacity(), capacity);
+    assert_eq!(hashed.capacity(), capacity);
+}
+
+#[test]
+fn build_flat() {
+    let (mut rng, builder) = seeded_builder();
+    let data = (0..2048).map(|_| rng.gen()).collect::<Vec<f32>>();
+    let (hnsw, pids) = builder().build_flat::<Point>(&data, 2);
+
+    assert_eq!(pids.len(), 1024);
+    for (pid, row) in pids.iter().zip(data.chunks(2)) {
+        assert_eq!(hnsw[*pid], Point(row[0], row[1]));
+    }
+}
+
+#[test]
+fn export_vectors() {
+    #[derive(Clone)]
+    struct Row([f32; 2]);
+
+    impl instant_distance::Point for Row {
+        fn distance(&self, other: &Self) -> f32 {
+            Point(self.0[0], self.0[1]).distance(&Point(other.0[0], other.0[1]))
+        }
+    }
+
+    impl AsRef<[f32]> for Row {
+        fn as_ref(&self) -> &[f32] {
+            &self.0
+        }
+    }
+
+    let (mut rng, builder) = seeded_builder();
+    let data = (0..2048).map(|_| rng.gen()).collect::<Vec<f32>>();
+    let (hnsw, pids) = builder().build_flat::<Point>(&data, 2);
+
+    let adjacency = hnsw.export_adjacency();
+    assert_eq!(adjacency.len(), 1024);
+    for (i, neighbors) in adjacency.iter().enumerate() {
+        assert!(!neighbors.is_empty());
+        assert!(!neighbors.contains(&hnsw.point_id(i as u32).unwrap()));
+        assert!(neighbors.iter().all(|&pid| hnsw.contains(pid)));
+    }
+
+    let vectors = hnsw.map_points(|_, p| Row([p.0, p.1])).export_vectors();
+    assert_eq!(vectors.len(), data.len());
+    for (pid, row) in pids.iter().zip(data.chunks(2)) {
+        let i = pid.into_inner() as usize;
+        assert_eq!(&vectors[i * 2..i * 2 + 2], row);
+    }
+}
+
+#[cfg(feature = "testdata")]
+#[test]
+fn testdata() {
+    use instant_distance::testdata;
+
+    let uniform = testdata::uniform(1024, 2, 1);
+    assert_eq!(uniform, testdata::uniform(1024, 2, 1));
+    assert_ne!(uniform, testdata::uniform(1024, 2, 2));
+    assert!(uniform.iter().all(|&x| (0.0..1.0).contains(&x)));
+
+    let clustered = testdata::clustered(1024, 2, 8, 1);
+    assert_eq!(clustered, testdata::clustered(1024, 2, 8, 1));
+    let (hnsw, _) = Builder::default().build_flat::<Point>(&clustered, 2);
+    let query = Point(clustered[0], clustered[1]);
+    let mut search = Search::default();
+    let nearest = hnsw.search(&query, &mut search).next().unwrap();
+    assert_eq!(nearest.distance(), 0.0);
+}
+
+#[test]
+fn random_layer_distributions() {
+    for distribution in &[
+        LayerDistribution::Capped {
+            ml: 0.5,
+            max_layers: 2,
+        },
+        LayerDistribution::Uniform { layers: 3 },
+    ] {
+        let (seed, recall) = randomized(Builder::default().layer_distribution(*distribution));
+        println!("{:?} (seed = {}) recall = {}", distribution, seed, recall);
+        assert!(recall > 97, "expected at least 98, got {}", recall);
+    }
+}
+
+#[test]
+fn stable_result_order() {
+    // Points on a coarse grid, so that many of them are at the same distance from the query
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32 % 8) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().seed(3).build(&points);
+
+    let query = Point(15.5, 3.5);
+    let mut search = Search::default();
+    let first = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    assert!(first
+        .windows(2)
+        .all(|w| (w[0].distance(), w[0].pid) < (w[1].distance(), w[1].pid)));
+
+    let again = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let fresh = hnsw
+        .search(&query, &mut Search::default())
+        .collect::<Vec<_>>();
+    assert_eq!(again, first);
+    assert_eq!(fresh, first);
+}
+
+#[test]
+fn layer_assignment() {
+    let (_, points, builder) = seeded(4096);
+
+    let (hnsw, pids) = builder().ml(0.25).build(&points);
+    assert!(pids.iter().all(|&pid| hnsw.contains(pid)));
+    assert!(hnsw.point_id(4096).is_none());
+
+    let mut counts = vec![0; hnsw.layer_of(hnsw.point_id(0).unwrap()) + 1];
+    for pid in pids {
+        for count in &mut counts[..=hnsw.layer_of(pid)] {
+            *count += 1;
+        }
+    }
+
+    assert_eq!(counts, vec![4096, 1024, 256, 64]);
+
+    // The ids present on each layer match the layer assignment
+    for (layer, &count) in counts.iter().enumerate() {
+        let points = hnsw.layer_points(layer);
+        assert_eq!(points.len(), count);
+        assert!(points.iter().all(|&pid| hnsw.layer_of(pid) >= layer));
+    }
+    assert!(hnsw.layer_points(counts.len()).is_empty());
+}
+
+#[test]
+fn sample() {
+    let (_, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    // Whole upper layers come first, then a random part of the next layer
+    let upper = hnsw.layer_points(1).len();
+    let sample = hnsw.sample(upper + 10, Sampling::Layers, 1);
+    assert_eq!(sample.len(), upper + 10);
+    assert!(sample.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(sample[..upper]
+        .iter()
+        .all(|&(pid, _)| hnsw.layer_of(pid) >= 1));
+    assert!(sample[upper..]
+        .iter()
+        .all(|&(pid, _)| hnsw.layer_of(pid) == 0));
+    assert!(sample.iter().all(|&(pid, point)| *point == hnsw[pid]));
+
+    let top = hnsw.layer_points(hnsw.layer_of(hnsw.point_id(0).unwrap()));
+    let pids =
+        |sample: Vec<(PointId, _)>| sample.into_iter().map(|(pid, _)| pid).collect::<Vec<_>>();
+    assert_eq!(pids(hnsw.sample(top.len(), Sampling::Layers, 2)), top);
+
+    // The seed determines the sample
+    let uniform = pids(hnsw.sample(100, Sampling::Uniform, 3));
+    assert_eq!(uniform.len(), 100);
+    assert_eq!(uniform, pids(hnsw.sample(100, Sampling::Uniform, 3)));
+    assert_ne!(uniform, pids(hnsw.sample(100, Sampling::Uniform, 4)));
+
+    assert_eq!(hnsw.sample(2048, Sampling::Uniform, 5).len(), points.len());
+    assert_eq!(hnsw.sample(2048, Sampling::Layers, 5).len(), points.len());
+}
+
+#[test]
+fn build_stats() {
+    let (_, points, builder) = seeded(1024);
+
+    let snapshots = Arc::new(Mutex::new(Vec::new()));
+    let collected = snapshots.clone();
+    let (hnsw, _) = builder()
+        .stats_callback(100, move |stats| {
+            collected.lock().unwrap().push(stats.clone())
+        })
+        .build(&points);
+
+    let snapshots = snapshots.lock().unwrap();
+    assert_eq!(snapshots.len(), 11);
+    assert!(snapshots.windows(2).all(|w| w[0].inserted < w[1].inserted));
+    for stats in snapshots.iter() {
+        assert_eq!(stats.total, 1024);
+        assert_eq!(stats.layers[0], stats.inserted);
+        assert!(stats.layers.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    let last = snapshots.last().unwrap();
+    assert_eq!(last.inserted, 1024);
+    for (layer, &count) in last.layers.iter().enumerate() {
+        let expected = (0..1024)
+            .filter(|&i| hnsw.layer_of(hnsw.point_id(i).unwrap()) >= layer)
+            .count();
+        assert_eq!(count, expected);
+    }
+}
+
+#[test]
+fn input_order() {
+    let (_, points, builder) = seeded(1024);
+
+    let (hnsw, pids) = builder().order(InsertionOrder::Input).build(&points);
+    let mut last = HashMap::new();
+    for pid in pids {
+        if let Some(prev) = last.insert(hnsw.layer_of(pid), pid) {
+            assert!(prev < pid);
+        }
+    }
+}
+
+#[test]
+fn medoid_entry_point() {
+    let (mut rng, points, builder) = seeded(256);
+
+    let total = |p: &Point| points.iter().map(|q| p.distance(q)).sum::<f32>();
+    let min = points.iter().map(total).fold(f32::INFINITY, f32::min);
+
+    // Sums over a different order may round differently, so allow for near-ties
+    let strategy = EntryStrategy::Medoid { sample: 256 };
+    let (hnsw, _) = builder().entry_point(strategy).build(&points);
+    assert!(total(&hnsw[hnsw.point_id(0).unwrap()]) <= min * 1.0001);
+
+    let hits = nearest_hits(&hnsw, &mut rng);
+    assert!(hits >= 18, "recall too low: {}/20", hits);
+}
+
+#[test]
+fn original_index() {
+    let (_, points, builder) = seeded(1024);
+
+    let (hnsw, pids) = builder().build(&points);
+    for (i, pid) in pids.into_iter().enumerate() {
+        assert_eq!(hnsw.original_index(pid), i);
+        assert_eq!(hnsw[pid], points[i]);
+        assert_eq!(hnsw.point_id(pid.into_inner()), Some(pid));
+    }
+
+    assert_eq!(hnsw.point_id(1024), None);
+    assert_eq!(hnsw.point_id(u32::MAX), None);
+}
+
+#[test]
+fn update() {
+    let (_, points, builder) = seeded(1024);
+
+    let (mut hnsw, pids) = builder().max_connections_0(16).build(&points);
+    let mut search = Search::default();
+    for (i, &target) in [Point(5.0, 5.0), Point(-3.0, 2.0)].iter().enumerate() {
+        let pid = pids[i * 100];
+        hnsw.update(pid, target, &mut search).unwrap();
+        assert_eq!(hnsw[pid], target);
+        let nearest = hnsw.search(&target, &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+
+        // Only the new neighbors link back to the point, and the build's limits are kept
+        let adjacency = hnsw.export_adjacency();
+        let neighbors = &adjacency[pid.into_inner() as usize];
+        for (j, list) in adjacency.iter().enumerate() {
+            assert!(list.len() <= 16);
+            if list.contains(&pid) {
+                assert!(neighbors.contains(&hnsw.point_id(j as u32).unwrap()));
+            }
+        }
+    }
+
+    // The other points can still be found
+    for &pid in pids.iter().skip(1).step_by(50) {
+        let nearest = hnsw.search(&hnsw[pid], &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+
+    // Compressed graphs are left unchanged
+    let mut finalized = hnsw.finalize();
+    let (pid, point) = (pids[1], finalized[pids[1]]);
+    assert_eq!(
+        finalized.update(pid, Point(9.0, 9.0), &mut search),
+        Err(Compressed)
+    );
+    assert_eq!(finalized[pid], point);
+    assert_eq!(finalized.append(point, &mut search), Err(Compressed));
+    assert_eq!(finalized.iter().count(), points.len());
+}
+
+#[test]
+fn append() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (mut hnsw, pids) = builder().build(&points);
+    let mut search = Search::default();
+    let added = (0..64)
+        .map(|i| {
+            let point = Point(rng.gen(), rng.gen());
+            let pid = hnsw.append(point, &mut search).unwrap();
+            assert_eq!(pid.into_inner(), 1024 + i);
+            assert_eq!(
+                (hnsw.layer_of(pid), hnsw.original_index(pid)),
+                (0, 1024 + i as usize)
+            );
+            (pid, point)
+        })
+        .collect::<Vec<_>>();
+
+    for &(pid, point) in &added {
+        let nearest = hnsw.search(&point, &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+    for &pid in pids.iter().step_by(50) {
+        let nearest = hnsw.search(&hnsw[pid], &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+
+    let (mut flat, _) = builder().flat(true).build(&points);
+    let pid = flat.append(Point(0.5, 0.5), &mut search).unwrap();
+    assert!(flat.is_flat());
+    let nearest = flat.search(&Point(0.5, 0.5), &mut search).next().unwrap();
+    assert_eq!((nearest.pid, nearest.distance()), (pid, 0.0));
+
+    // Empty indexes keep the kind they were built as
+    for flat in [false, true] {
+        let (mut empty, _) = builder().flat(flat).build::<Point>(&[]);
+        for &point in &points[..16] {
+            empty.append(point, &mut search).unwrap();
+        }
+        assert_eq!(empty.is_flat(), flat);
+        assert!(empty.verify_integrity().is_ok());
+        let nearest = empty.search(&points[7], &mut search).next().unwrap();
+        assert_eq!(nearest.pid.into_inner(), 7);
+    }
+}
+
+#[test]
+fn min_distance() {
+    let (_, points, builder) = seeded(1024);
+
+    let (hnsw, pids) = builder().build(&points);
+    let mut search = Search::default();
+    search.set_min_distance(Some(0.05));
+    let mut hits = 0;
+    for (point, &pid) in points.iter().zip(&pids).take(10) {
+        let found = hnsw.search(point, &mut search).collect::<Vec<_>>();
+        assert!(found.iter().all(|candidate| candidate.distance() >= 0.05));
+        assert!(found.iter().all(|candidate| candidate.pid != pid));
+
+        let exact = hnsw
+            .exact_search(point, 1024)
+            .into_iter()
+            .filter(|candidate| candidate.distance() >= 0.05)
+            .take(10)
+            .collect::<Vec<_>>();
+        hits += found[..10].iter().filter(|c| exact.contains(c)).count();
+    }
+    assert!(hits >= 90, "recall too low: {}/100", hits);
+}
+
+#[test]
+fn shared_distances() {
+    #[derive(Clone)]
+    struct Item {
+        idx: usize,
+        distances: Arc<Vec<Vec<f32>>>,
+    }
+
+    impl instant_distance::Point for Item {
+        fn distance(&self, other: &Self) -> f32 {
+            self.distances[self.idx][other.idx]
+        }
+    }
+
+    let (_, points, builder) = seeded(256);
+    let distances = Arc::new(
+        points
+            .iter()
+            .map(|a| points.iter().map(|b| a.distance(b)).collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+    );
+
+    let items = (0..points.len())
+        .map(|idx| Item {
+            idx,
+            distances: distances.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let (hnsw, pids) = builder().build(&items);
+    let mut search = Search::default();
+    let nearest = hnsw.search(&items[7], &mut search).next().unwrap();
+    assert_eq!(nearest.pid, pids[7]);
+    assert_eq!(nearest.distance(), 0.0);
+}
+
+#[test]
+fn lazy_points() {
+    #[derive(Clone)]
+    enum Lazy {
+        Stored(PointId, Arc<HashMap<PointId, Point>>),
+        Query(Point),
+    }
+
+    impl Lazy {
+        fn get(&self) -> Point {
+            match self {
+                Lazy::Stored(pid, store) => store[pid],
+                Lazy::Query(point) => *point,
+            }
+        }
+    }
+
+    impl instant_distance::Point for Lazy {
+        fn distance(&self, other: &Self) -> f32 {
+            self.get().distance(&other.get())
+        }
+    }
+
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let expected = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+
+    let store = Arc::new(
+        hnsw.iter()
+            .map(|(pid, p)| (pid, *p))
+            .collect::<HashMap<_, _>>(),
+    );
+    let lazy = hnsw.map_points(|pid, _| Lazy::Stored(pid, store.clone()));
+    let found = lazy
+        .search(&Lazy::Query(query), &mut search)
+        .collect::<Vec<_>>();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn rerank() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().ef_search(50).build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let nearest = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    let reranked = hnsw.search_rerank(&query, &mut search, 200, |pid| -query.distance(&hnsw[pid]));
+
+    assert_eq!(reranked.len(), 200);
+    assert!(reranked.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    assert_eq!(reranked.last().unwrap().0, nearest[0].pid);
+    assert_eq!(hnsw.search(&query, &mut search).len(), 50);
+
+    search.set_k(Some(10));
+    let limited = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+    assert_eq!(&limited[..], &nearest[..10]);
+    assert_eq!(search.len(), 10);
+    let reranked = hnsw.search_rerank(&query, &mut search, 200, |pid| -query.distance(&hnsw[pid]));
+    assert_eq!(reranked.len(), 200);
+}
+
+#[test]
+fn search_rescored() {
+    let (mut rng, points, builder) = seeded(1024);
+
+    let (hnsw, _) = builder().ef_search(50).build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let manhattan = |p: &Point| (p.0 - query.0).abs() + (p.1 - query.1).abs();
+    let mut search = Search::default();
+    let nearest = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+
+    search.set_k(Some(10));
+    let rescored = hnsw
+        .search_rescored(&query, &mut search, |pid| manhattan(&hnsw[pid]))
+        .collect::<Vec<_>>();
+    let mut expected = nearest
+        .iter()
+        .map(|c| (manhattan(&hnsw[c.pid]), c.pid))
+        .collect::<Vec<_>>();
+    expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    expected.truncate(10);
+    let found = rescored
+        .iter()
+        .map(|c| (c.distance(), c.pid))
+        .collect::<Vec<_>>();
+    assert_eq!(found, expected);
+
+    search.set_k(None);
+    search.set_max_distance(Some(expected[4].0));
+    let limited = hnsw
+        .search_rescored(&query, &mut search, |pid| manhattan(&hnsw[pid]))
+        .collect::<Vec<_>>();
+    assert!(limited.len() >= 5 && limited.len() < 50);
+    assert!(limited.iter().all(|c| c.distance() <= expected[4].0));
+}
+
+#[test]
+fn recall_target() {
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+
+    let (mut found, mut search) = (0, Search::default());
+    search.set_ef_search(Some(50));
+    for _ in 0..20 {
+        let query = Point(rng.gen(), rng.gen());
+        let truth = hnsw
+            .exact_search(&query, 10)
+            .into_iter()
+            .map(|candidate| candidate.pid)
+            .collect::<HashSet<_>>();
+        let results = hnsw.search_with_recall_target(&query, &mut search, 10, 0.95);
+        assert_eq!(results.len(), 10);
+        assert!(results
+            .windows(2)
+            .all(|w| w[0].distance() <= w[1].distance()));
+        found += results.iter().filter(|c| truth.contains(&c.pid)).count();
+    }
+
+    assert!(found >= 180, "recall too low: {}/200", found);
+    assert_eq!(hnsw.search(&Point(0.5, 0.5), &mut search).len(), 50);
+}
+
+#[test]
+fn exhaustive() {
+    let (mut rng, points, builder) = seeded(256);
+    let (hnsw, _) = builder().ef_search(5).build(&points);
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let found = hnsw
+        .search_exhaustive(&query, &mut search)
+        .collect::<Vec<_>>();
+    // Only points that are unreachable in the graph can be missing
+    let exact = hnsw.exact_search(&query, points.len());
+    assert!(
+        found.len() * 100 >= points.len() * 99,
+        "found {} of {}",
+        found.len(),
+        points.len()
+    );
+    assert!(found.iter().all(|candidate| exact.contains(candidate)));
+    assert!(found.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(hnsw.search(&query, &mut search).len(), 5);
+}
+
+#[test]
+fn bounded_distance() {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Bounded(Point);
+
+    impl instant_distance::Point for Bounded {
+        fn distance(&self, other: &Self) -> f32 {
+            self.0.distance(&other.0)
+        }
+
+        fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+            match self.0.distance(&other.0) {
+                distance if distance > bound => f32::INFINITY,
+                distance => distance,
+            }
+        }
+    }
+
+    let (mut rng, points, builder) = seeded(1024);
+    let (hnsw, _) = builder().build(&points);
+    let query = Point(rng.gen(), rng.gen());
+    let mut search = Search::default();
+    let expected = hnsw.search(&query, &mut search).collect::<Vec<_>>();
+
+    let bounded = hnsw.map_points(|_, point| Bounded(point));
+    let found = bounded
+        .search(&Bounded(query), &mut search)
+        .collect::<Vec<_>>();
+    assert_eq!(expected, found);
+}
+
+#[test]
+fn centroid() {
+    let points = vec![
+        Point(0.0, 0.0),
+        Point(2.0, 0.0),
+        Point(1.0, 3.0),
+        Point(5.0, 5.0),
+    ];
+    let (hnsw, pids) = Builder::default().build(&points);
+
+    assert_eq!(hnsw.centroid(&pids[..3]), Point(1.0, 1.0));
+    assert_eq!(hnsw.centroid(&pids[3..]), Point(5.0, 5.0));
+}
+
+#[test]
+fn get_points() {
+    let points = vec![Point(0.0, 0.0), Point(2.0, 0.0), Point(1.0, 3.0)];
+    let (hnsw, pids) = Builder::default().build(&points);
+
+    let reversed = pids.iter().rev().copied().collect::<Vec<_>>();
+    let found = hnsw.get_points(&reversed);
+    assert_eq!(found, points.iter().rev().collect::<Vec<_>>());
+    assert!(hnsw.get_points(&[]).is_empty());
+}
+
+#[test]
+fn random_max_connections() {
+    let builder = Builder::default().max_connections(8).max_connections_0(24);
+    let (seed, recall) = randomized(builder);
+    println!("max connections (seed = {}) recall = {}", seed, recall);
+    assert!(recall > 90, "expected at least 90, got {}", recall);
+}
+
+#[test]
+fn dedup() {
+    #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+    struct Exact(u32, u32);
+
+    impl instant_distance::Point for Exact {
+        fn distance(&self, other: &Self) -> f32 {
+            Point(self.0 as f32, self.1 as f32).distance(&Point(other.0 as f32, other.1 as f32))
+        }
+    }
+
+    let (mut rng, builder) = seeded_builder();
+    let points = (0..1024)
+        .map(|_| Exact(rng.gen_range(0..16), rng.gen_range(0..16)))
+        .collect::<Vec<_>>();
+
+    let (hnsw, pids) = builder().build_dedup(&points);
+    let unique = points.iter().collect::<HashSet<_>>();
+    assert_eq!(hnsw.iter().count(), unique.len());
+    for (i, (point, pid)) in points.iter().zip(&pids).enumerate() {
+        assert_eq!(&hnsw[*pid], point);
+        let first = hnsw.original_index(*pid);
+        assert!(first <= i);
+        assert_eq!(&points[first], point);
+    }
+}
+
+/// Counts how many of 20 random queries find their exact nearest neighbor first
+fn nearest_hits(hnsw: &Hnsw<Point>, rng: &mut StdRng) -> usize {
+    let mut search = Search::default();
+    (0..20)
+        .filter(|_| {
+            let query = Point(rng.gen(), rng.gen());
+            let nearest = hnsw.search(&query, &mut search).next().unwrap();
+            nearest.distance() == hnsw.exact_search(&query, 1)[0].distance()
+        })
+        .count()
+}
+
+/// Returns a generator, `n` uniformly random points from it and a `Builder` seeded alike
+fn seeded(n: usize) -> (StdRng, Vec<Point>, impl Fn() -> Builder) {
+    let (mut rng, builder) = seeded_builder();
+    let points = (0..n)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    (rng, points, builder)
+}
+
+/// Returns a generator for test data and a `Builder` seeded alike
+///
+/// The seed is printed so that a failing run can be reproduced by setting `TEST_SEED`.
+fn seeded_builder() -> (StdRng, impl Fn() -> Builder) {
+    let seed = match std::env::var("TEST_SEED") {
+        Ok(seed) => seed.parse().unwrap(),
+        Err(_) => ThreadRng::default().gen::<u64>(),
+    };
+    println!("seed = {}", seed);
+    (StdRng::seed_from_u64(seed), move || {
+        Builder::default().seed(seed)
+    })
+}
+
 fn randomized(builder: Builder) -> (u64, usize) {
     let seed = ThreadRng::default().gen::<u64>();
     let mut rng = StdRng::seed_from_u64(seed);
     let points = (0..1024)
-        .into_iter()
         .map(|_| Point(rng.gen(), rng.gen()))
         .collect::<Vec<_>>();
 
@@ -56,7 +1861,8 @@ fn randomized(builder: Builder) -> (u64, usize) {
     (seed, forced.intersection(&found).count())
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 struct Point(f32, f32);
 
 impl instant_distance::Point for Point {
@@ -65,3 +1871,18 @@ impl instant_distance::Point for Point {
         ((self.0 - other.0).powi(2) + (self.1 - other.1).powi(2)).sqrt()
     }
 }
+
+impl instant_distance::Centroid for Point {
+    fn centroid(points: &[&Self]) -> Self {
+        let n = points.len() as f32;
+        let x = points.iter().map(|p| p.0).sum::<f32>();
+        let y = points.iter().map(|p| p.1).sum::<f32>();
+        Point(x / n, y / n)
+    }
+}
+
+impl From<&[f32]> for Point {
+    fn from(row: &[f32]) -> Self {
+        Point(row[0], row[1])
+    }
+}